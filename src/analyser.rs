@@ -0,0 +1,200 @@
+//!
+//! Oscilloscope and spectrum analyser feed for the GUI
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel as cb;
+use ringbuf::{RingBuffer, Producer, Consumer};
+use rustfft::{FftPlanner, Fft};
+use rustfft::num_complex::Complex;
+
+use crate::messages::*;
+use crate::comms::*;
+
+/// number of points in a waveform snapshot
+const SCOPE_POINTS: usize = 512;
+/// size of FFT, and the window of samples analysed
+const FFT_SIZE: usize = 2048;
+/// number of (linearly spaced) bins sent to GUI
+const SPECTRUM_BINS: usize = 256;
+/// samples buffered between audio and analysis threads, per tap
+const RING_SIZE: usize = 16384;
+/// time between updates sent to GUI
+const UPDATE_INTERVAL: Duration = Duration::from_millis(33);
+
+// shared between the audio thread's tap and the analysis thread
+struct Control {
+    enabled: AtomicBool,
+    exit: AtomicBool,
+    sample_rate: AtomicU32,
+}
+
+/// audio thread side of the analyser, copies a mono mix of input and output buffers
+/// into lock-free rings. Does nothing unless enabled from the GUI.
+pub struct AnalyserTap {
+    control: Arc<Control>,
+    input: Producer<f32>,
+    output: Producer<f32>,
+}
+
+impl AnalyserTap {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.control.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn set_sample_rate(&self, sample_rate: f64) {
+        self.control.sample_rate.store(sample_rate as u32, Ordering::Relaxed);
+    }
+
+    /// tap interleaved input and output buffers
+    pub fn tap(
+        &mut self,
+        in_buffer: &[f32],
+        in_channels: usize,
+        out_buffer: &[f32],
+        out_channels: usize,
+        frames: usize) {
+        if !self.control.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        Self::push_mono(&mut self.input, in_buffer, in_channels, frames);
+        Self::push_mono(&mut self.output, out_buffer, out_channels, frames);
+    }
+
+    // push a mono mix of an interleaved buffer, samples are dropped if the ring is full
+    fn push_mono(producer: &mut Producer<f32>, buffer: &[f32], channels: usize, frames: usize) {
+        if channels == 0 {
+            return;
+        }
+        for frame in buffer.chunks(channels).take(frames) {
+            let _ = producer.push(frame.iter().sum::<f32>() / channels as f32);
+        }
+    }
+}
+
+impl Drop for AnalyserTap {
+    fn drop(&mut self) {
+        self.control.exit.store(true, Ordering::Relaxed);
+    }
+}
+
+// analysis of a single tap, run on the analysis thread
+struct Analysis {
+    node: Index,
+    consumer: Consumer<f32>,
+    window: Vec<f32>,
+    incoming: Vec<f32>,
+}
+
+impl Analysis {
+    fn new(node: Index, consumer: Consumer<f32>) -> Self {
+        Self {
+            node,
+            consumer,
+            window: vec![0.0; FFT_SIZE],
+            incoming: vec![0.0; RING_SIZE],
+        }
+    }
+
+    // move any new samples into window, returning true if there were some
+    fn update(&mut self) -> bool {
+        let n = self.consumer.pop_slice(&mut self.incoming[..]);
+        if n >= FFT_SIZE {
+            self.window.copy_from_slice(&self.incoming[n - FFT_SIZE..n]);
+        }
+        else if n > 0 {
+            self.window.copy_within(n.., 0);
+            self.window[FFT_SIZE - n..].copy_from_slice(&self.incoming[..n]);
+        }
+        n > 0
+    }
+
+    fn discard(&mut self) {
+        while self.consumer.pop_slice(&mut self.incoming[..]) > 0 {}
+    }
+
+    fn scope(&self) -> Vec<f32> {
+        self.window.iter().step_by(FFT_SIZE / SCOPE_POINTS).cloned().collect()
+    }
+
+    // magnitude spectrum in dB, using a Hann window
+    fn spectrum(&self, fft: &Arc<dyn Fft<f32>>, hann: &[f32]) -> Vec<f32> {
+        let mut buffer: Vec<Complex<f32>> = self.window.iter().zip(hann.iter())
+            .map(|(s, w)| Complex::new(s * w, 0.0))
+            .collect();
+        fft.process(&mut buffer);
+
+        let per_bin = FFT_SIZE / 2 / SPECTRUM_BINS;
+        let scale = 2.0 / FFT_SIZE as f32;
+        buffer[..FFT_SIZE / 2].chunks(per_bin)
+            .map(|bins| {
+                let m = bins.iter().map(|c| c.norm()).sum::<f32>() / per_bin as f32 * scale;
+                20.0 * m.max(1.0e-6).log10()
+            })
+            .collect()
+    }
+}
+
+/// create an analyser, returning the tap for the audio thread. Snapshots are sent to
+/// the GUI, from a separate thread, while the analyser is enabled.
+pub fn spawn_analyser(send_to_gui: cb::Sender<Message>) -> AnalyserTap {
+    let control = Arc::new(Control {
+        enabled: AtomicBool::new(false),
+        exit: AtomicBool::new(false),
+        sample_rate: AtomicU32::new(44_100),
+    });
+
+    let (input, input_consumer) = RingBuffer::<f32>::new(RING_SIZE).split();
+    let (output, output_consumer) = RingBuffer::<f32>::new(RING_SIZE).split();
+
+    let thread_control = control.clone();
+    thread::spawn(move || {
+        let control = thread_control;
+        let fft = FftPlanner::<f32>::new().plan_fft_forward(FFT_SIZE);
+        let hann: Vec<f32> = (0..FFT_SIZE)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / FFT_SIZE as f32).cos())
+            .collect();
+        let mut taps = vec![Analysis::new(0, input_consumer), Analysis::new(1, output_consumer)];
+
+        while !control.exit.load(Ordering::Relaxed) {
+            thread::sleep(UPDATE_INTERVAL);
+
+            if !control.enabled.load(Ordering::Relaxed) {
+                for tap in taps.iter_mut() {
+                    tap.discard();
+                }
+                continue;
+            }
+
+            let sample_rate = control.sample_rate.load(Ordering::Relaxed);
+            for tap in taps.iter_mut() {
+                if tap.update() {
+                    let _ = send_to_gui.try_send(Message {
+                        id: MessageID::Scope,
+                        node: tap.node,
+                        index: 0,
+                        value: Value::VVF32(tap.scope()),
+                    });
+                    let _ = send_to_gui.try_send(Message {
+                        id: MessageID::Spectrum,
+                        node: tap.node,
+                        index: sample_rate,
+                        value: Value::VVF32(tap.spectrum(&fft, &hann)),
+                    });
+                }
+            }
+        }
+    });
+
+    AnalyserTap {
+        control,
+        input,
+        output,
+    }
+}
@@ -0,0 +1,385 @@
+//!
+//! Self-contained module archives (.aab), a zip of a module's bundle.json, wasm files,
+//! and GUI assets, served to the host, and webview, over loopback HTTP
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+use zip::{ZipArchive, ZipWriter, CompressionMethod};
+use zip::write::FileOptions;
+
+use crate::bundle::*;
+use crate::utils::get_vec;
+use crate::validate::{validate_bundle_json, has_errors};
+
+/// the bundle, within an archive
+pub const BUNDLE_JSON: &str = "bundle.json";
+/// the host's GUI, within an archive
+pub const INDEX_HTML: &str = "index.html";
+const MODULES_JSON: &str = "modules.json";
+/// largest file read from an archive, uncompressed, whatever size the archive declares
+const MAX_FILE_SIZE: u64 = 256 << 20;
+/// most memory reserved for a file before it is read, as its declared size may be false
+const MAX_RESERVED: u64 = 1 << 20;
+
+/// the files of a module archive, by path, without a leading /
+pub struct Archive {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl Archive {
+    /// open an archive, from a file, or an http(s) or file URL
+    pub fn open(location: &str) -> Result<Self, String> {
+        let data =
+            if location.contains("://") {
+                get_vec(location).map_err(|_| format!("failed to fetch {}", location))?
+            }
+            else {
+                fs::read(location).map_err(|e| format!("{} {}", location, e))?
+            };
+        Self::read(data).map_err(|e| format!("{} {}", location, e))
+    }
+
+    pub fn read(data: Vec<u8>) -> Result<Self, String> {
+        let mut zip = ZipArchive::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+        let mut files = HashMap::new();
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i).map_err(|e| e.to_string())?;
+            if file.is_dir() {
+                continue;
+            }
+            let size = file.size();
+            let name = file.name().trim_start_matches('/').to_string();
+            let bytes = read_file(&mut file, size, MAX_FILE_SIZE).map_err(|e| format!("{} {}", name, e))?;
+            files.insert(name, bytes);
+        }
+
+        let archive = Self { files };
+        if !archive.files.contains_key(BUNDLE_JSON) {
+            return Err(format!("archive has no {}", BUNDLE_JSON));
+        }
+        Ok(archive)
+    }
+
+    /// a file, by path or URL path, e.g. /module.wasm
+    pub fn get(&self, path: &str) -> Option<&[u8]> {
+        self.files.get(path.trim_start_matches('/')).map(|bytes| &bytes[..])
+    }
+
+    pub fn bundle(&self) -> Result<Bundle, String> {
+        let json = self.get(BUNDLE_JSON).ok_or_else(|| format!("archive has no {}", BUNDLE_JSON))?;
+        let json = std::str::from_utf8(json).map_err(|e| e.to_string())?;
+        Bundle::from_json(json).map_err(|_| format!("invalid {}", BUNDLE_JSON))
+    }
+
+    // a modules.json, with the archive's module as the only, and default, module
+    fn modules_json(&self) -> Vec<u8> {
+        let name = self.bundle().map_or("module".to_string(), |bundle| bundle.gui.name);
+        serde_json::json!({
+            "default": BUNDLE_JSON,
+            "modules": [{ "name": name, "json_url": BUNDLE_JSON }],
+        }).to_string().into_bytes()
+    }
+}
+
+// read a file from an archive, of its declared size, up to limit bytes
+fn read_file<R: Read>(file: R, size: u64, limit: u64) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(size.min(limit).min(MAX_RESERVED) as usize);
+    file.take(limit + 1).read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+    if bytes.len() as u64 > limit {
+        return Err(format!("is larger than {} bytes", limit));
+    }
+    Ok(bytes)
+}
+
+//-----------------------------------------------------------------------------
+
+/// create an archive from a directory, which must contain a valid bundle.json, and the
+/// wasm files and GUI it refers to. All files in the directory are included.
+pub fn pack(dir: &str, output: &str) -> Result<(), String> {
+    let root = Path::new(dir);
+    let json = fs::read_to_string(root.join(BUNDLE_JSON))
+        .map_err(|e| format!("{} {}", root.join(BUNDLE_JSON).display(), e))?;
+    let diagnostics = validate_bundle_json(&json);
+    for diagnostic in diagnostics.iter() {
+        eprintln!("{}: {}", BUNDLE_JSON, diagnostic);
+    }
+    if has_errors(&diagnostics) {
+        return Err(format!("invalid {}", BUNDLE_JSON));
+    }
+    let bundle = Bundle::parse(&json).map_err(|e| e.to_string())?;
+
+    let mut paths = Vec::new();
+    collect_files(root, root, &mut paths).map_err(|e| format!("{} {}", dir, e))?;
+    paths.sort();
+
+    // everything the bundle refers to must be in the archive
+    let gui_path = bundle.gui.url.split(|c| c == '?' || c == '#').next().unwrap_or("");
+    for path in bundle.wasm_url.iter().map(|p| &p[..]).chain(std::iter::once(gui_path)) {
+        let path = path.trim_start_matches('/');
+        if !paths.iter().any(|p| p == path) {
+            return Err(format!("{} refers to {}, which is not in {}", BUNDLE_JSON, path, dir));
+        }
+    }
+    if !paths.iter().any(|p| p == INDEX_HTML) {
+        eprintln!("{} has no {}, the archive can only be rendered", dir, INDEX_HTML);
+    }
+
+    let file = File::create(output).map_err(|e| format!("{} {}", output, e))?;
+    let mut zip = ZipWriter::new(file);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for path in paths.iter() {
+        let data = fs::read(root.join(path)).map_err(|e| format!("{} {}", path, e))?;
+        zip.start_file(path.as_str(), options).map_err(|e| e.to_string())?;
+        zip.write_all(&data).map_err(|e| e.to_string())?;
+    }
+    zip.finish().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// paths of all files below dir, relative to root, with / separators
+fn collect_files(root: &Path, dir: &Path, paths: &mut Vec<String>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(root, &path, paths)?;
+        }
+        else if let Ok(relative) = path.strip_prefix(root) {
+            let components: Vec<_> = relative.components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect();
+            paths.push(components.join("/"));
+        }
+    }
+    Ok(())
+}
+
+//-----------------------------------------------------------------------------
+
+/// serves an archive over HTTP on loopback, so that it appears to the host, and the
+/// webview, as a module repository with a single module
+pub struct ArchiveServer {
+    url: String,
+}
+
+impl ArchiveServer {
+    pub fn start(archive: Archive) -> Result<Self, String> {
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|e| e.to_string())?;
+        let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+        let archive = Arc::new(archive);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    let archive = archive.clone();
+                    thread::spawn(move || {
+                        let _ = Self::serve(stream, &archive);
+                    });
+                }
+            }
+        });
+
+        Ok(Self { url: format!("http://127.0.0.1:{}", port) })
+    }
+
+    /// URL of the repository, to be used in place of a server's
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // serve a single request, the connection is closed after the response
+    fn serve(mut stream: TcpStream, archive: &Archive) -> std::io::Result<()> {
+        let (method, target) = read_request(&stream)?;
+        let path = target.split(|c| c == '?' || c == '#').next().unwrap_or("/");
+        let path = if path == "/" { INDEX_HTML } else { path.trim_start_matches('/') };
+
+        let modules;
+        let body = match archive.get(path) {
+            Some(body) => Some(body),
+            None if path == MODULES_JSON => {
+                modules = archive.modules_json();
+                Some(&modules[..])
+            },
+            None => None,
+        };
+        respond(&mut stream, &method, content_type(path), body)
+    }
+}
+
+/// read a HTTP request, returning its method and target. Headers are ignored, but read,
+/// so the client sees the response not a reset.
+pub(crate) fn read_request(stream: &TcpStream) -> std::io::Result<(String, String)> {
+    let mut request = String::new();
+    let mut reader = BufReader::new(stream);
+    reader.read_line(&mut request)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("/").to_string();
+    Ok((method, target))
+}
+
+/// respond to a GET or HEAD request with body, or not found if None. Responses are not
+/// to be cached by the client, as they are served from memory, or the host's cache.
+pub(crate) fn respond(
+    stream: &mut TcpStream,
+    method: &str,
+    content_type: &str,
+    body: Option<&[u8]>) -> std::io::Result<()> {
+
+    match body {
+        Some(body) if method == "GET" || method == "HEAD" => {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+                content_type,
+                body.len())?;
+            if method == "GET" {
+                stream.write_all(body)?;
+            }
+        },
+        Some(_) => write!(stream, "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?,
+        None => write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?,
+    }
+    stream.flush()
+}
+
+pub(crate) fn content_type(path: &str) -> &'static str {
+    match path.rsplit('.').next().unwrap_or("") {
+        "html" | "htm" => "text/html",
+        "js" => "text/javascript",
+        "css" => "text/css",
+        "json" => "application/json",
+        "wasm" => "application/wasm",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wav" => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const BUNDLE: &str = r#"{
+        "format_version": 2,
+        "wasm_url": ["/synth.wasm"],
+        "gui": { "url": "/index.html", "name": "Synth", "params": [[440.0]], "width": 400, "height": 300 },
+        "info": { "name": "Synth", "parameters": 1, "inputs": 0, "outputs": 2 }
+    }"#;
+
+    // a module directory, with a bundle, wasm, GUI, and a nested asset
+    fn module_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aa_archive_{}_{}", std::process::id(), name));
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join(BUNDLE_JSON), BUNDLE).unwrap();
+        fs::write(dir.join("synth.wasm"), b"\0asm").unwrap();
+        fs::write(dir.join(INDEX_HTML), "<html></html>").unwrap();
+        fs::write(dir.join("assets").join("style.css"), "body {}").unwrap();
+        dir
+    }
+
+    fn packed(name: &str) -> (PathBuf, String) {
+        let dir = module_dir(name);
+        let output = dir.with_extension("aab").to_string_lossy().to_string();
+        pack(&dir.to_string_lossy(), &output).unwrap();
+        (dir, output)
+    }
+
+    #[test]
+    fn packed_archives_read_back() {
+        let (dir, output) = packed("read");
+        let archive = Archive::open(&output).unwrap();
+        assert_eq!(archive.get("/synth.wasm"), Some(&b"\0asm"[..]));
+        assert_eq!(archive.get("assets/style.css"), Some(&b"body {}"[..]));
+        assert_eq!(archive.get("/missing"), None);
+        assert_eq!(archive.bundle().unwrap().gui.name, "Synth");
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_file(output);
+    }
+
+    #[test]
+    fn packing_requires_the_files_the_bundle_refers_to() {
+        let dir = module_dir("missing");
+        fs::remove_file(dir.join("synth.wasm")).unwrap();
+        let output = dir.with_extension("aab").to_string_lossy().to_string();
+        let e = pack(&dir.to_string_lossy(), &output).unwrap_err();
+        assert!(e.contains("synth.wasm"), "{}", e);
+        fs::write(dir.join(BUNDLE_JSON), "{}").unwrap();
+        assert!(pack(&dir.to_string_lossy(), &output).is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn archives_need_a_bundle() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("index.html", FileOptions::default()).unwrap();
+        zip.write_all(b"<html></html>").unwrap();
+        let data = zip.finish().unwrap().into_inner();
+        assert!(Archive::read(data).unwrap_err().contains(BUNDLE_JSON));
+        assert!(Archive::read(b"not a zip".to_vec()).is_err());
+    }
+
+    #[test]
+    fn declared_sizes_are_not_trusted() {
+        let data = vec![1u8; 100];
+        // declared much larger, or smaller, than it is
+        assert_eq!(read_file(&data[..], u64::MAX, 1000).unwrap(), data);
+        assert_eq!(read_file(&data[..], 10, 1000).unwrap(), data);
+        assert_eq!(read_file(&data[..], 100, 100).unwrap(), data);
+        assert!(read_file(&data[..], 10, 99).is_err());
+    }
+
+    // send a request to a server, returning the response's status line and body
+    fn request(url: &str, method: &str, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(url.trim_start_matches("http://")).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.lines().next().unwrap_or("").to_string();
+        let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or("").to_string();
+        (status, body)
+    }
+
+    #[test]
+    fn archives_are_served_as_a_repository() {
+        let (dir, output) = packed("serve");
+        let server = ArchiveServer::start(Archive::open(&output).unwrap()).unwrap();
+        let url = server.url();
+        assert_eq!(request(url, "GET", "/"), ("HTTP/1.1 200 OK".to_string(), "<html></html>".to_string()));
+        assert_eq!(request(url, "GET", "/assets/style.css?v=1").1, "body {}");
+        assert_eq!(request(url, "HEAD", "/synth.wasm"), ("HTTP/1.1 200 OK".to_string(), String::new()));
+        assert_eq!(request(url, "GET", "/missing.js").0, "HTTP/1.1 404 Not Found");
+        assert_eq!(request(url, "POST", "/index.html").0, "HTTP/1.1 405 Method Not Allowed");
+
+        let modules: serde_json::Value = serde_json::from_str(&request(url, "GET", "/modules.json").1).unwrap();
+        assert_eq!(modules["default"], BUNDLE_JSON);
+        assert_eq!(modules["modules"][0]["name"], "Synth");
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_file(output);
+    }
+
+    #[test]
+    fn content_types_follow_extensions() {
+        assert_eq!(content_type("index.html"), "text/html");
+        assert_eq!(content_type("/module.wasm"), "application/wasm");
+        assert_eq!(content_type("noextension"), "application/octet-stream");
+    }
+}
@@ -0,0 +1,443 @@
+//!
+//! Audio backends, abstracting the audio API used to run modules
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+extern crate portaudio;
+use portaudio as pa;
+
+use crate::utils::*;
+
+/// an audio device, as reported by a backend
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    /// backend specific index of the device
+    pub index: u32,
+    pub name: String,
+    pub max_inputs: i32,
+    pub max_outputs: i32,
+}
+
+/// requested configuration of a stream, a backend may choose a different
+/// sample rate, which is reported by the opened stream
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    pub input_device: Option<u32>,
+    pub output_device: Option<u32>,
+    pub inputs: i32,
+    pub outputs: i32,
+    pub sample_rate: f64,
+    pub frames: u32,
+}
+
+/// process callback, called with interleaved input and output buffers and the number
+/// of frames in each. Returns false when the stream should complete.
+pub type ProcessCallback = Box<dyn FnMut(&[f32], &mut [f32], usize) -> bool>;
+
+/// an open audio stream, which does not call its process callback until started
+pub trait AudioStream {
+    fn start(&mut self) -> Result<()>;
+    fn stop(&mut self) -> Result<()>;
+    /// actual sample rate of the stream
+    fn sample_rate(&self) -> f64;
+    /// actual (output, or input for input only streams) latency in seconds
+    fn latency(&self) -> f64;
+}
+
+pub trait AudioBackend {
+    fn name(&self) -> &str;
+    fn devices(&self) -> Result<Vec<DeviceInfo>>;
+    fn default_input_device(&self) -> Option<u32>;
+    fn default_output_device(&self) -> Option<u32>;
+    fn open_duplex(&mut self, config: &StreamConfig, callback: ProcessCallback) -> Result<Box<dyn AudioStream>>;
+    fn open_output(&mut self, config: &StreamConfig, callback: ProcessCallback) -> Result<Box<dyn AudioStream>>;
+    fn open_input(&mut self, config: &StreamConfig, callback: ProcessCallback) -> Result<Box<dyn AudioStream>>;
+}
+
+// Some audio APIs require their callback to be Send, while the callbacks here share the
+// aaunit and host (via Rc) with the audio thread. Send is only implemented for the types
+// that are moved to a stream's thread, below, each of which is only used while the audio
+// thread is blocked, and for modules moved from the loader to the audio thread.
+pub(crate) struct AudioThreadOnly<T>(pub T);
+
+// SAFETY: a process callback is moved to the null stream's thread when it is started. The
+// audio thread blocks, in Standalone::audio, from start until the stream is stopped, and
+// stop joins the stream's thread, so the Rcs the callback holds are never used, cloned,
+// or dropped on both threads at once.
+unsafe impl Send for AudioThreadOnly<ProcessCallback> {}
+
+// SAFETY: JACK only calls process between activate and deactivate, during which the audio
+// thread is blocked, in audio_jack, and deactivate waits for process to return, as for
+// process callbacks above.
+#[cfg(feature = "jack")]
+unsafe impl Send for AudioThreadOnly<crate::engine::Engine> {}
+
+// SAFETY: a module, its aaunit and voices, is instantiated by the loader, which moves it,
+// whole, to the audio thread, keeping no reference to any part of it, so it is only ever
+// used by one thread at a time. wasmtime sets up its per-thread trap handling on each
+// thread as it first calls into wasm, not when a module is instantiated, so the audio
+// thread is set up as it runs the module.
+unsafe impl Send for AudioThreadOnly<crate::loader::LoadedModule> {}
+
+//-----------------------------------------------------------------------------
+
+/// backend using PortAudio, the default for all platforms
+pub struct PortAudioBackend {
+    pa: pa::PortAudio,
+}
+
+impl PortAudioBackend {
+    pub fn new() -> Result<Self> {
+        pa::PortAudio::new().map_or(err(), |pa| ok(Self { pa }))
+    }
+
+    fn parameters(&self, device: Option<u32>, channels: i32, input: bool) -> Result<pa::stream::Parameters<f32>> {
+        let device = match device {
+            Some(index) => pa::DeviceIndex(index),
+            None => {
+                let default =
+                    if input { self.pa.default_input_device() } else { self.pa.default_output_device() };
+                default.map_err(|_| ())?
+            }
+        };
+        ok(pa::stream::Parameters::new(device, channels, true, 0.1))
+    }
+}
+
+struct PortAudioStream<F: pa::stream::Flow> {
+    stream: pa::Stream<pa::NonBlocking, F>,
+    input: bool,
+}
+
+impl <F: pa::stream::Flow> AudioStream for PortAudioStream<F> {
+    fn start(&mut self) -> Result<()> {
+        self.stream.start().map_err(|_| ())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.stream.stop().map_err(|_| ())
+    }
+
+    fn sample_rate(&self) -> f64 {
+        self.stream.info().sample_rate
+    }
+
+    fn latency(&self) -> f64 {
+        let info = self.stream.info();
+        if self.input { info.input_latency } else { info.output_latency }
+    }
+}
+
+impl AudioBackend for PortAudioBackend {
+    fn name(&self) -> &str {
+        "portaudio"
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        let mut devices = Vec::new();
+        for device in self.pa.devices().map_err(|_| ())? {
+            if let Ok((index, info)) = device {
+                devices.push(DeviceInfo {
+                    index: index.0,
+                    name: info.name.to_string(),
+                    max_inputs: info.max_input_channels,
+                    max_outputs: info.max_output_channels,
+                });
+            }
+        }
+        ok(devices)
+    }
+
+    fn default_input_device(&self) -> Option<u32> {
+        self.pa.default_input_device().ok().map(|d| d.0)
+    }
+
+    fn default_output_device(&self) -> Option<u32> {
+        self.pa.default_output_device().ok().map(|d| d.0)
+    }
+
+    fn open_duplex(&mut self, config: &StreamConfig, mut callback: ProcessCallback) -> Result<Box<dyn AudioStream>> {
+        let settings = pa::stream::DuplexSettings::new(
+            self.parameters(config.input_device, config.inputs, true)?,
+            self.parameters(config.output_device, config.outputs, false)?,
+            config.sample_rate,
+            config.frames);
+
+        let stream = self.pa.open_non_blocking_stream(settings, move |pa::DuplexStreamCallbackArgs {
+            in_buffer,
+            out_buffer,
+            frames,
+            .. }| {
+                if callback(in_buffer, out_buffer, frames) { pa::Continue } else { pa::Complete }
+            }).map_err(|_| ())?;
+        ok(Box::new(PortAudioStream { stream, input: false }))
+    }
+
+    fn open_output(&mut self, config: &StreamConfig, mut callback: ProcessCallback) -> Result<Box<dyn AudioStream>> {
+        let settings = pa::stream::OutputSettings::new(
+            self.parameters(config.output_device, config.outputs, false)?,
+            config.sample_rate,
+            config.frames);
+
+        let stream = self.pa.open_non_blocking_stream(settings, move |pa::OutputStreamCallbackArgs {
+            buffer,
+            frames,
+            .. }| {
+                if callback(&[], buffer, frames) { pa::Continue } else { pa::Complete }
+            }).map_err(|_| ())?;
+        ok(Box::new(PortAudioStream { stream, input: false }))
+    }
+
+    fn open_input(&mut self, config: &StreamConfig, mut callback: ProcessCallback) -> Result<Box<dyn AudioStream>> {
+        let settings = pa::stream::InputSettings::new(
+            self.parameters(config.input_device, config.inputs, true)?,
+            config.sample_rate,
+            config.frames);
+
+        let stream = self.pa.open_non_blocking_stream(settings, move |pa::InputStreamCallbackArgs {
+            buffer,
+            frames,
+            .. }| {
+                if callback(buffer, &mut [], frames) { pa::Continue } else { pa::Complete }
+            }).map_err(|_| ())?;
+        ok(Box::new(PortAudioStream { stream, input: true }))
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// backend without any audio hardware, buffers are pulled on a timer, at the rate
+/// of a real device, or as fast as possible. Input is silence.
+pub struct NullBackend {
+    realtime: bool,
+}
+
+impl NullBackend {
+    pub fn new(realtime: bool) -> Self {
+        Self {
+            realtime,
+        }
+    }
+}
+
+struct NullStream {
+    config: StreamConfig,
+    realtime: bool,
+    callback: Option<AudioThreadOnly<ProcessCallback>>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl NullStream {
+    fn new(config: &StreamConfig, realtime: bool, callback: ProcessCallback) -> Self {
+        Self {
+            config: config.clone(),
+            realtime,
+            callback: Some(AudioThreadOnly(callback)),
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+}
+
+impl AudioStream for NullStream {
+    fn start(&mut self) -> Result<()> {
+        let mut callback = self.callback.take().ok_or(())?;
+        let running = self.running.clone();
+        let realtime = self.realtime;
+        let frames = self.config.frames as usize;
+        let period = Duration::from_secs_f64(frames as f64 / self.config.sample_rate);
+        let in_buffer = vec![0.0f32; frames * self.config.inputs.max(0) as usize];
+        let mut out_buffer = vec![0.0f32; frames * self.config.outputs.max(0) as usize];
+
+        running.store(true, Ordering::SeqCst);
+        self.thread = Some(thread::spawn(move || {
+            let mut next = Instant::now();
+            while running.load(Ordering::SeqCst) {
+                if !(callback.0)(&in_buffer[..], &mut out_buffer[..], frames) {
+                    break;
+                }
+                if realtime {
+                    next += period;
+                    thread::sleep(next.saturating_duration_since(Instant::now()));
+                }
+            }
+        }));
+        ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().map_err(|_| ())?;
+        }
+        ok(())
+    }
+
+    fn sample_rate(&self) -> f64 {
+        self.config.sample_rate
+    }
+
+    fn latency(&self) -> f64 {
+        self.config.frames as f64 / self.config.sample_rate
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn name(&self) -> &str {
+        "null"
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        ok(vec![DeviceInfo {
+            index: 0,
+            name: "Null".to_string(),
+            max_inputs: 2,
+            max_outputs: 2,
+        }])
+    }
+
+    fn default_input_device(&self) -> Option<u32> {
+        Some(0)
+    }
+
+    fn default_output_device(&self) -> Option<u32> {
+        Some(0)
+    }
+
+    fn open_duplex(&mut self, config: &StreamConfig, callback: ProcessCallback) -> Result<Box<dyn AudioStream>> {
+        ok(Box::new(NullStream::new(config, self.realtime, callback)))
+    }
+
+    fn open_output(&mut self, config: &StreamConfig, callback: ProcessCallback) -> Result<Box<dyn AudioStream>> {
+        let config = StreamConfig { inputs: 0, .. config.clone() };
+        ok(Box::new(NullStream::new(&config, self.realtime, callback)))
+    }
+
+    fn open_input(&mut self, config: &StreamConfig, callback: ProcessCallback) -> Result<Box<dyn AudioStream>> {
+        let config = StreamConfig { outputs: 0, .. config.clone() };
+        ok(Box::new(NullStream::new(&config, self.realtime, callback)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn config(inputs: i32, outputs: i32) -> StreamConfig {
+        StreamConfig {
+            input_device: None,
+            output_device: None,
+            inputs,
+            outputs,
+            sample_rate: 44_100.0,
+            frames: 64,
+        }
+    }
+
+    // a callback recording the size of each buffer, which completes after count buffers
+    fn counting(count: usize, sizes: Arc<Mutex<Vec<(usize, usize, usize)>>>) -> ProcessCallback {
+        Box::new(move |in_buffer: &[f32], out_buffer: &mut [f32], frames: usize| {
+            let mut sizes = sizes.lock().unwrap();
+            sizes.push((in_buffer.len(), out_buffer.len(), frames));
+            sizes.len() < count
+        })
+    }
+
+    fn run(stream: &mut Box<dyn AudioStream>) {
+        stream.start().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream.stop().unwrap();
+    }
+
+    #[test]
+    fn duplex_buffers_are_sized_by_config() {
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let mut backend = NullBackend::new(false);
+        let mut stream = backend.open_duplex(&config(1, 2), counting(4, sizes.clone())).unwrap();
+        run(&mut stream);
+        let sizes = sizes.lock().unwrap();
+        assert_eq!(sizes.len(), 4);
+        assert!(sizes.iter().all(|s| *s == (64, 128, 64)));
+    }
+
+    #[test]
+    fn output_and_input_streams_have_no_other_buffer() {
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let mut backend = NullBackend::new(false);
+        let mut stream = backend.open_output(&config(2, 2), counting(1, sizes.clone())).unwrap();
+        run(&mut stream);
+        let mut stream = backend.open_input(&config(2, 2), counting(2, sizes.clone())).unwrap();
+        run(&mut stream);
+        assert_eq!(*sizes.lock().unwrap(), vec![(0, 128, 64), (128, 0, 64)]);
+    }
+
+    #[test]
+    fn input_is_silence() {
+        let silent = Arc::new(Mutex::new(true));
+        let result = silent.clone();
+        let mut backend = NullBackend::new(false);
+        let callback: ProcessCallback = Box::new(move |in_buffer: &[f32], _: &mut [f32], _| {
+            *result.lock().unwrap() &= in_buffer.iter().all(|s| *s == 0.0);
+            false
+        });
+        let mut stream = backend.open_duplex(&config(2, 2), callback).unwrap();
+        run(&mut stream);
+        assert!(*silent.lock().unwrap());
+    }
+
+    #[test]
+    fn stop_ends_a_running_stream() {
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let mut backend = NullBackend::new(true);
+        let mut stream = backend.open_output(&config(0, 2), counting(usize::MAX, sizes.clone())).unwrap();
+        run(&mut stream);
+        let count = sizes.lock().unwrap().len();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(sizes.lock().unwrap().len(), count);
+    }
+
+    #[test]
+    fn realtime_stream_runs_at_the_sample_rate() {
+        // when each buffer was computed, stopping after 100 buffers
+        let times = Arc::new(Mutex::new(Vec::new()));
+        let result = times.clone();
+        let callback: ProcessCallback = Box::new(move |_: &[f32], _: &mut [f32], _| {
+            let mut times = result.lock().unwrap();
+            times.push(Instant::now());
+            times.len() < 100
+        });
+        let mut backend = NullBackend::new(true);
+        let mut stream = backend.open_output(&config(0, 2), callback).unwrap();
+        let start = Instant::now();
+        stream.start().unwrap();
+        let deadline = start + Duration::from_secs(10);
+        while times.lock().unwrap().len() < 100 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        stream.stop().unwrap();
+        // the stream may fall behind when the thread is not scheduled in time, but
+        // never runs ahead, so buffer n is not computed until n periods have passed
+        let period = Duration::from_secs_f64(64.0 / 44_100.0);
+        let times = times.lock().unwrap();
+        assert_eq!(times.len(), 100);
+        for (n, time) in times.iter().enumerate() {
+            assert!(time.duration_since(start) >= period * n as u32, "buffer {} was early", n);
+        }
+    }
+
+    #[test]
+    fn stream_reports_its_config() {
+        let mut backend = NullBackend::new(false);
+        let stream = backend.open_output(&config(0, 2), Box::new(|_: &[f32], _: &mut [f32], _| false)).unwrap();
+        assert_eq!(stream.sample_rate(), 44_100.0);
+        assert!((stream.latency() - 64.0 / 44_100.0).abs() < 1e-9);
+    }
+}
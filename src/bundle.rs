@@ -1,7 +1,17 @@
 use serde::{Deserialize};
+use serde_json::Value as Json;
 
 use crate::utils::{err, ok, Result};
 use crate::messages::*;
+use crate::validate::{Diagnostic, validate_bundle_json, validate_modules_json};
+
+/// current version of the bundle format. Bundles without a format_version are version 1,
+/// the original format, which had no metadata and required every field of info.
+pub const FORMAT_VERSION: u32 = 2;
+
+fn legacy_format_version() -> u32 {
+    1
+}
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct GUIBundle {
@@ -10,39 +20,203 @@ pub struct GUIBundle {
     pub params: Vec<Vec<Value>>,
     pub width: i32,
     pub height: i32, 
+    /// output parameters (e.g. meters), as (node, index), whose values are sent to the GUI
+    #[serde(default)]
+    pub output_params: Vec<(u32, u32)>,
+    /// parameters, as (node, index), set for each note, when the host provides polyphony
+    #[serde(default)]
+    pub voice: Option<VoiceParams>,
+    /// parameters, as (node, index), set from the host's clock
+    #[serde(default)]
+    pub host_params: HostParams,
+}
+
+/// parameters of a module that follow the host's clock, set before each buffer
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct HostParams {
+    /// tempo, in BPM
+    #[serde(default)]
+    pub tempo: Option<(u32, u32)>,
+    /// 1.0 while the transport is playing, otherwise 0.0
+    #[serde(default)]
+    pub playing: Option<(u32, u32)>,
+    /// bar, counting from 0
+    #[serde(default)]
+    pub bar: Option<(u32, u32)>,
+    /// position, in beats, within the bar
+    #[serde(default)]
+    pub beat: Option<(u32, u32)>,
+}
+
+/// parameters of a single voice module, which the host's voice allocator drives
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct VoiceParams {
+    /// set to the note's frequency, in Hz
+    #[serde(default)]
+    pub freq: Option<(u32, u32)>,
+    /// set to 1.0 on note on and 0.0 on note off
+    #[serde(default)]
+    pub gate: Option<(u32, u32)>,
+    /// set to the note's velocity, from 0.0 to 1.0
+    #[serde(default)]
+    pub gain: Option<(u32, u32)>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Info {
     pub name: String,
+    #[serde(default)]
     pub vendor: String,
+    #[serde(default)]
     pub presets: u32,
     pub parameters: u32,
     pub inputs: i32,
     pub outputs: i32,
+    #[serde(default)]
     pub midi_inputs: u32,
+    #[serde(default)]
     pub midi_outputs: u32,
+    #[serde(default)]
     pub id: u32,
+    #[serde(default)]
     pub version: u32,
+    #[serde(default)]
     pub category: String,
+    #[serde(default)]
     pub initial_delay: u32,
+    #[serde(default)]
     pub preset_chunks: bool,
+    #[serde(default)]
     pub f64_precision: bool,
+    #[serde(default)]
     pub silent_when_stopped: bool,
 }
 
+/// descriptive information about a module, all optional
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Metadata {
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// image, relative to the module repository's URL
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// SPDX license identifier, e.g. MIT
+    #[serde(default)]
+    pub license: Option<String>,
+}
+
+/// ed25519 signature over a bundle, which covers its wasm files through their hashes
+#[derive(Deserialize, Debug, Clone)]
+pub struct BundleSignature {
+    /// public key, hex encoded
+    pub key: String,
+    /// signature, hex encoded
+    pub value: String,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Bundle {
+    #[serde(default = "legacy_format_version")]
+    pub format_version: u32,
     pub wasm_url: Vec<String>,
+    /// SHA-256, hex encoded, of each wasm file, checked before it is run
+    #[serde(default)]
+    pub wasm_sha256: Vec<String>,
     pub gui: GUIBundle,
     pub info: Info,
+    #[serde(default)]
+    pub metadata: Metadata,
+    #[serde(default)]
+    pub signature: Option<BundleSignature>,
 }
 
 impl Bundle {
+    /// parse a bundle, migrating it from an older format version, printing the bundle's
+    /// diagnostics if it is invalid. Migration warnings are not printed, as bundles are
+    /// loaded repeatedly, they are reported by validate.
     pub fn from_json(data: &str) -> Result<Self> {
-        let bundle : serde_json::Result<Bundle> = serde_json::from_str(data);
+        let bundle = Self::parse(data);
         //println!("{:?}", bundle);
-        bundle.map_or(err(), |b| ok(b))
+        bundle.map_or_else(|_| {
+            eprintln!("Invalid module bundle:");
+            for diagnostic in validate_bundle_json(data) {
+                eprintln!("  {}", diagnostic);
+            }
+            err()
+        }, |b| ok(b))
+    }
+
+    /// parse a bundle, migrating it from an older format version, without printing anything
+    pub fn parse(data: &str) -> serde_json::Result<Self> {
+        serde_json::from_str::<Json>(data).and_then(|mut json| {
+            Self::migrate(&mut json);
+            serde_json::from_value(json)
+        })
+    }
+
+    /// migrate a bundle's JSON, from an older format version, to the current version,
+    /// returning a warning for each change made. Bundles from a newer version are
+    /// left as they are, as fields they add are ignored.
+    pub fn migrate(json: &mut Json) -> Vec<Diagnostic> {
+        let mut warnings = Vec::new();
+        let version = match json.get("format_version") {
+            Some(version) => match version.as_u64() {
+                Some(version) => version as u32,
+                // reported by validation
+                None => return warnings,
+            },
+            None => legacy_format_version(),
+        };
+        if version > FORMAT_VERSION {
+            warnings.push(Diagnostic::warning(
+                "$.format_version",
+                format!("version {} is newer than supported version {}, new fields are ignored", version, FORMAT_VERSION)));
+            return warnings;
+        }
+        if version == FORMAT_VERSION {
+            return warnings;
+        }
+
+        warnings.push(Diagnostic::warning(
+            "$.format_version",
+            format!("migrated from version {} to {}", version, FORMAT_VERSION)));
+        if version < 2 {
+            Self::migrate_v1(json, &mut warnings);
+        }
+        if let Some(bundle) = json.as_object_mut() {
+            bundle.insert("format_version".to_string(), FORMAT_VERSION.into());
+        }
+        warnings
+    }
+
+    // version 1 required every field of info, so those that are missing are defaulted,
+    // and had no metadata, so the vendor is taken as the author
+    fn migrate_v1(json: &mut Json, warnings: &mut Vec<Diagnostic>) {
+        const DEFAULTED: &[&str] = &[
+            "vendor", "presets", "midi_inputs", "midi_outputs", "id", "version", "category",
+            "initial_delay", "preset_chunks", "f64_precision", "silent_when_stopped",
+        ];
+        let vendor = match json.get("info").and_then(|info| info.as_object()) {
+            Some(info) => {
+                for name in DEFAULTED.iter().filter(|name| !info.contains_key(**name)) {
+                    warnings.push(Diagnostic::warning(
+                        &format!("$.info.{}", name),
+                        "missing from a version 1 bundle, using the default".to_string()));
+                }
+                info.get("vendor").and_then(|vendor| vendor.as_str()).map(|vendor| vendor.to_string())
+            },
+            None => None,
+        };
+
+        if let (Some(vendor), Some(bundle)) = (vendor, json.as_object_mut()) {
+            if !bundle.contains_key("metadata") {
+                bundle.insert("metadata".to_string(), serde_json::json!({ "author": vendor }));
+            }
+        }
     }
 }
 
@@ -61,6 +235,12 @@ pub struct Modules {
 impl Modules {
     pub fn from_json(data: &str) -> Result<Self> {
         let modules : serde_json::Result<Modules> = serde_json::from_str(data);
-        modules.map_or(err(), |b| ok(b))
+        modules.map_or_else(|_| {
+            eprintln!("Invalid modules.json:");
+            for diagnostic in validate_modules_json(data) {
+                eprintln!("  {}", diagnostic);
+            }
+            err()
+        }, |b| ok(b))
     }
 }
\ No newline at end of file
@@ -0,0 +1,362 @@
+//!
+//! On disk cache of fetched modules, modules.json, bundles, and wasm, revalidated with
+//! ETag and Last-Modified, which is used when the server can not be reached. The GUI,
+//! the host's and each module's, is served to the webview through the cache, over
+//! loopback HTTP, so it is also available offline.
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use once_cell::sync::OnceCell;
+use serde_json::Value as Json;
+
+use crate::archive::{content_type, read_request, respond};
+use crate::trust::sha256_hex;
+use crate::utils::{err, ok, fetch, get_vec, Response, Result};
+
+/// the cache, once initialized from the command line
+static CACHE: OnceCell<Cache> = OnceCell::new();
+/// repositories and the URLs of the servers of their cached GUI assets, once started
+static SERVERS: OnceCell<Vec<(String, String)>> = OnceCell::new();
+
+/// options for the cache, from the command line
+#[derive(Clone, Debug)]
+pub struct CacheOptions {
+    /// cache directory, or the default in the user's cache directory
+    pub dir: Option<String>,
+    /// do not fetch from the network, only from the cache
+    pub offline: bool,
+}
+
+/// the default cache directory, $XDG_CACHE_HOME/aa_standalone or ~/.cache/aa_standalone
+pub fn default_cache_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .map(|cache| cache.join("aa_standalone"))
+}
+
+/// initialize the cache used by utils::get_vec, if not called fetches are not cached
+pub fn init(options: &CacheOptions) -> std::result::Result<(), String> {
+    let dir = options.dir.as_ref().map(PathBuf::from)
+        .or_else(default_cache_dir)
+        .ok_or_else(|| "no cache directory".to_string())?;
+    fs::create_dir_all(&dir).map_err(|e| format!("{} {}", dir.display(), e))?;
+    let _ = CACHE.set(Cache { dir, offline: options.offline });
+    Ok(())
+}
+
+/// the cache, if initialized
+pub fn cache() -> Option<&'static Cache> {
+    CACHE.get()
+}
+
+// a cached response
+struct Entry {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body: Vec<u8>,
+}
+
+/// responses, keyed by URL, each stored as a body and a JSON file with its URL and
+/// validators
+pub struct Cache {
+    dir: PathBuf,
+    offline: bool,
+}
+
+impl Cache {
+    // paths of a URL's body and metadata
+    fn paths(&self, url: &str) -> (PathBuf, PathBuf) {
+        let key = sha256_hex(url.as_bytes());
+        (self.dir.join(format!("{}.data", key)), self.dir.join(format!("{}.json", key)))
+    }
+
+    fn load(&self, url: &str) -> Option<Entry> {
+        let (data, meta) = self.paths(url);
+        let meta: Json = serde_json::from_slice(&fs::read(meta).ok()?).ok()?;
+        // guard against (unlikely) collisions
+        if meta.get("url").and_then(|u| u.as_str()) != Some(url) {
+            return None;
+        }
+        let validator = |name: &str| meta.get(name).and_then(|v| v.as_str()).map(|v| v.to_string());
+        Some(Entry {
+            etag: validator("etag"),
+            last_modified: validator("last_modified"),
+            body: fs::read(data).ok()?,
+        })
+    }
+
+    // store a response, body first, then metadata, each written to a temporary file and
+    // renamed, so that a partial write is never loaded
+    fn store(&self, url: &str, response: &Response) {
+        let (data, meta) = self.paths(url);
+        let json = serde_json::json!({
+            "url": url,
+            "etag": response.header("etag"),
+            "last_modified": response.header("last-modified"),
+        });
+        let write = |path: &Path, bytes: &[u8]| {
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, bytes).and_then(|_| fs::rename(&tmp, path))
+        };
+        if let Err(e) = write(&data, &response.body).and_then(|_| write(&meta, json.to_string().as_bytes())) {
+            eprintln!("Failed to cache {} {}", url, e);
+        }
+    }
+
+    /// fetch a URL, revalidating a cached copy if there is one. The cached copy is used
+    /// if offline, or if the server can not be reached, fails, or the URL is not found.
+    pub fn get(&self, url: &str, progress: &mut dyn FnMut(u64, u64) -> bool) -> Result<Vec<u8>> {
+        // only remote URLs are cached
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return fetch(url, &[], progress).map(|response| response.body);
+        }
+
+        let entry = self.load(url);
+        // loopback, e.g. a module archive, is reachable without a network
+        if self.offline && !is_loopback(url) {
+            return match entry {
+                Some(entry) => ok(entry.body),
+                None => {
+                    eprintln!("{} is not cached, and offline", url);
+                    err()
+                },
+            };
+        }
+
+        let mut headers = Vec::new();
+        if let Some(entry) = &entry {
+            if let Some(etag) = &entry.etag {
+                headers.push(format!("If-None-Match: {}", etag));
+            }
+            if let Some(last_modified) = &entry.last_modified {
+                headers.push(format!("If-Modified-Since: {}", last_modified));
+            }
+        }
+
+        match (fetch(url, &headers, progress), entry) {
+            (Ok(response), Some(entry)) if response.code == 304 => ok(entry.body),
+            (Ok(response), _) => {
+                let no_store = response.header("cache-control").map_or(false, |c| c.contains("no-store"));
+                if (200..300).contains(&response.code) && !no_store {
+                    self.store(url, &response);
+                }
+                ok(response.body)
+            },
+            (Err(_), Some(entry)) => {
+                eprintln!("{} could not be fetched, using cached copy", url);
+                ok(entry.body)
+            },
+            (Err(_), None) => err(),
+        }
+    }
+}
+
+fn is_loopback(url: &str) -> bool {
+    let host = url.splitn(2, "://").nth(1).unwrap_or("");
+    host.starts_with("127.0.0.1") || host.starts_with("localhost") || host.starts_with("[::1]")
+}
+
+//-----------------------------------------------------------------------------
+
+/// serve fetches, through the cache, over HTTP on loopback, so that the webview loads
+/// GUI assets from the cache. Each remote repository is served by its own listener, so
+/// that each has its own origin, with its URL's path below the server's, so relative
+/// URLs within a GUI resolve to the same server, e.g. for the repository
+/// https://example.com/modules, https://example.com/modules/gui/index.html is served at
+/// http://127.0.0.1:<port>/gui/index.html. Only URLs within a repository are served.
+pub fn serve(repositories: &[String]) -> std::result::Result<(), String> {
+    if cache().is_none() {
+        return Err("no cache".to_string());
+    }
+    let mut servers: Vec<(String, String)> = Vec::new();
+    for repository in repositories.iter() {
+        let repository = repository.trim_end_matches('/');
+        // loopback and file URLs are loaded directly
+        let remote = repository.starts_with("http://") || repository.starts_with("https://");
+        if !remote || is_loopback(repository) || servers.iter().any(|(r, _)| r == repository) {
+            continue;
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|e| e.to_string())?;
+        let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+        let prefix = repository.to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    let prefix = prefix.clone();
+                    thread::spawn(move || {
+                        let _ = serve_request(stream, &prefix);
+                    });
+                }
+            }
+        });
+        servers.push((repository.to_string(), format!("http://127.0.0.1:{}", port)));
+    }
+
+    let _ = SERVERS.set(servers);
+    Ok(())
+}
+
+/// the URL the webview should load url from, served from the cache by its repository's
+/// server, if started. Any other URL is loaded directly.
+pub fn local_url(url: &str) -> String {
+    SERVERS.get()
+        .and_then(|servers| local_path(servers, url))
+        .unwrap_or_else(|| url.to_string())
+}
+
+// the URL of url on the server of the repository it is within, the longest match
+fn local_path(servers: &[(String, String)], url: &str) -> Option<String> {
+    servers.iter()
+        .filter(|(repository, _)| {
+            matches!(url.strip_prefix(&repository[..]), Some(path) if path.is_empty() || path.starts_with('/'))
+        })
+        .max_by_key(|(repository, _)| repository.len())
+        .map(|(repository, server)| format!("{}{}", server, &url[repository.len()..]))
+}
+
+// the URL of a request's target, within repository, or None if the target could leave
+// it, e.g. with a dot segment, which is removed before the request is sent
+fn repository_url(repository: &str, target: &str) -> Option<String> {
+    let target = target.split('#').next().unwrap_or("");
+    let path = target.split('?').next().unwrap_or("");
+    let lower = path.to_ascii_lowercase();
+    let leaves = path.split('/').any(|segment| segment == "." || segment == "..")
+        || lower.contains("%2e") || lower.contains("%2f") || path.contains('\\');
+    if !target.starts_with('/') || leaves {
+        return None;
+    }
+    Some(format!("{}{}", repository, target))
+}
+
+// serve a single request, for a URL within repository, fetched through the cache
+fn serve_request(mut stream: TcpStream, repository: &str) -> std::io::Result<()> {
+    let (method, target) = read_request(&stream)?;
+    let url = match repository_url(repository, &target) {
+        Some(url) => url,
+        None => return respond(&mut stream, &method, "", None),
+    };
+    if method != "GET" && method != "HEAD" {
+        return respond(&mut stream, &method, "", Some(&[][..]));
+    }
+    let path = url.split('?').next().unwrap_or("");
+    let body = get_vec(&url).ok();
+    respond(&mut stream, &method, content_type(path), body.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::thread::JoinHandle;
+
+    fn temp_cache(name: &str, offline: bool) -> Cache {
+        let dir = std::env::temp_dir().join(format!("aa_cache_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Cache { dir, offline }
+    }
+
+    // a stand-in server, which sends each response in turn, then stops, returning the
+    // headers of each request
+    fn stand_in(responses: Vec<&'static str>) -> (String, JoinHandle<Vec<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut headers = Vec::new();
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    headers.push(line.trim().to_lowercase());
+                    line.clear();
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+                requests.push(headers);
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    const OK: &str = "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 6\r\nConnection: close\r\n\r\ncached";
+    const NOT_MODIFIED: &str = "HTTP/1.1 304 Not Modified\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+    #[test]
+    fn unchanged_copies_are_revalidated_not_downloaded() {
+        let cache = temp_cache("hit", false);
+        let (url, server) = stand_in(vec![OK, NOT_MODIFIED]);
+        let url = format!("{}/modules.json", url);
+
+        assert_eq!(cache.get(&url, &mut |_, _| true).unwrap(), b"cached");
+        assert_eq!(cache.get(&url, &mut |_, _| true).unwrap(), b"cached");
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].iter().any(|h| h.starts_with("if-none-match")));
+        assert!(requests[1].contains(&"if-none-match: \"v1\"".to_string()));
+    }
+
+    #[test]
+    fn stale_copies_are_used_once_the_server_stops() {
+        let cache = temp_cache("stale", false);
+        let (url, server) = stand_in(vec![OK]);
+        let url = format!("{}/modules.json", url);
+
+        assert_eq!(cache.get(&url, &mut |_, _| true).unwrap(), b"cached");
+        server.join().unwrap();
+        assert_eq!(cache.get(&url, &mut |_, _| true).unwrap(), b"cached");
+    }
+
+    #[test]
+    fn uncached_urls_fail_once_the_server_stops() {
+        let cache = temp_cache("miss", false);
+        let (url, server) = stand_in(vec![OK]);
+
+        assert!(cache.get(&format!("{}/modules.json", url), &mut |_, _| true).is_ok());
+        server.join().unwrap();
+        assert!(cache.get(&format!("{}/bundle.json", url), &mut |_, _| true).is_err());
+    }
+
+    #[test]
+    fn offline_uses_only_the_cache() {
+        let cache = temp_cache("offline", true);
+        let url = "https://example.com/modules/modules.json";
+        cache.store(url, &Response { code: 200, headers: Vec::new(), body: b"cached".to_vec() });
+
+        assert_eq!(cache.get(url, &mut |_, _| true).unwrap(), b"cached");
+        assert!(cache.get("https://example.com/modules/bundle.json", &mut |_, _| true).is_err());
+    }
+
+    #[test]
+    fn urls_are_served_by_their_repository() {
+        let servers = vec![
+            ("https://example.com/modules".to_string(), "http://127.0.0.1:1".to_string()),
+            ("https://example.com/modules/more".to_string(), "http://127.0.0.1:2".to_string()),
+        ];
+        let local = |url: &str| local_path(&servers, url);
+
+        assert_eq!(local("https://example.com/modules/gui/index.html").unwrap(), "http://127.0.0.1:1/gui/index.html");
+        assert_eq!(local("https://example.com/modules/more/index.html").unwrap(), "http://127.0.0.1:2/index.html");
+        assert_eq!(local("https://example.com/modules"), Some("http://127.0.0.1:1".to_string()));
+        assert_eq!(local("https://example.com/modulesx/index.html"), None);
+        assert_eq!(local("https://example.org/modules/index.html"), None);
+    }
+
+    #[test]
+    fn targets_outside_the_repository_are_rejected() {
+        let repository = "https://example.com/modules";
+        assert_eq!(repository_url(repository, "/gui/index.html?v=1#top").unwrap(), "https://example.com/modules/gui/index.html?v=1");
+        for target in ["/../secret", "/gui/../../secret", "/%2e%2e/secret", "/%2E%2e/secret", "/..%2fsecret", "/..\\secret", "@evil.com/", "http://evil.com/"].iter() {
+            assert_eq!(repository_url(repository, target), None, "{}", target);
+        }
+    }
+}
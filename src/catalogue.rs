@@ -0,0 +1,289 @@
+//!
+//! Catalogue of the modules of all repositories, from their bundles' info and metadata,
+//! which the GUI can browse and search
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use serde::Serialize;
+
+use crate::repository::*;
+
+/// what a module is, from its inputs
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// played with MIDI, without audio inputs
+    Instrument,
+    /// processes audio inputs
+    Effect,
+    /// neither, e.g. a drone
+    Generator,
+    /// info could not be fetched
+    Unknown,
+}
+
+/// a module, as sent to the GUI
+#[derive(Serialize, Clone, Debug)]
+pub struct CatalogueEntry {
+    /// key to select the module with, as sent with AddModule
+    pub key: String,
+    pub name: String,
+    pub repository: String,
+    pub category: String,
+    pub kind: Kind,
+    pub vendor: String,
+    pub version: u32,
+    pub inputs: i32,
+    pub outputs: i32,
+    pub midi_inputs: u32,
+    pub midi_outputs: u32,
+    pub author: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl CatalogueEntry {
+    fn new(modules: &ModuleList, module: &ModuleEntry) -> Self {
+        let info = module.info.as_ref();
+        let inputs = info.map_or(0, |info| info.inputs);
+        let midi_inputs = info.map_or(0, |info| info.midi_inputs);
+        let kind = match info {
+            None => Kind::Unknown,
+            Some(_) if inputs > 0 => Kind::Effect,
+            Some(_) if midi_inputs > 0 => Kind::Instrument,
+            Some(_) => Kind::Generator,
+        };
+        Self {
+            key: modules.key(module),
+            name: module.name.clone(),
+            repository: modules.repositories[module.repository].name.clone(),
+            category: module.category.clone(),
+            kind,
+            vendor: info.map_or(String::new(), |info| info.vendor.clone()),
+            version: info.map_or(0, |info| info.version),
+            inputs,
+            outputs: info.map_or(0, |info| info.outputs),
+            midi_inputs,
+            midi_outputs: info.map_or(0, |info| info.midi_outputs),
+            author: module.metadata.author.clone(),
+            description: module.metadata.description.clone(),
+            tags: module.metadata.tags.clone(),
+        }
+    }
+
+    // true if any of the entry's text contains the word, which is lower case
+    fn contains(&self, word: &str) -> bool {
+        let fields = [&self.name, &self.category, &self.vendor, &self.repository];
+        fields.iter().any(|f| f.to_lowercase().contains(word))
+            || self.description.as_ref().map_or(false, |d| d.to_lowercase().contains(word))
+            || self.author.as_ref().map_or(false, |a| a.to_lowercase().contains(word))
+            || self.tags.iter().any(|t| t.to_lowercase().contains(word))
+    }
+}
+
+/// a condition a module must meet to match a query
+#[derive(Clone, Debug, PartialEq)]
+enum Filter {
+    /// word in its name, category, vendor, author, description, or tags
+    Text(String),
+    Kind(Kind),
+    Category(String),
+    Vendor(String),
+    Tag(String),
+    Repository(String),
+    MidiIn,
+    MidiOut,
+    Inputs(i32),
+    Outputs(i32),
+}
+
+// words that add nothing to a query, e.g. "instruments with midi in"
+const IGNORED: &[&str] = &["a", "an", "and", "the", "with", "for"];
+
+/// a search of the catalogue, all of whose filters a module must match, parsed from words,
+/// e.g. "stereo effects", "instruments with midi in", "category:synth reverb"
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    filters: Vec<Filter>,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Self {
+        let query = query.to_lowercase();
+        let mut words = query.split_whitespace().peekable();
+        let mut filters = Vec::new();
+        while let Some(word) = words.next() {
+            let filter = match word.find(':') {
+                Some(i) => {
+                    let (field, value) = (&word[..i], word[i + 1..].to_string());
+                    match field {
+                        "category" => Filter::Category(value),
+                        "vendor" => Filter::Vendor(value),
+                        "tag" => Filter::Tag(value),
+                        "repository" | "repo" => Filter::Repository(value),
+                        "kind" => match Self::kind(&value) {
+                            Some(kind) => Filter::Kind(kind),
+                            None => Filter::Text(value),
+                        },
+                        "midi" if value == "out" => Filter::MidiOut,
+                        "midi" => Filter::MidiIn,
+                        "inputs" => Filter::Inputs(value.parse().unwrap_or(0)),
+                        "outputs" => Filter::Outputs(value.parse().unwrap_or(0)),
+                        _ => Filter::Text(word.to_string()),
+                    }
+                },
+                None => match word {
+                    "mono" => Filter::Outputs(1),
+                    "stereo" => Filter::Outputs(2),
+                    // "midi in", "midi out", or just "midi"
+                    "midi" => match words.peek() {
+                        Some(&"out") => { words.next(); Filter::MidiOut },
+                        Some(&"in") => { words.next(); Filter::MidiIn },
+                        _ => Filter::MidiIn,
+                    },
+                    word if IGNORED.contains(&word) => continue,
+                    word => match Self::kind(word) {
+                        Some(kind) => Filter::Kind(kind),
+                        None => Filter::Text(word.to_string()),
+                    },
+                },
+            };
+            filters.push(filter);
+        }
+        Self { filters }
+    }
+
+    fn kind(word: &str) -> Option<Kind> {
+        match word.trim_end_matches('s') {
+            "instrument" | "synth" => Some(Kind::Instrument),
+            "effect" | "fx" => Some(Kind::Effect),
+            "generator" => Some(Kind::Generator),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, entry: &CatalogueEntry) -> bool {
+        let eq = |a: &str, b: &str| a.to_lowercase() == b;
+        self.filters.iter().all(|filter| match filter {
+            Filter::Text(word) => entry.contains(word),
+            Filter::Kind(kind) => entry.kind == *kind,
+            Filter::Category(category) => eq(&entry.category, category),
+            Filter::Vendor(vendor) => eq(&entry.vendor, vendor),
+            Filter::Tag(tag) => entry.tags.iter().any(|t| eq(t, tag)),
+            Filter::Repository(repository) => eq(&entry.repository, repository),
+            Filter::MidiIn => entry.midi_inputs > 0,
+            Filter::MidiOut => entry.midi_outputs > 0,
+            Filter::Inputs(inputs) => entry.inputs == *inputs,
+            Filter::Outputs(outputs) => entry.outputs == *outputs,
+        })
+    }
+}
+
+/// the modules of all repositories, in the order of the GUI's module list
+#[derive(Clone, Debug, Default)]
+pub struct Catalogue {
+    pub entries: Vec<CatalogueEntry>,
+}
+
+impl Catalogue {
+    pub fn new(modules: &ModuleList) -> Self {
+        Self {
+            entries: modules.modules.iter().map(|m| CatalogueEntry::new(modules, m)).collect(),
+        }
+    }
+
+    /// all entries, as a JSON array, for the GUI
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.entries).unwrap_or_else(|_| "[]".to_string())
+    }
+
+    /// keys of the modules matching a query, all if it is empty
+    pub fn search(&self, query: &str) -> Vec<&str> {
+        let query = Query::parse(query);
+        self.entries.iter()
+            .filter(|entry| query.matches(entry))
+            .map(|entry| &entry.key[..])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, category: &str, inputs: i32, outputs: i32, midi_inputs: u32) -> CatalogueEntry {
+        let kind = match (inputs, midi_inputs) {
+            (0, 0) => Kind::Generator,
+            (0, _) => Kind::Instrument,
+            _ => Kind::Effect,
+        };
+        CatalogueEntry {
+            key: name.to_lowercase(),
+            name: name.to_string(),
+            repository: "AA".to_string(),
+            category: category.to_string(),
+            kind,
+            vendor: "Muses".to_string(),
+            version: 1,
+            inputs,
+            outputs,
+            midi_inputs,
+            midi_outputs: 0,
+            author: None,
+            description: None,
+            tags: vec!["analog".to_string()],
+        }
+    }
+
+    fn catalogue() -> Catalogue {
+        let mut hall = entry("Hall", "Reverb", 2, 2, 0);
+        hall.description = Some("A warm room".to_string());
+        Catalogue {
+            entries: vec![
+                entry("Sine", "Synth", 0, 1, 1),
+                entry("Pad", "Synth", 0, 2, 1),
+                hall,
+                entry("Delay", "Delay", 1, 1, 0),
+                entry("Drone", "Synth", 0, 2, 0),
+            ],
+        }
+    }
+
+    #[test]
+    fn queries_are_parsed_to_filters() {
+        assert_eq!(Query::parse("instruments with midi in").filters, vec![Filter::Kind(Kind::Instrument), Filter::MidiIn]);
+        assert_eq!(Query::parse("stereo effects").filters, vec![Filter::Outputs(2), Filter::Kind(Kind::Effect)]);
+        assert_eq!(Query::parse("category:Synth reverb").filters, vec![Filter::Category("synth".to_string()), Filter::Text("reverb".to_string())]);
+        assert_eq!(Query::parse("midi out kind:generator").filters, vec![Filter::MidiOut, Filter::Kind(Kind::Generator)]);
+        assert_eq!(Query::parse("kind:drone colour:red").filters, vec![Filter::Text("drone".to_string()), Filter::Text("colour:red".to_string())]);
+        assert!(Query::parse("the and a").filters.is_empty());
+    }
+
+    #[test]
+    fn instruments_with_midi_in_match() {
+        assert_eq!(catalogue().search("instruments with midi in"), vec!["sine", "pad"]);
+    }
+
+    #[test]
+    fn stereo_effects_match() {
+        assert_eq!(catalogue().search("stereo effects"), vec!["hall"]);
+        assert_eq!(catalogue().search("mono fx"), vec!["delay"]);
+    }
+
+    #[test]
+    fn fields_match_exactly_and_text_anywhere() {
+        assert_eq!(catalogue().search("category:synth"), vec!["sine", "pad", "drone"]);
+        assert_eq!(catalogue().search("category:synth stereo"), vec!["pad", "drone"]);
+        assert!(catalogue().search("category:synt").is_empty());
+        // in the Hall's category, and its description
+        assert_eq!(catalogue().search("reverb"), vec!["hall"]);
+        assert_eq!(catalogue().search("warm"), vec!["hall"]);
+        assert_eq!(catalogue().search("vendor:muses tag:analog generator"), vec!["drone"]);
+    }
+
+    #[test]
+    fn empty_queries_match_everything() {
+        assert_eq!(catalogue().search("").len(), 5);
+    }
+}
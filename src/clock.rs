@@ -0,0 +1,528 @@
+//!
+//! Host tempo clock and transport, which may follow incoming MIDI clock or an Ableton
+//! Link session, and sends MIDI clock out
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use midir::MidiOutput;
+
+/// MIDI clock ticks per beat (quarter note)
+const TICKS_PER_BEAT: f64 = 24.0;
+/// largest song position, in 16th notes
+const MAX_SONG_POSITION: f64 = 16383.0;
+/// weight given to each new tempo estimate from incoming MIDI clock
+const TEMPO_SMOOTHING: f64 = 0.1;
+
+const MIDI_CLOCK: u8 = 0xF8;
+const MIDI_START: u8 = 0xFA;
+const MIDI_CONTINUE: u8 = 0xFB;
+const MIDI_STOP: u8 = 0xFC;
+const MIDI_SONG_POSITION: u8 = 0xF2;
+
+/// what drives the clock's tempo and transport
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockSource {
+    Internal,
+    /// follow MIDI clock, start, stop, and song position from MIDI input
+    Midi,
+    /// share tempo, beat, and start/stop with Ableton Link peers (requires link feature)
+    Link,
+}
+
+impl FromStr for ClockSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "internal" => Ok(ClockSource::Internal),
+            "midi" => Ok(ClockSource::Midi),
+            "link" => Ok(ClockSource::Link),
+            _ => Err(format!("unknown clock source: {}", s)),
+        }
+    }
+}
+
+/// options for the clock, from the command line
+#[derive(Clone, Debug)]
+pub struct ClockOptions {
+    /// tempo, in BPM
+    pub tempo: f64,
+    pub beats_per_bar: u32,
+    pub source: ClockSource,
+    /// MIDI output device to send clock to
+    pub midi_out: Option<String>,
+}
+
+// shared between the audio thread's clock and the MIDI clock out thread
+struct Shared {
+    /// f64 bits
+    tempo: AtomicU64,
+    /// position, in beats, when playing last changed, f64 bits
+    position: AtomicU64,
+    playing: AtomicBool,
+    exit: AtomicBool,
+}
+
+/// the host's tempo and transport, advanced from the audio thread
+pub struct Clock {
+    tempo: f64,
+    playing: bool,
+    /// position, in beats, from the start
+    position: f64,
+    beats_per_bar: u32,
+    source: ClockSource,
+    /// MIDI clock ticks received since start
+    ticks: u64,
+    /// MIDI start or continue has been received, and the next tick is at the current
+    /// position, rather than a tick later
+    awaiting_tick: bool,
+    /// frame at which the last MIDI clock tick was received
+    last_tick: Option<u64>,
+    shared: Arc<Shared>,
+    /// output latency of the stream, in seconds, the clock is for the buffer being heard
+    output_latency: f64,
+    /// Link session, while following Link
+    #[cfg(feature = "link")]
+    link: Option<crate::link::LinkSession>,
+    /// joins and leaves Link sessions, off the audio thread
+    #[cfg(feature = "link")]
+    link_handoff: crate::link::LinkHandoff,
+    /// a session has been requested from link_handoff, and not yet joined
+    #[cfg(feature = "link")]
+    link_pending: bool,
+}
+
+impl Clock {
+    pub fn new(options: &ClockOptions) -> Self {
+        let tempo = options.tempo.max(1.0);
+        let shared = Arc::new(Shared {
+            tempo: AtomicU64::new(tempo.to_bits()),
+            position: AtomicU64::new(0.0f64.to_bits()),
+            playing: AtomicBool::new(false),
+            exit: AtomicBool::new(false),
+        });
+        if let Some(device) = &options.midi_out {
+            if let Err(e) = spawn_clock_out(device, shared.clone()) {
+                eprintln!("Failed to open MIDI clock output {} {}", device, e);
+            }
+        }
+        let mut clock = Self {
+            tempo,
+            playing: options.source != ClockSource::Midi,
+            position: 0.0,
+            beats_per_bar: options.beats_per_bar.max(1),
+            source: ClockSource::Internal,
+            ticks: 0,
+            awaiting_tick: false,
+            last_tick: None,
+            shared,
+            output_latency: 0.0,
+            #[cfg(feature = "link")]
+            link: None,
+            #[cfg(feature = "link")]
+            link_handoff: crate::link::LinkHandoff::spawn(),
+            #[cfg(feature = "link")]
+            link_pending: false,
+        };
+        clock.set_source(options.source);
+        clock
+    }
+
+    /// tempo, in BPM
+    #[inline]
+    pub fn tempo(&self) -> f64 {
+        self.tempo
+    }
+
+    #[inline]
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// position, in beats, from the start
+    #[inline]
+    pub fn position(&self) -> f64 {
+        self.position
+    }
+
+    /// bar, counting from 0
+    #[inline]
+    pub fn bar(&self) -> u64 {
+        (self.position / self.beats_per_bar as f64) as u64
+    }
+
+    /// position, in beats, within the current bar
+    #[inline]
+    pub fn beat(&self) -> f64 {
+        self.position % self.beats_per_bar as f64
+    }
+
+    #[inline]
+    pub fn source(&self) -> ClockSource {
+        self.source
+    }
+
+    pub fn set_tempo(&mut self, tempo: f64) {
+        self.tempo = tempo.max(1.0).min(999.0);
+        self.shared.tempo.store(self.tempo.to_bits(), Ordering::Relaxed);
+        #[cfg(feature = "link")]
+        {
+            if let Some(link) = self.link.as_mut() {
+                link.set_tempo(self.tempo);
+            }
+        }
+    }
+
+    pub fn set_beats_per_bar(&mut self, beats: u32) {
+        self.beats_per_bar = beats.max(1);
+        #[cfg(feature = "link")]
+        {
+            if let Some(link) = self.link.as_mut() {
+                link.set_quantum(self.beats_per_bar as f64);
+            }
+        }
+    }
+
+    /// set the stream's output latency, in seconds
+    pub fn set_output_latency(&mut self, latency: f64) {
+        self.output_latency = latency.max(0.0);
+    }
+
+    /// set the clock source, joining or leaving a Link session. Sessions are joined, and
+    /// left, by another thread, Link is followed once joined.
+    pub fn set_source(&mut self, source: ClockSource) {
+        self.last_tick = None;
+        #[cfg(feature = "link")]
+        {
+            if source == ClockSource::Link {
+                if self.link.is_none() && !self.link_pending {
+                    self.link_pending = self.link_handoff.join(self.tempo, self.beats_per_bar as f64);
+                }
+            }
+            else if let Some(link) = self.link.take() {
+                self.link_handoff.leave(link);
+            }
+        }
+        #[cfg(not(feature = "link"))]
+        {
+            if source == ClockSource::Link {
+                eprintln!("Link clock requested, but not enabled (build with --features link)");
+                return;
+            }
+        }
+        self.source = source;
+    }
+
+    /// play, from the start if rewind
+    pub fn play(&mut self, rewind: bool) {
+        if rewind {
+            self.position = 0.0;
+            self.ticks = 0;
+        }
+        self.set_playing(true);
+    }
+
+    pub fn stop(&mut self) {
+        self.set_playing(false);
+    }
+
+    fn set_playing(&mut self, playing: bool) {
+        self.playing = playing;
+        // the position is stored first, so clock out sends it with the change
+        self.shared.position.store(self.position.to_bits(), Ordering::Release);
+        self.shared.playing.store(playing, Ordering::Release);
+        #[cfg(feature = "link")]
+        {
+            if let Some(link) = self.link.as_mut() {
+                link.set_playing(playing);
+            }
+        }
+    }
+
+    /// set position, in beats
+    pub fn locate(&mut self, position: f64) {
+        self.position = position.max(0.0);
+        self.ticks = (self.position * TICKS_PER_BEAT) as u64;
+    }
+
+    /// handle a MIDI system message, received at frame, returns false if it was not a
+    /// clock or transport message. Ignored unless following MIDI clock.
+    pub fn midi(&mut self, data: &[u8], frame: u64, sample_rate: f64) -> bool {
+        match data.first() {
+            Some(&MIDI_CLOCK) | Some(&MIDI_START) | Some(&MIDI_CONTINUE)
+                | Some(&MIDI_STOP) | Some(&MIDI_SONG_POSITION) => { },
+            _ => return false,
+        }
+        if self.source != ClockSource::Midi {
+            return true;
+        }
+        match data[0] {
+            MIDI_CLOCK => {
+                // estimate tempo from the time between ticks
+                if let Some(last) = self.last_tick {
+                    let seconds = frame.saturating_sub(last) as f64 / sample_rate;
+                    if seconds > 0.0 {
+                        let tempo = 60.0 / (seconds * TICKS_PER_BEAT);
+                        self.set_tempo(self.tempo + (tempo - self.tempo) * TEMPO_SMOOTHING);
+                    }
+                }
+                self.last_tick = Some(frame);
+                if self.playing {
+                    if self.awaiting_tick {
+                        self.awaiting_tick = false;
+                    }
+                    else {
+                        self.ticks += 1;
+                    }
+                    self.position = self.ticks as f64 / TICKS_PER_BEAT;
+                }
+            },
+            MIDI_START | MIDI_CONTINUE => {
+                self.play(data[0] == MIDI_START);
+                self.awaiting_tick = true;
+            },
+            MIDI_STOP => self.stop(),
+            _ => {
+                // song position, in 16th notes
+                if data.len() >= 3 {
+                    let sixteenths = (data[1] as u32 & 0x7F) | ((data[2] as u32 & 0x7F) << 7);
+                    self.locate(sixteenths as f64 / 4.0);
+                }
+            },
+        }
+        true
+    }
+
+    /// advance by a buffer of frames. When following MIDI clock the position does not
+    /// run ahead of the next tick, and when following Link it is the session's.
+    pub fn advance(&mut self, frames: usize, sample_rate: f64) {
+        #[cfg(feature = "link")]
+        {
+            if let Some(link) = self.link_handoff.joined() {
+                self.link_pending = false;
+                if self.source == ClockSource::Link {
+                    self.link = Some(link);
+                }
+                else {
+                    self.link_handoff.leave(link);
+                }
+            }
+            if let Some(link) = self.link.as_mut() {
+                let (tempo, beat, playing) = link.capture((self.output_latency * 1.0e6) as i64);
+                self.tempo = tempo;
+                self.position = beat;
+                self.playing = playing;
+                self.shared.tempo.store(tempo.to_bits(), Ordering::Relaxed);
+                self.shared.playing.store(playing, Ordering::Relaxed);
+                return;
+            }
+        }
+        if !self.playing {
+            return;
+        }
+        let beats = frames as f64 / sample_rate * self.tempo / 60.0;
+        self.position += beats;
+        if self.source == ClockSource::Midi {
+            let next_tick = if self.awaiting_tick { self.ticks } else { self.ticks + 1 };
+            self.position = self.position.min(next_tick as f64 / TICKS_PER_BEAT);
+        }
+    }
+}
+
+impl Drop for Clock {
+    fn drop(&mut self) {
+        self.shared.exit.store(true, Ordering::Relaxed);
+    }
+}
+
+// song position pointer, in 16th notes, for a position in beats
+fn song_position(position: f64) -> [u8; 3] {
+    let sixteenths = (position * 4.0).max(0.0).min(MAX_SONG_POSITION) as u16;
+    [MIDI_SONG_POSITION, (sixteenths & 0x7F) as u8, (sixteenths >> 7) as u8]
+}
+
+// send the messages for a change of transport, start from the beginning, or continue from
+// the song position, and stop
+fn send_transport<F: FnMut(&[u8])>(playing: bool, position: f64, mut send: F) {
+    if !playing {
+        send(&[MIDI_STOP]);
+    }
+    else if position <= 0.0 {
+        send(&[MIDI_START]);
+    }
+    else {
+        send(&song_position(position));
+        send(&[MIDI_CONTINUE]);
+    }
+}
+
+// send MIDI clock, continuously, and start, continue, and stop, when transport changes,
+// from a separate thread, to avoid MIDI output in the audio thread
+fn spawn_clock_out(device: &str, shared: Arc<Shared>) -> Result<(), String> {
+    let output = MidiOutput::new("midi clock").map_err(|e| e.to_string())?;
+    let port = output.ports().into_iter()
+        .find(|p| output.port_name(p).map_or(false, |name| name == device))
+        .ok_or_else(|| "no such device".to_string())?;
+    let mut connection = output.connect(&port, "clock").map_err(|e| e.to_string())?;
+
+    thread::spawn(move || {
+        let mut playing = false;
+        let mut next = Instant::now();
+        while !shared.exit.load(Ordering::Relaxed) {
+            let now_playing = shared.playing.load(Ordering::Acquire);
+            if now_playing != playing {
+                playing = now_playing;
+                let position = f64::from_bits(shared.position.load(Ordering::Acquire));
+                send_transport(playing, position, |message| { let _ = connection.send(message); });
+            }
+            let _ = connection.send(&[MIDI_CLOCK]);
+
+            // deadlines are absolute, so timing errors do not accumulate
+            let tempo = f64::from_bits(shared.tempo.load(Ordering::Relaxed));
+            next += Duration::from_secs_f64(60.0 / (tempo * TICKS_PER_BEAT));
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            }
+            else {
+                next = now;
+            }
+        }
+        connection.close();
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+    // frames between ticks at 120 BPM
+    const TICK_FRAMES: u64 = 1000;
+
+    fn clock(source: ClockSource) -> Clock {
+        Clock::new(&ClockOptions { tempo: 100.0, beats_per_bar: 4, source, midi_out: None })
+    }
+
+    fn tick(clock: &mut Clock, frame: u64) {
+        assert!(clock.midi(&[MIDI_CLOCK], frame, SAMPLE_RATE));
+    }
+
+    #[test]
+    fn tempo_is_estimated_from_ticks() {
+        let mut clock = clock(ClockSource::Midi);
+        for t in 0..200 {
+            tick(&mut clock, t * TICK_FRAMES);
+        }
+        assert!((clock.tempo() - 120.0).abs() < 0.01, "{}", clock.tempo());
+        // repeated ticks are ignored
+        tick(&mut clock, 199 * TICK_FRAMES);
+        assert!((clock.tempo() - 120.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn the_first_tick_after_start_is_the_start() {
+        let mut clock = clock(ClockSource::Midi);
+        assert!(!clock.is_playing());
+        tick(&mut clock, 0);
+        assert_eq!(clock.position(), 0.0);
+
+        assert!(clock.midi(&[MIDI_START], 0, SAMPLE_RATE));
+        assert!(clock.is_playing());
+        tick(&mut clock, TICK_FRAMES);
+        assert_eq!(clock.position(), 0.0);
+        tick(&mut clock, 2 * TICK_FRAMES);
+        assert_eq!(clock.position(), 1.0 / 24.0);
+
+        assert!(clock.midi(&[MIDI_STOP], 0, SAMPLE_RATE));
+        assert!(!clock.is_playing());
+        tick(&mut clock, 3 * TICK_FRAMES);
+        assert_eq!(clock.position(), 1.0 / 24.0);
+
+        // continues from where it stopped
+        assert!(clock.midi(&[MIDI_CONTINUE], 0, SAMPLE_RATE));
+        assert!(clock.is_playing());
+        tick(&mut clock, 4 * TICK_FRAMES);
+        assert_eq!(clock.position(), 1.0 / 24.0);
+        tick(&mut clock, 5 * TICK_FRAMES);
+        assert_eq!(clock.position(), 2.0 / 24.0);
+
+        // start rewinds
+        assert!(clock.midi(&[MIDI_START], 0, SAMPLE_RATE));
+        assert_eq!(clock.position(), 0.0);
+    }
+
+    #[test]
+    fn song_position_locates() {
+        let mut clock = clock(ClockSource::Midi);
+        // 18 16th notes is a bar and half a beat
+        assert!(clock.midi(&[MIDI_SONG_POSITION, 18, 0], 0, SAMPLE_RATE));
+        assert_eq!(clock.position(), 4.5);
+        assert_eq!(clock.bar(), 1);
+        assert_eq!(clock.beat(), 0.5);
+        assert!(clock.midi(&[MIDI_CONTINUE], 0, SAMPLE_RATE));
+        tick(&mut clock, 0);
+        assert_eq!(clock.position(), 4.5);
+        tick(&mut clock, TICK_FRAMES);
+        assert_eq!(clock.position(), 109.0 / 24.0);
+
+        // 14 bits
+        assert!(clock.midi(&[MIDI_SONG_POSITION, 0x00, 0x01], 0, SAMPLE_RATE));
+        assert_eq!(clock.position(), 32.0);
+        // too short
+        assert!(clock.midi(&[MIDI_SONG_POSITION, 0x10], 0, SAMPLE_RATE));
+        assert_eq!(clock.position(), 32.0);
+    }
+
+    #[test]
+    fn advance_does_not_run_ahead_of_ticks() {
+        let mut clock = clock(ClockSource::Midi);
+        assert!(clock.midi(&[MIDI_START], 0, SAMPLE_RATE));
+        clock.advance(48_000, SAMPLE_RATE);
+        assert_eq!(clock.position(), 0.0);
+        tick(&mut clock, 0);
+        clock.advance(48_000, SAMPLE_RATE);
+        assert_eq!(clock.position(), 1.0 / 24.0);
+        tick(&mut clock, TICK_FRAMES);
+        clock.advance(10, SAMPLE_RATE);
+        assert!(clock.position() > 1.0 / 24.0 && clock.position() < 2.0 / 24.0);
+    }
+
+    #[test]
+    fn the_internal_clock_ignores_midi() {
+        let mut clock = clock(ClockSource::Internal);
+        assert!(clock.is_playing());
+        assert!(clock.midi(&[MIDI_STOP], 0, SAMPLE_RATE));
+        assert!(clock.is_playing());
+        assert!(!clock.midi(&[0x90, 60, 100], 0, SAMPLE_RATE));
+        assert!(!clock.midi(&[], 0, SAMPLE_RATE));
+        clock.set_tempo(120.0);
+        clock.advance(48_000, SAMPLE_RATE);
+        assert_eq!(clock.position(), 2.0);
+        clock.set_tempo(0.0);
+        assert_eq!(clock.tempo(), 1.0);
+    }
+
+    fn transport(playing: bool, position: f64) -> Vec<Vec<u8>> {
+        let mut sent = Vec::new();
+        send_transport(playing, position, |message| sent.push(message.to_vec()));
+        sent
+    }
+
+    #[test]
+    fn clock_out_continues_from_the_song_position() {
+        assert_eq!(transport(true, 0.0), vec![vec![MIDI_START]]);
+        assert_eq!(transport(true, 4.5), vec![vec![MIDI_SONG_POSITION, 18, 0], vec![MIDI_CONTINUE]]);
+        assert_eq!(transport(true, 40.0), vec![vec![MIDI_SONG_POSITION, 0x20, 0x01], vec![MIDI_CONTINUE]]);
+        assert_eq!(transport(true, 1.0e6)[0], vec![MIDI_SONG_POSITION, 0x7F, 0x7F]);
+        assert_eq!(transport(false, 4.5), vec![vec![MIDI_STOP]]);
+    }
+}
@@ -39,6 +39,46 @@ pub enum MessageID {
     NoteOn = 8,
     /// NoteOff
     NoteOff = 9,
+    /// transport started (1) or stopped (0) (to GUI)
+    Transport = 10,
+    /// peak and RMS level of an input (node 0) or output (node 1) channel (to GUI)
+    Level = 11,
+    /// value of a module's output parameter, e.g. a meter or envelope (to GUI)
+    OutputParam = 12,
+    /// show (1) or hide (0) the analyser (from GUI)
+    Analyser = 13,
+    /// waveform snapshot of input (node 0) or output (node 1) (to GUI)
+    Scope = 14,
+    /// magnitude spectrum, in dB, of input (node 0) or output (node 1), index 
+    /// is the sample rate (to GUI)
+    Spectrum = 15,
+    /// start (path, empty for default, or 1) or stop (0) recording (from GUI)
+    Record = 16,
+    /// recording started (path) or stopped (empty) (to GUI)
+    Recording = 17,
+    /// MIDI file player control, index 0 play (1) or stop (0), index 1 loop on (1) or 
+    /// off (0), index 2 tempo scale (from GUI)
+    MidiFile = 18,
+    /// frequency of each of the 128 MIDI notes (from GUI)
+    Tuning = 19,
+    /// arpeggiator, index 0 mode (-1 off, 0 up, 1 down, 2 up/down, 3 random, 4 as played),
+    /// 1 steps per beat, 2 gate, 3 octaves (from GUI)
+    Arpeggiator = 20,
+    /// chord memory, index 0 intervals (empty to disable), 1 learn next chord played (from GUI)
+    Chord = 21,
+    /// clock, index 0 stop (0), continue (1), or play from start (2), 1 tempo in BPM,
+    /// 2 source internal (0), MIDI (1), or Link (2), 3 beats per bar (from GUI)
+    Clock = 22,
+    /// clock position, node is bar, index is playing, value is (tempo, beat within bar) (to GUI)
+    ClockPosition = 23,
+    /// catalogue of all modules, as a JSON array of each module's info (to GUI)
+    Catalogue = 24,
+    /// module download progress, value is the URL, node bytes received, and index total
+    /// bytes, 0 if not known (to GUI)
+    LoadProgress = 25,
+    /// module load status, value is the module's key, index is loading (0), ready (1),
+    /// failed (2), or cancelled (3) (to GUI)
+    LoadStatus = 26,
 }
 
 /// Simple message format used to communicate between different components, in particular, 
@@ -66,6 +106,17 @@ impl Message {
             value: Value::VString([url, &width.to_string(), &height.to_string()].join(" ")),
         }
     }
+
+    /// utility function to create a module download progress message
+    #[inline]
+    pub fn load_progress(url: &str, loaded: u64, total: u64) -> Self {
+        Message {
+            id: MessageID::LoadProgress,
+            node: loaded.min(Index::MAX as u64) as Index,
+            index: total.min(Index::MAX as u64) as Index,
+            value: Value::VString(url.to_string()),
+        }
+    }
 }
 
 pub trait Send {
@@ -0,0 +1,81 @@
+//!
+//! Audio driver selection for standalone app
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::str::FromStr;
+
+/// which of the JACK ports, if any, are automatically connected to the system's
+/// physical ports when the client is activated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AutoConnect {
+    /// leave all connections to the user (or a session manager)
+    None,
+    /// connect module inputs to physical capture ports
+    Inputs,
+    /// connect module outputs to physical playback ports
+    Outputs,
+    /// connect both inputs and outputs
+    All,
+}
+
+impl AutoConnect {
+    #[inline]
+    pub fn inputs(self) -> bool {
+        self == AutoConnect::Inputs || self == AutoConnect::All
+    }
+
+    #[inline]
+    pub fn outputs(self) -> bool {
+        self == AutoConnect::Outputs || self == AutoConnect::All
+    }
+}
+
+impl FromStr for AutoConnect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(AutoConnect::None),
+            "inputs" => Ok(AutoConnect::Inputs),
+            "outputs" => Ok(AutoConnect::Outputs),
+            "all" => Ok(AutoConnect::All),
+            _ => Err(format!("unknown JACK connection mode: {}", s)),
+        }
+    }
+}
+
+/// options for the JACK driver
+#[derive(Clone, Debug)]
+pub struct JackOptions {
+    /// name the client registers with the JACK server
+    pub client_name: String,
+    /// automatic connection of audio ports
+    pub auto_connect: AutoConnect,
+    /// optional source port to connect to the JACK MIDI input
+    pub midi_connect: Option<String>,
+    /// output silence while transport is stopped, for modules marked silent_when_stopped
+    pub follow_transport: bool,
+}
+
+/// audio driver used to run modules
+#[derive(Clone, Debug)]
+pub enum AudioDriver {
+    /// PortAudio, selected audio devices are controlled from the GUI
+    PortAudio,
+    /// JACK, requires the crate to be built with the jack feature
+    Jack(JackOptions),
+    /// no audio hardware, buffers are pulled on a timer and output is discarded
+    Null,
+}
+
+impl AudioDriver {
+    #[inline]
+    pub fn is_jack(&self) -> bool {
+        match self {
+            AudioDriver::Jack(_) => true,
+            _ => false,
+        }
+    }
+}
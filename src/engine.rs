@@ -0,0 +1,799 @@
+//!
+//! Audio engine, handles messages and drives a module from an audio callback
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::sync::mpsc::Sender;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Instant;
+
+use crossbeam_channel as cb;
+use rimd::{MidiMessage, Status};
+
+use aa_wasmtime::*;
+use crate::messages::*;
+use crate::comms::*;
+use crate::bundle::*;
+use crate::levels::*;
+use crate::analyser::*;
+use crate::recorder::*;
+use crate::midi_device::MidiEvent;
+use crate::file_player::*;
+use crate::midi_file::*;
+use crate::voices::*;
+use crate::tuning::*;
+use crate::midi_effects::*;
+use crate::clock::*;
+
+/// capacity of the queue from the audio thread to the GUI. Messages are dropped, rather
+/// than blocking or allocating in the audio thread, if the GUI falls behind.
+pub const AUDIO_QUEUE_SIZE: usize = 1024;
+
+/// rate, in Hz, at which levels and output parameters are sent to the GUI
+const REPORT_RATE: f64 = 30.0;
+
+/// scheduled MIDI events, per buffer, before allocating
+const EVENTS_CAPACITY: usize = 256;
+
+// set a aaunit parameter
+#[inline]
+pub fn set_param(aaunit: &AAUnit, node: Index, index: Index, param: Value) {
+    match param {
+        Value::VFloat(f) => {
+            let _ = aaunit.set_param_float(node, index, f);
+        },
+        Value::VInt(i) => {
+            let _ = aaunit.set_param_int(node, index, i);
+        },
+        _ => {
+        }
+    }
+}
+
+/// dispatch a note on or off to a aaunit, returns false for any other message, or one
+/// too short to be a note
+pub fn handle_note(aaunit: &AAUnit, message: &MidiMessage) -> bool {
+    if message.data.len() < 3 {
+        return false;
+    }
+    match message.status() {
+        Status::NoteOn => {
+            let note     = message.data(1) as i32;
+            let velocity = message.data(2) as f32 / 127.0;
+            let _ = aaunit.handle_note_on(note, velocity);
+            true
+        },
+        Status::NoteOff => {
+            let note     = message.data(1) as i32;
+            let velocity = message.data(2) as f32 / 127.0;
+            let _ = aaunit.handle_note_off(note, velocity);
+            true
+        },
+        _ => false,
+    }
+}
+
+/// compute a single buffer of interleaved audio, dispatching on the number of
+/// inputs and outputs. Input only modules (analysers, recorders, ...) are run as 
+/// mono output, into scratch, which only allocates if the buffer size grows.
+pub fn compute_block(
+    aaunit: &AAUnit, 
+    num_inputs: i32, 
+    num_outputs: i32, 
+    frames: usize, 
+    in_buffer: &[f32], 
+    out_buffer: &mut [f32],
+    scratch: &mut Vec<f32>) {
+    match (num_inputs, num_outputs) {
+        (0, 1) => { let _ = aaunit.compute_zero_one(frames, out_buffer); },
+        (0, _) => { let _ = aaunit.compute_zero_two(frames, out_buffer); },
+        (_, 0) => {
+            if scratch.len() < frames {
+                scratch.resize(frames, 0.0);
+            }
+            if num_inputs == 1 {
+                let _ = aaunit.compute_one_one(frames, in_buffer, &mut scratch[..frames]);
+            }
+            else {
+                let _ = aaunit.compute_two_one(frames, in_buffer, &mut scratch[..frames]);
+            }
+        },
+        (1, 1) => { let _ = aaunit.compute_one_one(frames, in_buffer, out_buffer); },
+        (1, _) => { let _ = aaunit.compute_one_two(frames, in_buffer, out_buffer); },
+        (_, 1) => { let _ = aaunit.compute_two_one(frames, in_buffer, out_buffer); },
+        (_, _) => { let _ = aaunit.compute_two_two(frames, in_buffer, out_buffer); },
+    }
+}
+
+/// options for MIDI file playback
+#[derive(Clone, Debug)]
+pub struct MidiFileOptions {
+    pub file: String,
+    pub looping: bool,
+    pub tempo_scale: f64,
+}
+
+/// options for microtuning, Scala files
+#[derive(Clone, Debug)]
+pub struct TuningOptions {
+    pub scale: String,
+    pub keyboard_map: Option<String>,
+}
+
+/// options for host state, from the command line
+#[derive(Clone, Debug)]
+pub struct HostOptions {
+    pub record: RecordOptions,
+    /// audio files offered, as input devices, to effect modules
+    pub input_files: Vec<String>,
+    /// MIDI file, played from start up
+    pub midi_file: Option<MidiFileOptions>,
+    /// host polyphony, for modules that declare voice parameters
+    pub voices: Option<VoiceOptions>,
+    /// tuning used wherever the host converts notes to frequency, 12 tone equal temperament if None
+    pub tuning: Option<TuningOptions>,
+    /// arpeggiator and chord memory
+    pub effects: MidiEffectsOptions,
+    /// tempo and transport, which the arpeggiator is synced to
+    pub clock: ClockOptions,
+}
+
+/// host state that outlives a single module's stream, such as taps on the audio for 
+/// analysis and recording, shared with each engine created on the audio thread
+pub struct Host {
+    pub analyser: AnalyserTap,
+    pub recorder: RecorderTap,
+    /// actual sample rate of the current stream
+    pub sample_rate: f64,
+    /// recording requested on the command line, started with the first stream
+    pending_record: Option<String>,
+    /// audio files, indexed from FILE_DEVICE_BASE, None if failed to load
+    input_files: Vec<Option<FilePlayer>>,
+    /// audio file currently used in place of the input device
+    input_file: Option<usize>,
+    pub midi_player: Option<MidiFilePlayer>,
+    /// additional voices of the current module, if the host provides polyphony
+    pub voices: Option<Voices>,
+    /// frequency of each note, updated from the GUI and MTS SysEx
+    pub tuning: Tuning,
+    pub effects: MidiEffects,
+    pub clock: Clock,
+}
+
+impl Host {
+    pub fn new(
+        send_from_audio: cb::Sender<Message>, 
+        send_to_gui: cb::Sender<Message>, 
+        options: HostOptions) -> Self {
+        let input_files = options.input_files.iter()
+            .map(|path| {
+                FilePlayer::load(path).map_err(|e| eprintln!("Failed to load {} {}", path, e)).ok()
+            })
+            .collect();
+
+        let midi_player = options.midi_file.as_ref().and_then(|options| {
+            match MidiFilePlayer::load(&options.file) {
+                Ok(mut player) => {
+                    player.set_looping(options.looping);
+                    player.set_tempo_scale(options.tempo_scale);
+                    player.play();
+                    Some(player)
+                },
+                Err(e) => {
+                    eprintln!("Failed to load {} {}", options.file, e);
+                    None
+                }
+            }
+        });
+
+        let tuning = options.tuning.as_ref().map_or(Tuning::default(), |options| {
+            Tuning::load(&options.scale, options.keyboard_map.as_deref())
+                .map_err(|e| eprintln!("Failed to load tuning {}", e))
+                .unwrap_or_default()
+        });
+
+        Self {
+            analyser: spawn_analyser(send_from_audio),
+            pending_record: options.record.file.clone(),
+            recorder: spawn_recorder(options.record, send_to_gui),
+            sample_rate: 44_100.0,
+            input_files,
+            input_file: None,
+            midi_player,
+            voices: None,
+            tuning,
+            effects: MidiEffects::new(&options.effects),
+            clock: Clock::new(&options.clock),
+        }
+    }
+
+    /// called once a stream has been opened, before it is started, with its output
+    /// latency in seconds
+    pub fn start_stream(&mut self, sample_rate: f64, outputs: i32, latency: f64) {
+        self.sample_rate = sample_rate;
+        self.clock.set_output_latency(latency);
+        self.analyser.set_sample_rate(sample_rate);
+        if let Some(voices) = self.voices.as_mut() {
+            voices.init(sample_rate);
+        }
+        if let Some(path) = self.pending_record.take() {
+            self.recorder.start(Some(path), outputs.max(0) as usize, sample_rate);
+        }
+    }
+
+    /// select the input device, returns true if it is an audio file, which will be used 
+    /// in place of the stream's input
+    pub fn select_input(&mut self, device: Option<u32>) -> bool {
+        self.input_file = device
+            .filter(|d| is_file_device(*d))
+            .map(|d| (d - FILE_DEVICE_BASE) as usize)
+            .filter(|i| self.input_files.get(*i).map_or(false, |f| f.is_some()));
+        self.input_file.is_some()
+    }
+
+    /// read the selected audio file, if any, into an interleaved buffer, returns false if 
+    /// an audio file is not selected
+    pub fn read_input_file(&mut self, buffer: &mut Vec<f32>, channels: usize, frames: usize) -> bool {
+        let sample_rate = self.sample_rate;
+        let input_files = &mut self.input_files;
+        match self.input_file.and_then(|i| input_files[i].as_mut()) {
+            Some(player) => {
+                // only allocates if the buffer size grows
+                if buffer.len() < frames * channels {
+                    buffer.resize(frames * channels, 0.0);
+                }
+                player.read(&mut buffer[..frames * channels], channels, sample_rate);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+// insert an event, keeping events ordered by offset, and in arrival order for equal offsets
+fn insert_event(events: &mut Vec<(usize, MidiMessage)>, offset: usize, message: MidiMessage) {
+    let position = events.iter().rposition(|(o, _)| *o <= offset).map_or(0, |p| p + 1);
+    events.insert(position, (offset, message));
+}
+
+// copy bytes into a spare message, which only allocates if none are spare, or it is
+// shorter than the bytes
+fn spare_message(spare: &mut Vec<MidiMessage>, bytes: &[u8]) -> MidiMessage {
+    let mut message = spare.pop().unwrap_or_else(|| MidiMessage::from_bytes(Vec::with_capacity(3)));
+    message.data.clear();
+    message.data.extend_from_slice(bytes);
+    message
+}
+
+// clear handled events, keeping their messages for reuse, up to the spare's capacity
+fn recycle_events(events: &mut Vec<(usize, MidiMessage)>, spare: &mut Vec<MidiMessage>) {
+    for (_, message) in events.drain(..) {
+        if spare.len() < spare.capacity() {
+            spare.push(message);
+        }
+    }
+}
+
+/// state shared by all audio backends, called once per buffer from the audio callback
+pub struct Engine {
+    aaunit: Rc<RefCell<AAUnit>>,
+    host: Rc<RefCell<Host>>,
+    num_inputs: i32,
+    num_outputs: i32,
+    receive_from_gui: cb::Receiver<Message>,
+    receive_from_midi: cb::Receiver<MidiEvent>,
+    /// messages from audio to the GUI, a bounded queue, see AUDIO_QUEUE_SIZE
+    send_from_audio: cb::Sender<Message>,
+    /// sent once, when the stream should be stopped
+    send_stop: Sender<Option<Message>>,
+    /// output parameters, as (node, index, last value sent to GUI)
+    output_params: Vec<(Index, Index, f32)>,
+    /// discarded output, for modules without outputs
+    scratch: Vec<f32>,
+    /// input read from an audio file, in place of the stream's input
+    file_input: Vec<f32>,
+    /// MIDI events scheduled within the current buffer, as (offset, message)
+    events: Vec<(usize, MidiMessage)>,
+    /// events after MIDI effects have been applied, swapped with events
+    effect_events: Vec<(usize, MidiMessage)>,
+    /// messages already handled, reused for MIDI file events so they can be scheduled
+    /// without allocating
+    spare_messages: Vec<MidiMessage>,
+    /// when the previous buffer was processed, live MIDI is scheduled relative to it
+    last_buffer: Option<Instant>,
+    /// frames processed, since the stream started
+    frame_time: u64,
+    /// module parameters set from the host's clock
+    host_params: HostParams,
+    input_levels: Levels,
+    output_levels: Levels,
+    frames_since_report: usize,
+}
+
+impl Engine {
+    pub fn new(
+        aaunit: Rc<RefCell<AAUnit>>,
+        host: Rc<RefCell<Host>>,
+        bundle: &Bundle,
+        receive_from_gui: cb::Receiver<Message>,
+        receive_from_midi: cb::Receiver<MidiEvent>,
+        send_from_audio: cb::Sender<Message>,
+        send_stop: Sender<Option<Message>>) -> Self {
+        Self {
+            aaunit,
+            host,
+            num_inputs: bundle.info.inputs,
+            num_outputs: bundle.info.outputs,
+            receive_from_gui,
+            receive_from_midi,
+            send_from_audio,
+            send_stop,
+            output_params: bundle.gui.output_params.iter()
+                .map(|(node, index)| (*node, *index, std::f32::NAN))
+                .collect(),
+            scratch: Vec::new(),
+            file_input: Vec::new(),
+            events: Vec::with_capacity(EVENTS_CAPACITY),
+            effect_events: Vec::with_capacity(EVENTS_CAPACITY),
+            spare_messages: Vec::with_capacity(EVENTS_CAPACITY),
+            last_buffer: None,
+            frame_time: 0,
+            host_params: bundle.gui.host_params.clone(),
+            input_levels: Levels::new(bundle.info.inputs.max(0) as usize),
+            output_levels: Levels::new(bundle.info.outputs.max(0) as usize),
+            frames_since_report: 0,
+        }
+    }
+
+    /// dispatch a MIDI message, at offset within the current buffer, to the aaunit
+    pub fn handle_midi(&mut self, offset: usize, message: &MidiMessage) {
+        {
+            let host = &mut *self.host.borrow_mut();
+            // MIDI clock and transport, which are not recorded
+            if host.clock.midi(&message.data, self.frame_time + offset as u64, host.sample_rate) {
+                return;
+            }
+            host.recorder.midi(offset, message);
+        }
+        if self.handle_note(message) {
+            return;
+        }
+        match message.status() {
+            Status::ControlChange => {
+                let mut host = self.host.borrow_mut();
+                if host.recorder.options().cc == Some(message.data(1)) {
+                    if message.data(2) >= 64 {
+                        let sample_rate = host.sample_rate;
+                        host.recorder.start(None, self.num_outputs.max(0) as usize, sample_rate);
+                    }
+                    else {
+                        host.recorder.stop();
+                    }
+                }
+            },
+            // MIDI Tuning Standard, single note tuning changes
+            Status::SysExStart => {
+                self.retune(None, Some(&message.data));
+            },
+            _ => {},
+        }
+    }
+
+    // dispatch a note to the host's voices, if any, otherwise to the aaunit
+    fn handle_note(&mut self, message: &MidiMessage) -> bool {
+        let aaunit = self.aaunit.borrow();
+        let host = &mut *self.host.borrow_mut();
+        match host.voices.as_mut() {
+            Some(voices) => voices.handle_note(&aaunit, &host.tuning, message),
+            None => handle_note(&aaunit, message),
+        }
+    }
+
+    // set the frequency of each note, retuning any sounding voices
+    fn retune(&mut self, freqs: Option<&[f32]>, sysex: Option<&[u8]>) {
+        let aaunit = self.aaunit.borrow();
+        let host = &mut *self.host.borrow_mut();
+        if let Some(freqs) = freqs {
+            host.tuning.set(freqs);
+        }
+        if let Some(data) = sysex {
+            if !host.tuning.apply_sysex(data) {
+                return;
+            }
+        }
+        if let Some(voices) = host.voices.as_ref() {
+            voices.retune(&aaunit, &host.tuning);
+        }
+    }
+
+    // dispatch a GUI message to the aaunit, returning the message if it requires the audio
+    // stream to be stopped
+    fn handle_gui(&mut self, message: Message) -> Option<Message> {
+        match message.id {
+            // scheduled at the start of the buffer, so they pass through the MIDI effects
+            MessageID::NoteOn => {
+                if let Value::VVU8(data) = message.value {
+                    self.schedule(0, MidiMessage::from_bytes(vec![0x90, data[0], data[1]]));
+                }
+            },
+            MessageID::NoteOff => {
+                if let Value::VVU8(data) = message.value {
+                    self.schedule(0, MidiMessage::from_bytes(vec![0x80, data[0], data[1]]));
+                }
+            },
+            MessageID::Param => {
+                let mut host = self.host.borrow_mut();
+                host.recorder.param(0, message.node, message.index, &message.value);
+                if let Some(voices) = host.voices.as_ref() {
+                    voices.set_param(message.node, message.index, &message.value);
+                }
+                set_param(&self.aaunit.borrow(), message.node, message.index, message.value);
+            },
+            MessageID::Record => {
+                let mut host = self.host.borrow_mut();
+                match message.value {
+                    Value::VString(path) => {
+                        let sample_rate = host.sample_rate;
+                        let path = if path.is_empty() { None } else { Some(path) };
+                        host.recorder.start(path, self.num_outputs.max(0) as usize, sample_rate);
+                    },
+                    Value::VInt(0) => {
+                        host.recorder.stop();
+                    },
+                    _ => {
+                        let sample_rate = host.sample_rate;
+                        host.recorder.start(None, self.num_outputs.max(0) as usize, sample_rate);
+                    }
+                }
+            },
+            MessageID::MidiFile => {
+                if let Some(player) = self.host.borrow_mut().midi_player.as_mut() {
+                    match message.index {
+                        0 => if i32::from(message.value) != 0 { player.play() } else { player.stop() },
+                        1 => player.set_looping(i32::from(message.value) != 0),
+                        _ => match message.value {
+                            Value::VFloat(scale) => player.set_tempo_scale(scale as f64),
+                            Value::VInt(scale) => player.set_tempo_scale(scale as f64),
+                            _ => { },
+                        },
+                    }
+                }
+            },
+            MessageID::Tuning => {
+                if let Value::VVF32(freqs) = &message.value {
+                    self.retune(Some(freqs), None);
+                }
+            },
+            MessageID::Arpeggiator => {
+                let host = &mut *self.host.borrow_mut();
+                let value = match message.value {
+                    Value::VFloat(f) => f as f64,
+                    Value::VInt(i) => i as f64,
+                    _ => return None,
+                };
+                match message.index {
+                    0 => host.effects.arp.set_mode(ArpMode::from_index(value as i32)),
+                    1 => host.effects.arp.set_rate(value),
+                    2 => host.effects.arp.set_gate(value),
+                    _ => host.effects.arp.set_octaves(value as u8),
+                }
+            },
+            MessageID::Clock => {
+                let mut host = self.host.borrow_mut();
+                let clock = &mut host.clock;
+                match message.index {
+                    0 => match i32::from(message.value) {
+                        0 => clock.stop(),
+                        1 => clock.play(false),
+                        _ => clock.play(true),
+                    },
+                    1 => match message.value {
+                        Value::VFloat(tempo) => clock.set_tempo(tempo as f64),
+                        Value::VInt(tempo) => clock.set_tempo(tempo as f64),
+                        _ => { },
+                    },
+                    2 => clock.set_source(match i32::from(message.value) {
+                        0 => ClockSource::Internal,
+                        1 => ClockSource::Midi,
+                        _ => ClockSource::Link,
+                    }),
+                    _ => clock.set_beats_per_bar(i32::from(message.value).max(1) as u32),
+                }
+            },
+            MessageID::Chord => {
+                let mut host = self.host.borrow_mut();
+                let chord = &mut host.effects.chord;
+                match (message.index, message.value) {
+                    (0, Value::VVU8(intervals)) => chord.set(intervals),
+                    (0, Value::VPair((a, b))) => chord.set(vec![a, b]),
+                    (0, _) => chord.set(Vec::new()),
+                    _ => chord.learn(),
+                }
+            },
+            MessageID::Analyser => {
+                self.host.borrow().analyser.set_enabled(i32::from(message.value) != 0);
+            },
+            MessageID::ChangeModule
+                | MessageID::AddInputDevice
+                | MessageID::AddOutputDevice
+                | MessageID::Exit => {
+                return Some(message);
+            },
+            _ => { }
+        }
+        None
+    }
+
+    /// handle any pending messages, returns false if the stream should complete.
+    /// MIDI is not handled here, see schedule_midi.
+    pub fn handle_messages(&mut self) -> bool {
+        // handle any incomming messages from UI
+        while let Ok(message) = self.receive_from_gui.try_recv() {
+            if let Some(message) = self.handle_gui(message) {
+                let _ = self.send_stop.send(Some(message));
+                return false;
+            }
+        }
+        true
+    }
+
+    /// compute a single buffer of interleaved audio
+    pub fn compute(&mut self, frames: usize, in_buffer: &[f32], out_buffer: &mut [f32]) {
+        let aaunit = self.aaunit.borrow();
+        match self.host.borrow_mut().voices.as_mut() {
+            Some(voices) => voices.compute(
+                &aaunit, 
+                self.num_inputs, 
+                self.num_outputs, 
+                frames, 
+                in_buffer, 
+                out_buffer, 
+                &mut self.scratch),
+            None => compute_block(
+                &aaunit, 
+                self.num_inputs, 
+                self.num_outputs, 
+                frames, 
+                in_buffer, 
+                out_buffer, 
+                &mut self.scratch),
+        }
+    }
+
+    /// compute a single buffer, splitting it at the offset of each scheduled MIDI event, 
+    /// so that events are applied at the correct sample
+    pub fn compute_events(&mut self, frames: usize, in_buffer: &[f32], out_buffer: &mut [f32]) {
+        let inputs = self.num_inputs.max(0) as usize;
+        let outputs = self.num_outputs.max(0) as usize;
+        let mut events = std::mem::take(&mut self.events);
+        let mut start = 0;
+        for (offset, message) in events.iter() {
+            let offset = (*offset).min(frames);
+            if offset > start {
+                self.compute(
+                    offset - start, 
+                    &in_buffer[start * inputs..offset * inputs], 
+                    &mut out_buffer[start * outputs..offset * outputs]);
+                start = offset;
+            }
+            self.handle_midi(offset, message);
+        }
+        if start < frames {
+            self.compute(
+                frames - start, 
+                &in_buffer[start * inputs..frames * inputs], 
+                &mut out_buffer[start * outputs..frames * outputs]);
+        }
+        recycle_events(&mut events, &mut self.spare_messages);
+        self.events = events;
+    }
+
+    /// schedule an event at offset within the next buffer, after any already scheduled
+    /// at the same offset. Does not allocate, unless EVENTS_CAPACITY is exceeded.
+    pub fn schedule(&mut self, offset: usize, message: MidiMessage) {
+        insert_event(&mut self.events, offset, message);
+    }
+
+    /// schedule an event from its bytes, reusing a spare message, so it does not allocate
+    /// on the audio thread
+    pub fn schedule_bytes(&mut self, offset: usize, bytes: &[u8]) {
+        let message = spare_message(&mut self.spare_messages, bytes);
+        insert_event(&mut self.events, offset, message);
+    }
+
+    /// schedule live MIDI events, that arrived during the previous buffer, at the same
+    /// offset within the next buffer. This adds a buffer of latency, but bounds timing 
+    /// jitter to a sample, rather than a buffer.
+    pub fn schedule_midi(&mut self, frames: usize) {
+        let now = Instant::now();
+        let start = self.last_buffer.replace(now).unwrap_or(now);
+        let sample_rate = self.host.borrow().sample_rate;
+        let last = frames.max(1) - 1;
+        while let Ok(event) = self.receive_from_midi.try_recv() {
+            let offset = event.time.saturating_duration_since(start).as_secs_f64() * sample_rate;
+            self.schedule((offset as usize).min(last), event.message);
+        }
+    }
+
+    /// handle all scheduled events immediately, for when a buffer is not computed
+    pub fn flush_events(&mut self) {
+        let mut events = std::mem::take(&mut self.events);
+        for (_, message) in events.iter() {
+            self.handle_midi(0, message);
+        }
+        recycle_events(&mut events, &mut self.spare_messages);
+        self.events = events;
+    }
+
+    /// schedule events, for the next buffer, from the MIDI file player. Parameter changes
+    /// are set at the start of the buffer, as they are from the GUI.
+    pub fn schedule_midi_file(&mut self, frames: usize) {
+        let host = &mut *self.host.borrow_mut();
+        let sample_rate = host.sample_rate;
+        if let Some(player) = host.midi_player.as_mut() {
+            let events = &mut self.events;
+            let spare = &mut self.spare_messages;
+            let (aaunit, voices, recorder) = (&self.aaunit, &host.voices, &mut host.recorder);
+            player.next_block(frames, sample_rate, |offset, event| match event {
+                FileEvent::Midi(message) => insert_event(events, offset, spare_message(spare, &message.data)),
+                FileEvent::Param(node, index, value) => {
+                    recorder.param(offset, *node, *index, value);
+                    if let Some(voices) = voices.as_ref() {
+                        voices.set_param(*node, *index, value);
+                    }
+                    set_param(&aaunit.borrow(), *node, *index, value.clone());
+                },
+            });
+        }
+    }
+
+    /// set the module's host parameters, tempo and position, from the clock, for the next buffer
+    pub fn set_host_params(&mut self) {
+        let aaunit = self.aaunit.borrow();
+        let host = self.host.borrow();
+        let clock = &host.clock;
+        let params = [
+            (self.host_params.tempo, clock.tempo()),
+            (self.host_params.playing, if clock.is_playing() { 1.0 } else { 0.0 }),
+            (self.host_params.bar, clock.bar() as f64),
+            (self.host_params.beat, clock.beat()),
+        ];
+        for (param, value) in params.iter() {
+            if let Some((node, index)) = param {
+                let value = Value::VFloat(*value as f32);
+                if let Some(voices) = host.voices.as_ref() {
+                    voices.set_param(*node, *index, &value);
+                }
+                set_param(&aaunit, *node, *index, value);
+            }
+        }
+    }
+
+    /// advance the clock, and frame time, once a buffer has been computed
+    pub fn advance(&mut self, frames: usize) {
+        let host = &mut *self.host.borrow_mut();
+        host.clock.advance(frames, host.sample_rate);
+        self.frame_time += frames as u64;
+    }
+
+    /// apply the host's MIDI effects, arpeggiator and chord memory, to the events scheduled
+    /// for the next buffer
+    pub fn apply_midi_effects(&mut self, frames: usize) {
+        let host = &mut *self.host.borrow_mut();
+        if !host.effects.enabled() {
+            return;
+        }
+        host.effects.process(
+            &mut self.events,
+            &mut self.effect_events,
+            &mut self.spare_messages,
+            frames,
+            host.sample_rate,
+            host.clock.tempo());
+        std::mem::swap(&mut self.events, &mut self.effect_events);
+    }
+
+    // send any output parameters, whose value has changed, to the GUI
+    fn send_output_params(&mut self) {
+        let aaunit = self.aaunit.borrow();
+        for (node, index, last) in self.output_params.iter_mut() {
+            if let Ok(value) = aaunit.get_param_float(*node, *index) {
+                if value != *last {
+                    *last = value;
+                    let _ = self.send_from_audio.try_send(Message {
+                        id: MessageID::OutputParam,
+                        node: *node,
+                        index: *index,
+                        value: Value::VFloat(value),
+                    });
+                }
+            }
+        }
+    }
+
+    // send the clock's tempo and position to the GUI
+    fn send_clock(&self) {
+        let host = self.host.borrow();
+        let _ = self.send_from_audio.try_send(Message {
+            id: MessageID::ClockPosition,
+            node: host.clock.bar() as Index,
+            index: host.clock.is_playing() as Index,
+            value: Value::VFPair((host.clock.tempo() as f32, host.clock.beat() as f32)),
+        });
+    }
+
+    // send the (peak, rms) of each channel to the GUI
+    fn send_levels(send_from_audio: &cb::Sender<Message>, node: Index, levels: &mut Levels) {
+        for c in 0..levels.channels() {
+            let _ = send_from_audio.try_send(Message {
+                id: MessageID::Level,
+                node,
+                index: c as Index,
+                value: Value::VFPair(levels.level(c)),
+            });
+        }
+        levels.reset();
+    }
+
+    /// meter and tap a computed buffer, sending levels and output parameters to the GUI, 
+    /// at most REPORT_RATE times a second
+    pub fn report(&mut self, in_buffer: &[f32], out_buffer: &[f32], frames: usize) {
+        self.input_levels.accumulate(in_buffer, frames);
+        self.output_levels.accumulate(out_buffer, frames);
+        let mut host = self.host.borrow_mut();
+        host.analyser.tap(
+            in_buffer, 
+            self.input_levels.channels(), 
+            out_buffer, 
+            self.output_levels.channels(), 
+            frames);
+        host.recorder.write(out_buffer, self.output_levels.channels(), frames);
+        // from the rate the stream runs at, which may not be the rate requested
+        let report_interval = (host.sample_rate / REPORT_RATE) as usize;
+        drop(host);
+
+        self.frames_since_report += frames;
+        if self.frames_since_report >= report_interval {
+            self.frames_since_report = 0;
+            Self::send_levels(&self.send_from_audio, 0, &mut self.input_levels);
+            Self::send_levels(&self.send_from_audio, 1, &mut self.output_levels);
+            self.send_output_params();
+            self.send_clock();
+        }
+    }
+
+    /// process callback for audio backends, returns false if the stream should complete
+    pub fn process(&mut self, in_buffer: &[f32], out_buffer: &mut [f32], frames: usize) -> bool {
+        if !self.handle_messages() {
+            return false;
+        }
+
+        // an audio file may be used in place of the stream's input
+        let mut file_input = std::mem::take(&mut self.file_input);
+        let channels = self.num_inputs.max(0) as usize;
+        let in_buffer = 
+            if channels > 0 && self.host.borrow_mut().read_input_file(&mut file_input, channels, frames) {
+                &file_input[..frames * channels]
+            }
+            else {
+                in_buffer
+            };
+
+        self.schedule_midi(frames);
+        self.schedule_midi_file(frames);
+        self.apply_midi_effects(frames);
+        self.set_host_params();
+        self.compute_events(frames, in_buffer, out_buffer);
+        self.report(in_buffer, out_buffer, frames);
+        self.advance(frames);
+        self.file_input = file_input;
+        true
+    }
+
+    #[inline]
+    pub fn send_from_audio(&self) -> &cb::Sender<Message> {
+        &self.send_from_audio
+    }
+}
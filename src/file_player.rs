@@ -0,0 +1,127 @@
+//!
+//! Looping audio file player, used as a pseudo input device
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::path::Path;
+
+/// input device indices at, or above, this are audio files rather than devices. Indices
+/// are sent to, and back from, GUI as numbers, so must fit an i32 and be exact as an f32.
+pub const FILE_DEVICE_BASE: u32 = 1 << 20;
+
+/// is a input device index an audio file
+#[inline]
+pub fn is_file_device(index: u32) -> bool {
+    index >= FILE_DEVICE_BASE
+}
+
+/// an audio file, decoded into memory, which is played in a loop
+pub struct FilePlayer {
+    name: String,
+    /// interleaved samples
+    samples: Vec<f32>,
+    channels: usize,
+    sample_rate: f64,
+    /// position, in frames, fractional as the file may be at a different rate to the stream
+    position: f64,
+}
+
+impl FilePlayer {
+    /// load a WAV or FLAC file
+    pub fn load(path: &str) -> Result<Self, String> {
+        let (samples, channels, sample_rate) =
+            if path.to_lowercase().ends_with(".flac") {
+                Self::load_flac(path)?
+            }
+            else {
+                Self::load_wav(path)?
+            };
+
+        if channels == 0 || samples.len() < channels {
+            return Err(format!("{} contains no audio", path));
+        }
+
+        Ok(Self {
+            name: Self::display_name(path),
+            samples,
+            channels,
+            sample_rate,
+            position: 0.0,
+        })
+    }
+
+    /// name shown in GUI for a file
+    pub fn display_name(path: &str) -> String {
+        let name = Path::new(path).file_name().map_or(path.to_string(), |n| n.to_string_lossy().to_string());
+        format!("File: {}", name)
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn load_wav(path: &str) -> Result<(Vec<f32>, usize, f64), String> {
+        let mut reader = hound::WavReader::open(path).map_err(|e| e.to_string())?;
+        let spec = reader.spec();
+        let samples = match spec.sample_format {
+            hound::SampleFormat::Float => {
+                reader.samples::<f32>().collect::<Result<Vec<f32>, _>>()
+            },
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+                reader.samples::<i32>().map(|s| s.map(|s| s as f32 * scale)).collect()
+            },
+        }.map_err(|e| e.to_string())?;
+        Ok((samples, spec.channels as usize, spec.sample_rate as f64))
+    }
+
+    fn load_flac(path: &str) -> Result<(Vec<f32>, usize, f64), String> {
+        let mut reader = claxon::FlacReader::open(path).map_err(|e| e.to_string())?;
+        let info = reader.streaminfo();
+        let scale = 1.0 / (1i64 << (info.bits_per_sample - 1)) as f32;
+        let samples = reader.samples()
+            .map(|s| s.map(|s| s as f32 * scale))
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok((samples, info.channels as usize, info.sample_rate as f64))
+    }
+
+    #[inline]
+    fn frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    #[inline]
+    fn sample(&self, frame: usize, channel: usize) -> f32 {
+        self.samples[frame * self.channels + channel % self.channels]
+    }
+
+    /// fill an interleaved buffer, with channels, at sample_rate, looping at the end of
+    /// the file. Channels are mapped round robin, so mono files feed both sides of stereo.
+    pub fn read(&mut self, buffer: &mut [f32], channels: usize, sample_rate: f64) {
+        if channels == 0 {
+            return;
+        }
+        let frames = self.frames();
+        let step = self.sample_rate / sample_rate;
+        for frame in buffer.chunks_mut(channels) {
+            // linear interpolation between neighbouring frames
+            let i = self.position as usize;
+            let j = (i + 1) % frames;
+            let t = (self.position - i as f64) as f32;
+            for (c, s) in frame.iter_mut().enumerate() {
+                *s = self.sample(i, c) * (1.0 - t) + self.sample(j, c) * t;
+            }
+            self.position += step;
+            if self.position >= frames as f64 {
+                self.position -= frames as f64;
+            }
+        }
+    }
+
+    pub fn rewind(&mut self) {
+        self.position = 0.0;
+    }
+}
@@ -11,6 +11,9 @@ use web_view::*;
 
 use crate::messages::*;
 use crate::comms::*;
+use crate::tuning::*;
+use crate::catalogue::*;
+use crate::recorder::recording_path;
 
 #[derive(Deserialize_repr, PartialEq, Debug, Clone)]
 #[repr(u16)]
@@ -24,6 +27,14 @@ pub enum MsgType {
     Loaded = 5,
     NoteOn = 6,
     NoteOff = 7,
+    ShowAnalyser = 8,
+    Record = 9,
+    MidiFile = 10,
+    Tuning = 11,
+    Arpeggiator = 12,
+    Chord = 13,
+    Clock = 14,
+    SearchModules = 15,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -39,13 +50,21 @@ type JavascriptCallback = Box<dyn FnMut(&mut web_view::WebView<()>, &str) -> WVR
 struct Handler {
     sender: Box<dyn Send>,
     gui_sender: cb::Sender<Message>,
+    /// Scala scale and keyboard mapping, loaded from the GUI
+    scale: Option<Scale>,
+    keyboard_map: Option<KeyboardMapping>,
+    /// modules of all repositories, searched from the GUI
+    catalogue: Catalogue,
 }
 
 impl Handler  {
-    pub fn new(sender: Box<dyn Send>, gui_sender: cb::Sender<Message>) -> Self {
+    pub fn new(sender: Box<dyn Send>, gui_sender: cb::Sender<Message>, catalogue: Catalogue) -> Self {
         Self {
             sender,
             gui_sender,
+            scale: None,
+            keyboard_map: None,
+            catalogue,
         }
     }
 
@@ -77,6 +96,70 @@ impl Handler  {
         self.sender.send(MessageID::NoteOff, 0,  0, value).unwrap();
     }
 
+    pub fn show_analyser(&mut self, value: Value) {
+        self.sender.send(MessageID::Analyser, 0, 0, value).unwrap();
+    }
+
+    pub fn record(&mut self, value: Value) {
+        // recordings started without a file are named here, rather than on the audio thread
+        let value = match value {
+            Value::VInt(0) => value,
+            Value::VString(ref path) if !path.is_empty() => value,
+            _ => Value::VString(recording_path()),
+        };
+        self.sender.send(MessageID::Record, 0, 0, value).unwrap();
+    }
+
+    pub fn midi_file(&mut self, index: Index, value: Value) {
+        self.sender.send(MessageID::MidiFile, 0, index, value).unwrap();
+    }
+
+    pub fn arpeggiator(&mut self, index: Index, value: Value) {
+        self.sender.send(MessageID::Arpeggiator, 0, index, value).unwrap();
+    }
+
+    pub fn chord(&mut self, index: Index, value: Value) {
+        self.sender.send(MessageID::Chord, 0, index, value).unwrap();
+    }
+
+    pub fn clock(&mut self, index: Index, value: Value) {
+        self.sender.send(MessageID::Clock, 0, index, value).unwrap();
+    }
+
+    /// load a scale (index 0) or keyboard mapping (index 1), an empty path clears it. 
+    /// Files are parsed here, so the audio thread is only sent the resulting tuning.
+    pub fn tuning(&mut self, index: Index, value: Value) {
+        let path = value.to_string();
+        let result = 
+            if index == 0 {
+                Self::load(&path, Scale::load).map(|scale| self.scale = scale)
+            }
+            else {
+                Self::load(&path, KeyboardMapping::load).map(|map| self.keyboard_map = map)
+            };
+        if let Err(e) = result {
+            eprintln!("Failed to load tuning {}", e);
+            return;
+        }
+
+        let tuning = self.scale.as_ref().map_or(Tuning::default(), |scale| {
+            Tuning::from_scala(scale, self.keyboard_map.as_ref())
+        });
+        self.sender.send(MessageID::Tuning, 0, 0, Value::VVF32(tuning.to_vec())).unwrap();
+    }
+
+    /// search the catalogue, e.g. "stereo effects", replying with the keys of the modules
+    /// that match
+    pub fn search_modules(&self, webview: &mut WebView<()>, query: &str) -> WVResult {
+        let keys = self.catalogue.search(query);
+        let keys = serde_json::to_string(&keys).unwrap_or_else(|_| "[]".to_string());
+        webview.eval(&format!("OnModuleSearch({})", keys))
+    }
+
+    fn load<T>(path: &str, load: fn(&str) -> Result<T, String>) -> Result<Option<T>, String> {
+        if path.is_empty() { Ok(None) } else { load(path).map(Some) }
+    }
+
     pub fn loaded(&mut self) {
         self.gui_sender.send(Message {
             id: MessageID::Loaded,
@@ -95,6 +178,9 @@ pub struct GUI<'a> {
     is_open: bool,
     external_sender: cb::Sender<Message>,
     external_receiver: cb::Receiver<Message>,
+    /// values, levels, and the like from the audio thread, which are only of interest 
+    /// when they arrive, so are dropped while the GUI is loading
+    audio_receiver: Option<cb::Receiver<Message>>,
     //queue: ArrayQueue<Message>,
 }
 
@@ -103,6 +189,7 @@ impl <'a> GUI<'a> {
         html: &str, 
         audio_sender: Box<dyn Send>,
         params: Vec<Vec<Value>>,
+        catalogue: Catalogue,
         title: &'a str,
         size: (i32,i32)) -> Result<Self, ()> {
 
@@ -110,7 +197,7 @@ impl <'a> GUI<'a> {
 
         //let queue = ArrayQueue::new(1024);
 
-        let handler = Handler::new(audio_sender, external_sender.clone());
+        let handler = Handler::new(audio_sender, external_sender.clone(), catalogue);
 
         match web_view::builder()
             .title(title)
@@ -128,7 +215,8 @@ impl <'a> GUI<'a> {
                         size,
                         is_open: false,
                         external_sender,
-                        external_receiver
+                        external_receiver,
+                        audio_receiver: None,
                     })
                 },
                 _ => {
@@ -141,6 +229,10 @@ impl <'a> GUI<'a> {
         self.external_sender.clone()
     }
 
+    pub fn set_audio_receiver(&mut self, receiver: cb::Receiver<Message>) {
+        self.audio_receiver = Some(receiver);
+    }
+
     pub fn comms(&self) -> Box<dyn Send> {
         Box::new(LocalSend::new(self.external_sender.clone()))
     } 
@@ -178,6 +270,15 @@ impl <'a> GUI<'a> {
                 }
             }
 
+            // process any incoming messages from audio
+            if let Some(receiver) = &self.audio_receiver {
+                while let Ok(m) = receiver.try_recv() {
+                    if self.loaded {
+                        Self::audio_message(&mut self.webview, &m).unwrap();
+                    }
+                }
+            }
+
             if self.loaded {
                 let mut msgs_consumed = 0;
                 for m in msgs.iter() {
@@ -217,6 +318,14 @@ impl <'a> GUI<'a> {
                             Self::add_output_device(&mut self.webview, args[0], args[1]).unwrap();
                             msgs_consumed += 1;
                         },
+                        MessageID::Transport => {
+                            Self::transport_change(&mut self.webview, i32::from((*m).value.clone())).unwrap();
+                            msgs_consumed += 1;
+                        },
+                        MessageID::Recording => {
+                            Self::recording_change(&mut self.webview, &(*m).value.to_string()).unwrap();
+                            msgs_consumed += 1;
+                        },
                         MessageID::Exit => {
                             // TODO: add Exit message?
                             msgs_consumed += 1;
@@ -227,6 +336,18 @@ impl <'a> GUI<'a> {
                             Self::add_module(&mut self.webview, args[0], args[1]).unwrap();
                             msgs_consumed += 1;
                         },
+                        MessageID::Catalogue => {
+                            Self::catalogue(&mut self.webview, &(*m).value.to_string()).unwrap();
+                            msgs_consumed += 1;
+                        },
+                        MessageID::LoadProgress => {
+                            Self::load_progress(&mut self.webview, &(*m).value.to_string(), (*m).node, (*m).index).unwrap();
+                            msgs_consumed += 1;
+                        },
+                        MessageID::LoadStatus => {
+                            Self::load_status(&mut self.webview, &(*m).value.to_string(), (*m).index).unwrap();
+                            msgs_consumed += 1;
+                        },
                         _ => {
                             msgs_consumed += 1;
                             // no need to handle loaded
@@ -282,6 +403,37 @@ impl <'a> GUI<'a> {
                                 return message.value.clone()
                                     .map_or(Ok(()), |v| { handler.add_output_device(v); Ok(()) });
                             },
+                            MsgType::ShowAnalyser => {
+                                return message.value.clone()
+                                    .map_or(Ok(()), |v| { handler.show_analyser(v); Ok(()) });
+                            },
+                            MsgType::Record => {
+                                return message.value.clone()
+                                    .map_or(Ok(()), |v| { handler.record(v); Ok(()) });
+                            },
+                            MsgType::MidiFile => {
+                                return message.value.clone()
+                                    .map_or(Ok(()), |v| { handler.midi_file(message.index, v); Ok(()) });
+                            },
+                            MsgType::Tuning => {
+                                return message.value.clone()
+                                    .map_or(Ok(()), |v| { handler.tuning(message.index, v); Ok(()) });
+                            },
+                            MsgType::Arpeggiator => {
+                                return message.value.clone()
+                                    .map_or(Ok(()), |v| { handler.arpeggiator(message.index, v); Ok(()) });
+                            },
+                            MsgType::Chord => {
+                                handler.chord(message.index, message.value.clone().unwrap_or(Value::VVU8(Vec::new())));
+                            },
+                            MsgType::Clock => {
+                                return message.value.clone()
+                                    .map_or(Ok(()), |v| { handler.clock(message.index, v); Ok(()) });
+                            },
+                            MsgType::SearchModules => {
+                                let query = message.value.clone().map_or(String::new(), |v| v.to_string());
+                                return handler.search_modules(webview, &query);
+                            },
                             MsgType::Loaded => {
                                 handler.loaded();
                             }
@@ -303,6 +455,41 @@ impl <'a> GUI<'a> {
         Ok(())
     }
 
+    fn audio_message(webview: &mut WebView<()>, m: &Message) -> WVResult {
+        match (m.id.clone(), m.value.clone()) {
+            (MessageID::Level, Value::VFPair((peak, rms))) => {
+                webview.eval(&format!("OnLevel({},{},{},{})", m.node, m.index, peak, rms)).unwrap();
+            },
+            (MessageID::OutputParam, value) => {
+                webview.eval(&format!("OnOutputParamChange({},{},{})", m.node, m.index, value.to_string())).unwrap();
+            },
+            (MessageID::Scope, value) => {
+                webview.eval(&format!("OnScope({},{})", m.node, value.to_string())).unwrap();
+            },
+            (MessageID::Spectrum, value) => {
+                webview.eval(&format!("OnSpectrum({},{},{})", m.node, m.index, value.to_string())).unwrap();
+            },
+            (MessageID::Transport, value) => {
+                Self::transport_change(webview, i32::from(value))?;
+            },
+            (MessageID::ClockPosition, Value::VFPair((tempo, beat))) => {
+                webview.eval(&format!("OnClockChange({},{},{},{})", m.node, m.index, tempo, beat)).unwrap();
+            },
+            _ => { }
+        }
+        Ok(())
+    }
+
+    fn recording_change(webview: &mut WebView<()>, path: &str) -> WVResult {
+        webview.eval(&format!("OnRecordChange({:?})", path)).unwrap();
+        Ok(())
+    }
+
+    fn transport_change(webview: &mut WebView<()>, rolling: i32) -> WVResult {
+        webview.eval(&format!("OnTransportChange({})", rolling)).unwrap();
+        Ok(())
+    }
+
     fn change_module(webview: &mut WebView<()>, url: &str, width: &str, height: &str) -> WVResult {
         let eval = format!("OnModuleChange(\"{}\",\"{}\",\"{}\")", url, width, height);
         webview.eval(&eval).unwrap();
@@ -323,4 +510,19 @@ impl <'a> GUI<'a> {
         webview.eval(&format!("OnAddModule(\"{}\",\"{}\")", name, json_url)).unwrap();
         Ok(())
     }
+
+    fn catalogue(webview: &mut WebView<()>, json: &str) -> WVResult {
+        webview.eval(&format!("OnModuleCatalogue({})", json)).unwrap();
+        Ok(())
+    }
+
+    fn load_progress(webview: &mut WebView<()>, url: &str, loaded: Index, total: Index) -> WVResult {
+        webview.eval(&format!("OnLoadProgress(\"{}\",{},{})", url, loaded, total)).unwrap();
+        Ok(())
+    }
+
+    fn load_status(webview: &mut WebView<()>, key: &str, status: Index) -> WVResult {
+        webview.eval(&format!("OnLoadStatus(\"{}\",{})", key, status)).unwrap();
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,364 @@
+//!
+//! JACK audio driver for standalone app
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use crossbeam_channel as cb;
+
+use aa_wasmtime::*;
+use crate::messages::*;
+use crate::comms::*;
+use crate::bundle::*;
+use crate::driver::*;
+use crate::engine::*;
+use crate::midi_device::MidiEvent;
+use crate::audio_backend::AudioThreadOnly;
+
+// JACK's type name for audio ports
+const AUDIO_PORT_TYPE: &str = "32 bit float mono audio";
+
+// how often the control thread checks whether the server has shut down
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// audio handler for JACK, registering a named port for each of the module's input and
+/// output channels and a single MIDI input port. Modules may be input only.
+pub fn audio_jack(
+    aaunit: Rc<RefCell<AAUnit>>,
+    host: Rc<RefCell<Host>>,
+    options: &JackOptions,
+    bundle: Bundle,
+    receive_from_gui: cb::Receiver<Message>,
+    receive_from_midi: cb::Receiver<MidiEvent>,
+    send_from_audio: cb::Sender<Message>) -> Option<Message> {
+
+    let client = match jack::Client::new(&options.client_name, jack::ClientOptions::NO_START_SERVER) {
+        Ok((client, _status)) => client,
+        Err(e) => {
+            eprintln!("Failed to connect to JACK server {:?}", e);
+            return None;
+        }
+    };
+
+    let num_inputs = bundle.info.inputs.max(0) as usize;
+    let num_outputs = bundle.info.outputs.max(0) as usize;
+
+    let mut in_ports = Vec::new();
+    for c in 0..num_inputs {
+        match client.register_port(&format!("in_{}", c + 1), jack::AudioIn::default()) {
+            Ok(port) => in_ports.push(port),
+            Err(e) => {
+                eprintln!("Failed to register JACK input port {:?}", e);
+                return None;
+            }
+        }
+    }
+
+    let mut out_ports = Vec::new();
+    for c in 0..num_outputs {
+        match client.register_port(&format!("out_{}", c + 1), jack::AudioOut::default()) {
+            Ok(port) => out_ports.push(port),
+            Err(e) => {
+                eprintln!("Failed to register JACK output port {:?}", e);
+                return None;
+            }
+        }
+    }
+
+    let midi_in = match client.register_port("midi_in", jack::MidiIn::default()) {
+        Ok(port) => port,
+        Err(e) => {
+            eprintln!("Failed to register JACK MIDI port {:?}", e);
+            return None;
+        }
+    };
+
+    // remember full port names, so they can be connected once the client is active
+    let in_names: Vec<String> = in_ports.iter().filter_map(|p| p.name().ok()).collect();
+    let out_names: Vec<String> = out_ports.iter().filter_map(|p| p.name().ok()).collect();
+    let midi_name = midi_in.name().ok();
+
+    // the module runs at whatever rate the server has been started with
+    let sample_rate = client.sample_rate() as f64;
+    let _ = aaunit.borrow_mut().init(sample_rate);
+    // output is heard at least a period later
+    host.borrow_mut().start_stream(sample_rate, bundle.info.outputs, client.buffer_size() as f64 / sample_rate);
+
+    let (send_stop, rec_stop) = channel();
+    let shut_down = Arc::new(AtomicBool::new(false));
+    let notifications = JackNotifications { shut_down: shut_down.clone() };
+    let buffer_size = client.buffer_size() as usize;
+    let process = JackProcess {
+        engine: AudioThreadOnly(Engine::new(
+            aaunit, host, &bundle, receive_from_gui, receive_from_midi, send_from_audio, send_stop)),
+        in_ports,
+        out_ports,
+        midi_in,
+        num_inputs,
+        num_outputs,
+        in_buffer: vec![0.0f32; buffer_size * num_inputs.max(1)],
+        out_buffer: vec![0.0f32; buffer_size * num_outputs.max(1)],
+        follow_transport: options.follow_transport && bundle.info.silent_when_stopped,
+        rolling: false,
+    };
+
+    let active_client = match client.activate_async(notifications, process) {
+        Ok(active_client) => active_client,
+        Err(e) => {
+            eprintln!("Failed to activate JACK client {:?}", e);
+            return None;
+        }
+    };
+
+    connect_ports(active_client.as_client(), options, &in_names, &out_names, midi_name);
+
+    // block until we recieve message to swap module, or the server shuts down
+    let message = wait_for_stop(&rec_stop, &shut_down);
+    let _ = active_client.deactivate();
+    message
+}
+
+// wait for a message to swap module, or for the server to shut down, in which case the
+// process callback will not be called again
+fn wait_for_stop(rec_stop: &Receiver<Option<Message>>, shut_down: &AtomicBool) -> Option<Message> {
+
+    loop {
+        match rec_stop.recv_timeout(SHUTDOWN_POLL) {
+            Ok(message) => return message,
+            Err(RecvTimeoutError::Timeout) => {
+                if shut_down.load(Ordering::SeqCst) {
+                    eprintln!("JACK server shut down");
+                    return None;
+                }
+            },
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+// flags when the server shuts down. JACK calls shutdown as if it were a signal handler,
+// so it only stores the flag, and the control thread reports it
+struct JackNotifications {
+    shut_down: Arc<AtomicBool>,
+}
+
+impl jack::NotificationHandler for JackNotifications {
+    fn shutdown(&mut self, _status: jack::ClientStatus, _reason: &str) {
+        self.shut_down.store(true, Ordering::SeqCst);
+    }
+}
+
+// runs the engine for each JACK buffer
+struct JackProcess {
+    engine: AudioThreadOnly<Engine>,
+    in_ports: Vec<jack::Port<jack::AudioIn>>,
+    out_ports: Vec<jack::Port<jack::AudioOut>>,
+    midi_in: jack::Port<jack::MidiIn>,
+    num_inputs: usize,
+    num_outputs: usize,
+    /// interleaved buffers, sized in buffer_size, so never allocated in process
+    in_buffer: Vec<f32>,
+    out_buffer: Vec<f32>,
+    follow_transport: bool,
+    rolling: bool,
+}
+
+impl jack::ProcessHandler for JackProcess {
+    fn process(&mut self, client: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+        let engine = &mut self.engine.0;
+        let (num_inputs, num_outputs) = (self.num_inputs, self.num_outputs);
+
+        // handle any incomming messages from UI
+        if !engine.handle_messages() {
+            return jack::Control::Quit;
+        }
+
+        // JACK MIDI events carry their offset within the buffer, while MIDI devices 
+        // are scheduled from their arrival time
+        let frames = ps.n_frames() as usize;
+        for event in self.midi_in.iter(ps) {
+            engine.schedule_bytes((event.time as usize).min(frames.max(1) - 1), event.bytes);
+        }
+        engine.schedule_midi(frames);
+
+        // report transport changes to the GUI
+        let now_rolling = client.transport().query_state()
+            .map_or(true, |s| matches!(s, jack::TransportState::Rolling));
+        if now_rolling != self.rolling {
+            self.rolling = now_rolling;
+            let _ = engine.send_from_audio().try_send(Message {
+                id: MessageID::Transport,
+                node: 0,
+                index: 0,
+                value: Value::VInt(self.rolling as i32),
+            });
+        }
+
+        if self.follow_transport && !self.rolling {
+            engine.flush_events();
+            engine.advance(frames);
+            for port in self.out_ports.iter_mut() {
+                for s in port.as_mut_slice(ps).iter_mut() {
+                    *s = 0.0;
+                }
+            }
+            return jack::Control::Continue;
+        }
+
+        // JACK ports are non-interleaved, while modules expect interleaved buffers
+        let in_buffer = &mut self.in_buffer[..frames * num_inputs];
+        let out_buffer = &mut self.out_buffer[..frames * num_outputs];
+        for (c, port) in self.in_ports.iter().enumerate() {
+            for (i, s) in port.as_slice(ps).iter().enumerate() {
+                in_buffer[i * num_inputs + c] = *s;
+            }
+        }
+
+        engine.schedule_midi_file(frames);
+        engine.apply_midi_effects(frames);
+        engine.set_host_params();
+        engine.compute_events(frames, in_buffer, out_buffer);
+        engine.report(in_buffer, out_buffer, frames);
+        engine.advance(frames);
+
+        for (c, port) in self.out_ports.iter_mut().enumerate() {
+            for (i, s) in port.as_mut_slice(ps).iter_mut().enumerate() {
+                *s = out_buffer[i * num_outputs + c];
+            }
+        }
+
+        jack::Control::Continue
+    }
+
+    // called before the first buffer, and whenever the server's buffer size changes, on
+    // the process thread, but allowed to allocate
+    fn buffer_size(&mut self, _: &jack::Client, size: jack::Frames) -> jack::Control {
+        let size = size as usize;
+        self.in_buffer.resize(size * self.num_inputs.max(1), 0.0);
+        self.out_buffer.resize(size * self.num_outputs.max(1), 0.0);
+        jack::Control::Continue
+    }
+}
+
+// connect ports, as requested by the user, once the client has been activated
+fn connect_ports(
+    client: &jack::Client,
+    options: &JackOptions,
+    in_names: &Vec<String>,
+    out_names: &Vec<String>,
+    midi_name: Option<String>) {
+
+    if options.auto_connect.inputs() {
+        let capture = client.ports(
+            None,
+            Some(AUDIO_PORT_TYPE),
+            jack::PortFlags::IS_PHYSICAL | jack::PortFlags::IS_OUTPUT);
+        for (src, dst) in capture.iter().zip(in_names.iter()) {
+            if let Err(e) = client.connect_ports_by_name(src, dst) {
+                eprintln!("Failed to connect {} to {} {:?}", src, dst, e);
+            }
+        }
+    }
+
+    if options.auto_connect.outputs() && !out_names.is_empty() {
+        let playback = client.ports(
+            None,
+            Some(AUDIO_PORT_TYPE),
+            jack::PortFlags::IS_PHYSICAL | jack::PortFlags::IS_INPUT);
+        // a mono module is sent to both sides of a stereo pair
+        for (i, dst) in playback.iter().take(out_names.len().max(2)).enumerate() {
+            let src = &out_names[i % out_names.len()];
+            if let Err(e) = client.connect_ports_by_name(src, dst) {
+                eprintln!("Failed to connect {} to {} {:?}", src, dst, e);
+            }
+        }
+    }
+
+    if let (Some(src), Some(dst)) = (&options.midi_connect, midi_name) {
+        if let Err(e) = client.connect_ports_by_name(src, &dst) {
+            eprintln!("Failed to connect {} to {} {:?}", src, dst, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Child, Command};
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn stop_returns_the_swap_message() {
+        let (send_stop, rec_stop) = channel();
+        let message = Message { id: MessageID::ChangeModule, node: 0, index: 0, value: Value::VInt(1) };
+        send_stop.send(Some(message)).unwrap();
+        let shut_down = AtomicBool::new(false);
+        let message = wait_for_stop(&rec_stop, &shut_down).unwrap();
+        assert_eq!(message.id, MessageID::ChangeModule);
+    }
+
+    #[test]
+    fn stop_returns_when_the_server_shuts_down() {
+        let (_send_stop, rec_stop) = channel();
+        let shut_down = AtomicBool::new(true);
+        assert!(wait_for_stop(&rec_stop, &shut_down).is_none());
+    }
+
+    // a JACK server with the dummy driver, under its own name, so it needs no audio
+    // hardware and does not disturb a running server
+    fn dummy_server(name: &str) -> Child {
+        let server = Command::new("jackd")
+            .args(&["--no-realtime", "-n", name, "-d", "dummy", "-r", "48000", "-p", "256"])
+            .spawn()
+            .expect("jackd is not installed");
+        thread::sleep(Duration::from_millis(500));
+        server
+    }
+
+    struct CountFrames(Arc<AtomicUsize>);
+
+    impl jack::ProcessHandler for CountFrames {
+        fn process(&mut self, _: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+            self.0.fetch_add(ps.n_frames() as usize, Ordering::SeqCst);
+            jack::Control::Continue
+        }
+    }
+
+    #[test]
+    #[ignore] // needs jackd installed
+    fn server_shutdown_is_flagged() {
+        let name = "aa_test_dummy";
+        std::env::set_var("JACK_DEFAULT_SERVER", name);
+        let mut server = dummy_server(name);
+
+        let (client, _) = jack::Client::new("aa_test", jack::ClientOptions::NO_START_SERVER).unwrap();
+        let frames = Arc::new(AtomicUsize::new(0));
+        let shut_down = Arc::new(AtomicBool::new(false));
+        let notifications = JackNotifications { shut_down: shut_down.clone() };
+        let active_client = client.activate_async(notifications, CountFrames(frames.clone())).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(frames.load(Ordering::SeqCst) > 0);
+        assert!(!shut_down.load(Ordering::SeqCst));
+
+        server.kill().unwrap();
+        server.wait().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !shut_down.load(Ordering::SeqCst) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(shut_down.load(Ordering::SeqCst));
+
+        let (_send_stop, rec_stop) = channel();
+        assert!(wait_for_stop(&rec_stop, &shut_down).is_none());
+        let _ = active_client.deactivate();
+    }
+}
@@ -0,0 +1,61 @@
+//!
+//! Peak and RMS level metering of interleaved audio buffers
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+/// per channel peak and RMS levels, accumulated over a number of buffers.
+/// Storage is allocated up front, so it is safe to use from the audio callback.
+pub struct Levels {
+    peak: Vec<f32>,
+    sum_squares: Vec<f32>,
+    frames: usize,
+}
+
+impl Levels {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            peak: vec![0.0; channels],
+            sum_squares: vec![0.0; channels],
+            frames: 0,
+        }
+    }
+
+    #[inline]
+    pub fn channels(&self) -> usize {
+        self.peak.len()
+    }
+
+    /// accumulate an interleaved buffer
+    pub fn accumulate(&mut self, buffer: &[f32], frames: usize) {
+        let channels = self.channels();
+        if channels == 0 {
+            return;
+        }
+        for frame in buffer.chunks(channels).take(frames) {
+            for (c, s) in frame.iter().enumerate() {
+                let a = s.abs();
+                if a > self.peak[c] {
+                    self.peak[c] = a;
+                }
+                self.sum_squares[c] += s * s;
+            }
+        }
+        self.frames += frames;
+    }
+
+    /// (peak, rms) for a channel since the last reset
+    pub fn level(&self, channel: usize) -> (f32, f32) {
+        let rms =
+            if self.frames > 0 { (self.sum_squares[channel] / self.frames as f32).sqrt() } else { 0.0 };
+        (self.peak[channel], rms)
+    }
+
+    pub fn reset(&mut self) {
+        for c in 0..self.channels() {
+            self.peak[c] = 0.0;
+            self.sum_squares[c] = 0.0;
+        }
+        self.frames = 0;
+    }
+}
@@ -0,0 +1,125 @@
+//!
+//! Ableton Link session, which the host's clock can follow
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::thread;
+
+use crossbeam_channel as cb;
+use rusty_link::{AblLink, SessionState};
+
+/// sessions requested, or left, and not yet handled by the handoff's thread
+const HANDOFF_QUEUE: usize = 4;
+
+/// a Link session, joined when created, whose state is captured from the audio thread.
+/// Tempo, beat, and start/stop are shared with all peers on the local network.
+pub struct LinkSession {
+    link: AblLink,
+    /// preallocated, so capturing does not allocate
+    state: SessionState,
+    /// beats in a bar, peers align their bars
+    quantum: f64,
+}
+
+impl LinkSession {
+    pub fn new(tempo: f64, quantum: f64) -> Self {
+        let link = AblLink::new(tempo);
+        link.enable(true);
+        link.enable_start_stop_sync(true);
+        Self {
+            link,
+            state: SessionState::new(),
+            quantum,
+        }
+    }
+
+    pub fn set_quantum(&mut self, quantum: f64) {
+        self.quantum = quantum;
+    }
+
+    /// (tempo, beat, playing) of the session, when a buffer computed now is heard, after
+    /// latency, in microseconds
+    pub fn capture(&mut self, latency: i64) -> (f64, f64, bool) {
+        self.link.capture_audio_session_state(&mut self.state);
+        let time = self.link.clock_micros() + latency;
+        (self.state.tempo(), self.state.beat_at_time(time, self.quantum).max(0.0), self.state.is_playing())
+    }
+
+    /// change the tempo of all peers
+    pub fn set_tempo(&mut self, tempo: f64) {
+        self.link.capture_audio_session_state(&mut self.state);
+        self.state.set_tempo(tempo, self.link.clock_micros());
+        self.link.commit_audio_session_state(&self.state);
+    }
+
+    /// start or stop all peers
+    pub fn set_playing(&mut self, playing: bool) {
+        self.link.capture_audio_session_state(&mut self.state);
+        self.state.set_is_playing(playing, self.link.clock_micros() as u64);
+        self.link.commit_audio_session_state(&self.state);
+    }
+
+    pub fn peers(&self) -> u64 {
+        self.link.num_peers()
+    }
+}
+
+impl Drop for LinkSession {
+    fn drop(&mut self) {
+        self.link.enable(false);
+    }
+}
+
+enum Request {
+    Join { tempo: f64, quantum: f64 },
+    Leave(LinkSession),
+}
+
+/// joins and leaves Link sessions on its own thread, as doing so starts and stops
+/// Link's threads and allocates, which the audio thread must not. The thread exits
+/// once the handoff is dropped.
+pub struct LinkHandoff {
+    requests: cb::Sender<Request>,
+    joined: cb::Receiver<LinkSession>,
+}
+
+impl LinkHandoff {
+    pub fn spawn() -> Self {
+        let (requests, receive_requests) = cb::bounded(HANDOFF_QUEUE);
+        let (send_joined, joined) = cb::bounded(HANDOFF_QUEUE);
+        thread::spawn(move || {
+            while let Ok(request) = receive_requests.recv() {
+                match request {
+                    Request::Join { tempo, quantum } => {
+                        if send_joined.send(LinkSession::new(tempo, quantum)).is_err() {
+                            break;
+                        }
+                    },
+                    Request::Leave(session) => drop(session),
+                }
+            }
+        });
+        Self {
+            requests,
+            joined,
+        }
+    }
+
+    /// request a session is joined, which is collected with joined, returns false if
+    /// the request could not be queued. Does not block.
+    pub fn join(&self, tempo: f64, quantum: f64) -> bool {
+        self.requests.try_send(Request::Join { tempo, quantum }).is_ok()
+    }
+
+    /// a session requested with join, once it has been joined
+    pub fn joined(&self) -> Option<LinkSession> {
+        self.joined.try_recv().ok()
+    }
+
+    /// leave a session, which is dropped on the handoff's thread, unless it has fallen
+    /// so far behind that the request cannot be queued
+    pub fn leave(&self, session: LinkSession) {
+        let _ = self.requests.try_send(Request::Leave(session));
+    }
+}
@@ -0,0 +1,241 @@
+//!
+//! Module loader, a worker thread that fetches, verifies, and instantiates the modules
+//! selected in the GUI, so the current module keeps playing while another downloads and
+//! compiles. The audio thread is only handed a module, and its voices, once ready.
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use crossbeam_channel as cb;
+
+use aa_wasmtime::*;
+use crate::messages::*;
+use crate::comms::{self, *};
+use crate::bundle::*;
+use crate::trust::*;
+use crate::repository::*;
+use crate::voices::*;
+use crate::audio_backend::AudioThreadOnly;
+use crate::standalone::Standalone;
+
+/// state of a module load, sent to GUI with LoadStatus
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadStatus {
+    Loading = 0,
+    /// swapped in by the audio thread, and running
+    Ready = 1,
+    Failed = 2,
+    /// another module was selected before it was ready
+    Cancelled = 3,
+}
+
+// a module to load, by key, and the generation it was requested in
+struct Request {
+    generation: u64,
+    key: String,
+}
+
+/// a module, fetched, verified, and instantiated, with its default parameters set, ready
+/// to be swapped in by the audio thread
+pub struct LoadedModule {
+    pub generation: u64,
+    /// key the module was requested with
+    pub key: String,
+    /// url of the module's repository
+    pub url: String,
+    pub bundle: Bundle,
+    pub aaunit: AAUnit,
+    /// additional instances, for host polyphony
+    pub voices: Option<Voices>,
+}
+
+/// requests modules to be loaded, each request cancelling any before it
+#[derive(Clone)]
+pub struct LoadSender {
+    requests: cb::Sender<Request>,
+    /// generation of the latest request
+    generation: Arc<AtomicU64>,
+}
+
+impl LoadSender {
+    /// load a module, by key, as sent to the GUI with AddModule, or a bundle relative to the
+    /// first repository
+    pub fn load(&self, key: &str) {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let _ = self.requests.send(Request { generation, key: key.to_string() });
+    }
+
+    /// cancel any load in progress
+    pub fn cancel(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// true if no module has been requested, or load cancelled, since generation
+    pub fn is_current(&self, generation: u64) -> bool {
+        self.generation.load(Ordering::SeqCst) == generation
+    }
+}
+
+/// sends messages from GUI to the audio thread, except for module changes, which are sent
+/// to the loader
+pub struct LoaderSend {
+    sender: cb::Sender<Message>,
+    loads: LoadSender,
+}
+
+impl LoaderSend {
+    pub fn new(sender: cb::Sender<Message>, loads: LoadSender) -> Self {
+        Self {
+            sender,
+            loads,
+        }
+    }
+}
+
+impl comms::Send for LoaderSend {
+    fn send(&self, id: MessageID, node: Index, index: Index, value: Value) -> Result<(), ()> {
+        match (id, value) {
+            (MessageID::ChangeModule, Value::VString(key)) => {
+                self.loads.load(&key);
+                Ok(())
+            },
+            (MessageID::ChangeModule, _) => Ok(()),
+            (id, value) => self.sender.send(Message { id, node, index, value }).map_or(Err(()), |_| Ok(())),
+        }
+    }
+}
+
+/// the loader, before its thread is started
+pub struct Loader {
+    requests: cb::Receiver<Request>,
+    /// generation of the latest request, shared with each LoadSender
+    generation: Arc<AtomicU64>,
+}
+
+impl Loader {
+    /// a loader, and the sender used to request modules from it
+    pub fn new() -> (Self, LoadSender) {
+        let (send, requests) = cb::unbounded();
+        let loads = LoadSender {
+            requests: send,
+            generation: Arc::new(AtomicU64::new(0)),
+        };
+        (Self { requests, generation: loads.generation.clone() }, loads)
+    }
+
+    fn is_current(&self, generation: u64) -> bool {
+        self.generation.load(Ordering::SeqCst) == generation
+    }
+
+    /// start the loader's thread. Each module loaded is sent on the returned channel, and
+    /// the audio thread told, with a ChangeModule message, to stop the running module and
+    /// swap it in. Download progress and load status are sent to GUI. The thread exits
+    /// once all LoadSenders are dropped.
+    pub(crate) fn spawn(
+        self,
+        modules: ModuleList,
+        trust: Trust,
+        voice_options: Option<VoiceOptions>,
+        comms: cb::Sender<Message>,
+        send_to_audio: cb::Sender<Message>) -> (thread::JoinHandle<()>, cb::Receiver<AudioThreadOnly<LoadedModule>>) {
+
+        let (send_ready, receive_ready) = cb::unbounded();
+        let thread = thread::spawn(move || {
+            while let Ok(mut request) = self.requests.recv() {
+                // only the latest request is loaded
+                while let Ok(next) = self.requests.try_recv() {
+                    send_load_status(&comms, &request.key, LoadStatus::Cancelled);
+                    request = next;
+                }
+
+                match self.load(&request, &modules, &trust, &voice_options, &comms) {
+                    _ if !self.is_current(request.generation) => {
+                        send_load_status(&comms, &request.key, LoadStatus::Cancelled);
+                    },
+                    // the audio thread reports that it is ready, once swapped in
+                    Some(module) => {
+                        if send_ready.send(AudioThreadOnly(module)).is_err() {
+                            // audio thread has exited
+                            break;
+                        }
+                        let _ = send_to_audio.send(Message {
+                            id: MessageID::ChangeModule,
+                            node: 0,
+                            index: 0,
+                            value: Value::VString(request.key.clone()),
+                        });
+                    },
+                    None => send_load_status(&comms, &request.key, LoadStatus::Failed),
+                }
+            }
+        });
+        (thread, receive_ready)
+    }
+
+    // fetch, verify, and instantiate a module, and its voices, giving up on any download,
+    // or before instantiating, if another module is requested
+    fn load(
+        &self,
+        request: &Request,
+        modules: &ModuleList,
+        trust: &Trust,
+        voice_options: &Option<VoiceOptions>,
+        comms: &cb::Sender<Message>) -> Option<LoadedModule> {
+
+        send_load_status(comms, &request.key, LoadStatus::Loading);
+        // the module's repository, or a bundle relative to the first
+        let (url, json) = modules.resolve(&request.key)
+            .unwrap_or_else(|| (modules.default.0.clone(), request.key.clone()));
+
+        let mut send_progress = Self::progress_sender(comms);
+        let mut progress = |wasm_url: &str, loaded, total| {
+            send_progress(wasm_url, loaded, total);
+            self.is_current(request.generation)
+        };
+        let (bundle, wasm_bytes) = Standalone::fetch_module(&url, &json, trust, &mut progress).ok()?;
+        if !self.is_current(request.generation) {
+            return None;
+        }
+
+        let aaunit = Standalone::instantiate(&wasm_bytes).ok()?;
+        Standalone::set_params(&aaunit, &bundle.gui.params);
+        let voices = Standalone::create_voices(&bundle, &wasm_bytes, voice_options);
+        Some(LoadedModule {
+            generation: request.generation,
+            key: request.key.clone(),
+            url,
+            bundle,
+            aaunit,
+            voices,
+        })
+    }
+
+    // a callback sending download progress to GUI, at most every percent, or every 64KB if
+    // the size is not known
+    fn progress_sender(comms: &cb::Sender<Message>) -> impl FnMut(&str, u64, u64) + '_ {
+        let mut sent: Option<(String, u64)> = None;
+        move |url, loaded, total| {
+            let step = if total > 0 { loaded * 100 / total } else { loaded >> 16 };
+            let changed = sent.as_ref().map_or(true, |(u, s)| u != url || *s != step);
+            if loaded > 0 && changed {
+                sent = Some((url.to_string(), step));
+                let _ = comms.send(Message::load_progress(url, loaded, total));
+            }
+        }
+    }
+
+}
+
+/// send a module's load status to GUI
+pub fn send_load_status(comms: &cb::Sender<Message>, key: &str, status: LoadStatus) {
+    let _ = comms.send(Message {
+        id: MessageID::LoadStatus,
+        node: 0,
+        index: status as Index,
+        value: Value::VString(key.to_string()),
+    });
+}
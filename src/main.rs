@@ -2,6 +2,8 @@
 //! main for standalone app
 //! Copyright: Benedict R. Gaster
 //! 
+use std::time::Duration;
+
 use clap::Clap;
 
 
@@ -18,8 +20,46 @@ mod bundle;
 mod utils;
 mod midi_utils;
 mod midi_device;
+mod driver;
+mod engine;
+mod audio_backend;
+mod levels;
+mod analyser;
+mod recorder;
+mod file_player;
+mod midi_file;
+mod render;
+mod voices;
+mod tuning;
+mod midi_effects;
+mod clock;
+mod validate;
+mod archive;
+mod trust;
+mod cache;
+mod repository;
+mod catalogue;
+mod loader;
+#[cfg(feature = "jack")]
+mod jack_audio;
+#[cfg(feature = "link")]
+mod link;
 
 use crate::midi_device::*;
+use crate::driver::*;
+use crate::recorder::RecordOptions;
+use crate::engine::{HostOptions, MidiFileOptions, TuningOptions};
+use crate::render::*;
+use crate::voices::*;
+use crate::midi_effects::*;
+use crate::clock::*;
+use crate::validate::{validate_repository, has_errors};
+use crate::archive::*;
+use crate::trust::*;
+use crate::cache::CacheOptions;
+use crate::utils::{set_http_options, HttpOptions};
+use crate::repository::*;
+use crate::catalogue::*;
 
 //-----------------------------------------------------------------------------
 
@@ -32,13 +72,166 @@ struct Opts {
     /// Optional port for AA server
     #[clap(short, long)]
     port: Option<String>,
+    /// Additional module repository, name=url or url, may be repeated
+    #[clap(long, number_of_values = 1)]
+    repository: Vec<String>,
+    /// File of additional module repositories, defaults to ~/.config/aa_standalone/repositories.json
+    #[clap(long)]
+    repositories: Option<String>,
+    /// Module archive (.aab), a file or URL, to run in place of the AA server
+    #[clap(long)]
+    bundle: Option<String>,
+    /// File of trusted public keys for module signatures, defaults to ~/.config/aa_standalone/trusted_keys
+    #[clap(long)]
+    trusted_keys: Option<String>,
+    /// Run modules that are not signed by a trusted key
+    #[clap(long)]
+    allow_unsigned: bool,
+    /// Directory to cache modules in, defaults to ~/.cache/aa_standalone
+    #[clap(long)]
+    cache_dir: Option<String>,
+    /// Do not cache modules
+    #[clap(long)]
+    no_cache: bool,
+    /// Load modules from the cache only, without using the network
+    #[clap(long)]
+    offline: bool,
+    /// Seconds allowed to connect to a server
+    #[clap(long, default_value = "10")]
+    connect_timeout: u64,
+    /// Seconds allowed to fetch a module file
+    #[clap(long, default_value = "120")]
+    timeout: u64,
+    /// Number of times a failed fetch is retried
+    #[clap(long, default_value = "3")]
+    retries: u32,
     /// Optional MIDI input device to use
     #[clap(short, long)]
     midi_device: Option<String>,
     #[clap(short, long)]
     list_midi_devices: bool,
+    /// Audio driver, portaudio, null, or jack (requires jack feature)
+    #[clap(short, long, default_value = "portaudio")]
+    driver: String,
+    /// JACK client name
+    #[clap(long, default_value = "aa_standalone")]
+    jack_name: String,
+    /// Automatically connect JACK ports to physical ports: none, inputs, outputs, or all
+    #[clap(long, default_value = "all")]
+    jack_connect: AutoConnect,
+    /// Optional JACK MIDI port to connect to the MIDI input
+    #[clap(long)]
+    jack_midi: Option<String>,
+    /// Silence modules marked silent_when_stopped while JACK transport is stopped
+    #[clap(long)]
+    jack_transport: bool,
+    /// Record output, from start up, to a .wav or .flac file
+    #[clap(short, long)]
+    record: Option<String>,
+    /// Bits per sample for recordings, 16, 24, or 32 (float, WAV only)
+    #[clap(long, default_value = "24")]
+    record_bits: u16,
+    /// MIDI CC that starts (value >= 64) and stops (value < 64) recording
+    #[clap(long)]
+    record_cc: Option<u8>,
+    /// Also record MIDI, and parameter changes, to a .mid file next to each recording
+    #[clap(long)]
+    record_midi: bool,
+    /// Audio file (.wav or .flac), looped, offered as an input device, may be repeated
+    #[clap(short, long, number_of_values = 1)]
+    input_file: Vec<String>,
+    /// Standard MIDI file to play into the module
+    #[clap(long)]
+    midi_file: Option<String>,
+    /// Loop the MIDI file
+    #[clap(long)]
+    midi_file_loop: bool,
+    /// Tempo scale for MIDI file playback, 1.0 is the file's tempo
+    #[clap(long, default_value = "1.0")]
+    midi_file_tempo: f64,
+    /// Render the MIDI file offline, as fast as possible, to a .wav or .flac file, without GUI or audio
+    #[clap(long)]
+    render: Option<String>,
+    /// Module bundle json, relative to URL, to render, defaults to the server's default module
+    #[clap(long)]
+    module: Option<String>,
+    /// Number of voices, for modules that declare voice parameters, 1 is monophonic
+    #[clap(long, default_value = "1")]
+    voices: usize,
+    /// Voice stolen, when all are sounding: oldest, lowest, or quietest
+    #[clap(long, default_value = "oldest")]
+    voice_stealing: Stealing,
+    /// Scala scale (.scl) used wherever notes are converted to frequency
+    #[clap(long)]
+    scale: Option<String>,
+    /// Scala keyboard mapping (.kbm) for the scale
+    #[clap(long)]
+    keyboard_map: Option<String>,
+    /// Arpeggiator mode: up, down, updown, random, or played (order notes were played)
+    #[clap(long)]
+    arp: Option<ArpMode>,
+    /// Arpeggiator steps per beat, e.g. 4 for 16th notes
+    #[clap(long, default_value = "4")]
+    arp_rate: f64,
+    /// Arpeggiator note length, as a fraction of a step
+    #[clap(long, default_value = "0.5")]
+    arp_gate: f64,
+    /// Number of octaves the arpeggiator spans
+    #[clap(long, default_value = "1")]
+    arp_octaves: u8,
+    /// Chord memory, semitones above each note played, e.g. 0,4,7
+    #[clap(long)]
+    chord: Option<String>,
+    /// Tempo, in BPM, of the host's clock
+    #[clap(long, default_value = "120")]
+    tempo: f64,
+    /// Beats per bar of the host's clock
+    #[clap(long, default_value = "4")]
+    beats_per_bar: u32,
+    /// Clock source: internal, midi to follow MIDI clock from the MIDI device, or link
+    /// to join an Ableton Link session (requires link feature)
+    #[clap(long, default_value = "internal")]
+    clock: ClockSource,
+    /// Optional MIDI output device to send MIDI clock, start, and stop to
+    #[clap(long)]
+    midi_clock_out: Option<String>,
+    /// Sample rate for offline rendering
+    #[clap(long, default_value = "44100")]
+    sample_rate: f64,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }   
 
+#[derive(Clap)]
+enum Command {
+    /// Check the module repository at URL, its modules.json and each module's bundle
+    Validate {
+        /// Do not check that each module's wasm files can be fetched
+        #[clap(long)]
+        skip_wasm: bool,
+    },
+    /// Search the modules of all repositories, e.g. "stereo effects" or "instruments with midi in"
+    Search {
+        /// Words to search for, all modules if none
+        query: Vec<String>,
+    },
+    /// Hash the wasm files of the bundle.json in a directory, and sign it
+    Sign {
+        /// Directory containing bundle.json and its wasm files
+        dir: String,
+        /// File containing a hex encoded 32 byte ed25519 secret key
+        #[clap(long)]
+        key: String,
+    },
+    /// Pack a directory, with a bundle.json, wasm files, and GUI, into a module archive (.aab)
+    Pack {
+        /// Directory to pack
+        dir: String,
+        /// Archive to create
+        output: String,
+    },
+}
+
 fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
 
@@ -52,15 +245,198 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
+    if let Some(Command::Sign { dir, key }) = &opts.command {
+        let secret_key = std::fs::read_to_string(key).map_err(|e| anyhow!("Failed to read key {}: {}", key, e))?;
+        let (json, public_key) = sign(dir, &secret_key).map_err(|e| anyhow!("Failed to sign {}: {}", dir, e))?;
+        let bundle = std::path::Path::new(dir).join(BUNDLE_JSON);
+        std::fs::write(&bundle, json).map_err(|e| anyhow!("Failed to write {}: {}", bundle.display(), e))?;
+        println!("Signed {}, add this public key to trusted keys:", bundle.display());
+        println!("{}", public_key);
+        return Ok(());
+    }
+
+    if let Some(Command::Pack { dir, output }) = &opts.command {
+        pack(dir, output).map_err(|e| anyhow!("Failed to pack {}: {}", dir, e))?;
+        return Ok(());
+    }
+
+    set_http_options(HttpOptions {
+        connect_timeout: Duration::from_secs(opts.connect_timeout),
+        timeout: Duration::from_secs(opts.timeout),
+        retries: opts.retries,
+        ..HttpOptions::default()
+    });
+
+    if !opts.no_cache {
+        let options = CacheOptions {
+            dir: opts.cache_dir.clone(),
+            offline: opts.offline,
+        };
+        if let Err(e) = cache::init(&options) {
+            eprintln!("Failed to create module cache {}", e);
+        }
+    }
+    else if opts.offline {
+        return Err(anyhow!("Offline requires the module cache"));
+    }
+
+    // an archive is served locally, in place of the AA server
+    let archive_server = match &opts.bundle {
+        Some(bundle) => {
+            let archive = Archive::open(bundle).map_err(|e| anyhow!("Failed to open module archive {}", e))?;
+            Some(ArchiveServer::start(archive).map_err(|e| anyhow!("Failed to serve module archive {}", e))?)
+        },
+        None => None,
+    };
+
     let url = 
-        if let Some(p) = opts.port {
+        if let Some(server) = &archive_server {
+            server.url().to_string()
+        }
+        else if let Some(p) = opts.port {
             [&opts.url, ":", &p].join("")
         }
         else {
             opts.url.clone()
         };
 
-    let standalone = Standalone::new(&url, opts.midi_device).map_err(|_| anyhow!("Failed to create standalone"))?;
+    // the AA server, or archive, is the first repository, followed by any others
+    let mut repositories = vec![Repository::parse(&url)];
+    if let Some(bundle) = &opts.bundle {
+        repositories[0].name = bundle.rsplit('/').next().unwrap_or(bundle).to_string();
+    }
+    repositories.extend(opts.repository.iter().map(|r| Repository::parse(r)));
+    repositories.extend(load_repositories(opts.repositories.as_deref())
+        .map_err(|e| anyhow!("Failed to load module repositories {}", e))?);
+
+    // the webview loads GUIs through the cache, so they are available offline
+    if cache::cache().is_some() {
+        let urls: Vec<String> = repositories.iter().map(|r| r.url.clone()).collect();
+        if let Err(e) = cache::serve(&urls) {
+            eprintln!("Failed to serve module cache {}", e);
+        }
+    }
+
+    if let Some(Command::Search { query }) = &opts.command {
+        let modules = ModuleList::load(&repositories).map_err(|e| anyhow!("Failed to load modules {}", e))?;
+        let catalogue = Catalogue::new(&modules);
+        let query = Query::parse(&query.join(" "));
+        for entry in catalogue.entries.iter().filter(|entry| query.matches(entry)) {
+            println!(
+                "{} / {} / {}: {:?}, {} in, {} out, MIDI {} in, {} out",
+                entry.repository, entry.category, entry.name, entry.kind,
+                entry.inputs, entry.outputs, entry.midi_inputs, entry.midi_outputs);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Validate { skip_wasm }) = opts.command {
+        let mut errors = 0;
+        let results = repositories.iter().flat_map(|r| validate_repository(&r.url, !skip_wasm));
+        for (file, diagnostics) in results {
+            if diagnostics.is_empty() {
+                println!("{}: ok", file);
+            }
+            else {
+                println!("{}:", file);
+                for diagnostic in diagnostics.iter() {
+                    println!("  {}", diagnostic);
+                }
+            }
+            if has_errors(&diagnostics) {
+                errors += 1;
+            }
+        }
+        if errors > 0 {
+            return Err(anyhow!("{} invalid files in module repositories", errors));
+        }
+        return Ok(());
+    }
+
+    let driver = 
+        match &opts.driver[..] {
+            "portaudio" => AudioDriver::PortAudio,
+            "null" => AudioDriver::Null,
+            "jack" => AudioDriver::Jack(JackOptions {
+                client_name: opts.jack_name.clone(),
+                auto_connect: opts.jack_connect,
+                midi_connect: opts.jack_midi.clone(),
+                follow_transport: opts.jack_transport,
+            }),
+            d => return Err(anyhow!("Unknown audio driver: {}", d)),
+        };
+
+    let trust = Trust::load(&TrustOptions {
+        keys_file: opts.trusted_keys.clone(),
+        allow_unsigned: opts.allow_unsigned,
+    }).map_err(|e| anyhow!("Failed to load trusted keys {}", e))?;
+
+    if ![16, 24, 32].contains(&opts.record_bits) {
+        return Err(anyhow!("Unsupported bits per sample for recording: {}", opts.record_bits));
+    }
+    let record = RecordOptions {
+        file: opts.record.clone(),
+        bits: opts.record_bits,
+        cc: opts.record_cc,
+        midi: opts.record_midi,
+    };
+
+    if let Some(output) = &opts.render {
+        let midi_file = opts.midi_file.clone().ok_or_else(|| anyhow!("Rendering requires a MIDI file"))?;
+        let options = RenderOptions {
+            url,
+            module: opts.module.clone(),
+            midi_file,
+            tempo_scale: opts.midi_file_tempo,
+            output: output.clone(),
+            bits: opts.record_bits,
+            sample_rate: opts.sample_rate,
+            trust,
+        };
+        render(&options).map_err(|_| anyhow!("Render failed"))?;
+        return Ok(());
+    }
+
+    let chord = match &opts.chord {
+        Some(chord) => chord.split(',')
+            .map(|i| i.trim().parse::<u8>())
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| anyhow!("Invalid chord: {}", chord))?,
+        None => Vec::new(),
+    };
+
+    let host_options = HostOptions {
+        record,
+        input_files: opts.input_file.clone(),
+        midi_file: opts.midi_file.clone().map(|file| MidiFileOptions {
+            file,
+            looping: opts.midi_file_loop,
+            tempo_scale: opts.midi_file_tempo,
+        }),
+        voices: Some(VoiceOptions {
+            count: opts.voices,
+            stealing: opts.voice_stealing,
+        }),
+        tuning: opts.scale.clone().map(|scale| TuningOptions {
+            scale,
+            keyboard_map: opts.keyboard_map.clone(),
+        }),
+        effects: MidiEffectsOptions {
+            arp: opts.arp,
+            arp_rate: opts.arp_rate,
+            arp_gate: opts.arp_gate,
+            arp_octaves: opts.arp_octaves,
+            chord,
+        },
+        clock: ClockOptions {
+            tempo: opts.tempo,
+            beats_per_bar: opts.beats_per_bar,
+            source: opts.clock,
+            midi_out: opts.midi_clock_out.clone(),
+        },
+    };
+
+    let standalone = Standalone::new(&repositories, opts.midi_device, driver, host_options, trust).map_err(|_| anyhow!("Failed to create standalone"))?;
     standalone.run().map_err(|_| anyhow!("Standalone run failed"))?;
     
     Ok(())
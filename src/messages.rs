@@ -9,6 +9,8 @@ pub enum Value {
     VString(String),
     VPair((u8,u8)),
     VVU8(Vec<u8>),
+    VFPair((f32,f32)),
+    VVF32(Vec<f32>),
 }
 
 impl From<Value> for i32 {
@@ -30,6 +32,13 @@ impl ToString for Value {
             Self::VPair((x,y)) => {
                 "[".to_string() + &x.to_string() + "," + &y.to_string() + "]"
             }
+            Self::VFPair((x,y)) => {
+                "[".to_string() + &x.to_string() + "," + &y.to_string() + "]"
+            }
+            Self::VVF32(v) => {
+                let v: Vec<String> = v.iter().map(|f| f.to_string()).collect();
+                "[".to_string() + &v.join(",") + "]"
+            },
             Self::VVU8(v) => {
                 let mut s = "[".to_string();
                 for (i, u) in v.iter().enumerate() {
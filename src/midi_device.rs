@@ -5,6 +5,7 @@ use rimd::{MidiMessage, Status, STATUS_MASK};
 
 use std::io::{stdin, stdout, Write};
 use std::error::Error;
+use std::time::{Duration, Instant};
 use midir::{MidiInput, Ignore, MidiInputConnection, MidiInputPort};
 //use std::sync::mpsc::{Sender};
 use crossbeam_channel as cb;
@@ -19,6 +20,24 @@ use crate::utils::*;
 //     port: usize,
 // }
 
+/// a MIDI message, timestamped on arrival, so that it can be scheduled at the
+/// correct sample within an audio buffer
+#[derive(Clone, Debug)]
+pub struct MidiEvent {
+    pub time: Instant,
+    pub message: MidiMessage,
+}
+
+impl MidiEvent {
+    /// an event that arrived now
+    pub fn now(message: MidiMessage) -> Self {
+        Self {
+            time: Instant::now(),
+            message,
+        }
+    }
+}
+
 pub struct Midi {
     input_connections: Vec<MidiInputConnection<()>>,
 }
@@ -51,7 +70,7 @@ impl Midi {
     pub fn open_input(
         &mut self, 
         device_name: String, 
-        sender: cb::Sender<MidiMessage>,
+        sender: cb::Sender<MidiEvent>,
         sender_to_gui: cb::Sender<Message>) -> Result<()> {
         MidiInput::new("midi input").map_or(
             err(), 
@@ -59,11 +78,23 @@ impl Midi {
             for (i, p) in input.ports().iter().enumerate() {
                 if let Ok(name) = input.port_name(p) {
                     if name == device_name {
+                        // midir's stamps, in microseconds, are relative to an arbitrary point, 
+                        // so are mapped to the system clock using the first event
+                        let mut base: Option<(u64, Instant)> = None;
                         let connection = input.connect(
                             p, 
                             &name, 
                             move |stamp, message, _| {
                                 //println!("{}: {:?} (len = {})", stamp, message, message.len());
+                                let now = Instant::now();
+                                let (base_stamp, base_time) = *base.get_or_insert((stamp, now));
+                                let mut time = 
+                                    base_time + Duration::from_micros(stamp.saturating_sub(base_stamp));
+                                // the first event was delivered late, so start again from this one
+                                if time > now {
+                                    base = Some((stamp, now));
+                                    time = now;
+                                }
                                 // send control messages to UI
                                 if message[0] & STATUS_MASK  == 0xB0 {
                                     let controller = message[1] as Index;
@@ -74,10 +105,15 @@ impl Midi {
                                         index: controller,
                                         value: Value::VInt(data),
                                     }).unwrap();
+                                    // audio also listens for some controls, e.g. record start/stop
+                                    let _ = sender.send(MidiEvent {
+                                        time,
+                                        message: MidiMessage::from_bytes(message.to_vec()),
+                                    });
                                 }
                                 else {
                                     let message = MidiMessage::from_bytes(message.iter().cloned().collect());
-                                    let _ = sender.send(message);
+                                    let _ = sender.send(MidiEvent { time, message });
                                 }
                             }, ());
                         
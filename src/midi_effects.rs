@@ -0,0 +1,605 @@
+//!
+//! Host side MIDI effects, an arpeggiator and chord memory, applied to notes before
+//! they reach the module
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::str::FromStr;
+
+use rimd::{MidiMessage, Status};
+
+/// order in which an arpeggiator plays held notes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArpMode {
+    Up,
+    Down,
+    /// up then down, without repeating the highest and lowest notes
+    UpDown,
+    Random,
+    /// in the order notes were pressed
+    AsPlayed,
+}
+
+impl ArpMode {
+    pub fn from_index(index: i32) -> Option<Self> {
+        match index {
+            0 => Some(ArpMode::Up),
+            1 => Some(ArpMode::Down),
+            2 => Some(ArpMode::UpDown),
+            3 => Some(ArpMode::Random),
+            4 => Some(ArpMode::AsPlayed),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ArpMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "up" => Ok(ArpMode::Up),
+            "down" => Ok(ArpMode::Down),
+            "updown" => Ok(ArpMode::UpDown),
+            "random" => Ok(ArpMode::Random),
+            "played" => Ok(ArpMode::AsPlayed),
+            _ => Err(format!("unknown arpeggiator mode: {}", s)),
+        }
+    }
+}
+
+/// options for MIDI effects, from the command line
+#[derive(Clone, Debug)]
+pub struct MidiEffectsOptions {
+    /// arpeggiator mode, None if disabled
+    pub arp: Option<ArpMode>,
+    /// arpeggiator steps per beat, e.g. 4 for 16th notes
+    pub arp_rate: f64,
+    /// arpeggiator note length, as a fraction of a step
+    pub arp_gate: f64,
+    /// number of octaves the arpeggiator spans
+    pub arp_octaves: u8,
+    /// chord memory, as semitones above each note played, empty if disabled
+    pub chord: Vec<u8>,
+}
+
+impl Default for MidiEffectsOptions {
+    fn default() -> Self {
+        Self {
+            arp: None,
+            arp_rate: 4.0,
+            arp_gate: 0.5,
+            arp_octaves: 1,
+            chord: Vec::new(),
+        }
+    }
+}
+
+// a note message, reusing a spare message, which only allocates if none are spare
+fn note_message(spare: &mut Vec<MidiMessage>, status: u8, note: u8, velocity: u8) -> MidiMessage {
+    let mut message = spare.pop().unwrap_or_else(|| MidiMessage::from_bytes(Vec::with_capacity(3)));
+    message.data.clear();
+    message.data.extend_from_slice(&[status, note, velocity]);
+    message
+}
+
+#[inline]
+fn note_on(spare: &mut Vec<MidiMessage>, note: u8, velocity: u8) -> MidiMessage {
+    note_message(spare, 0x90, note, velocity)
+}
+
+#[inline]
+fn note_off(spare: &mut Vec<MidiMessage>, note: u8) -> MidiMessage {
+    note_message(spare, 0x80, note, 0)
+}
+
+/// note on (Some(velocity)) or off (None) of a message, None if it is not a note
+fn as_note(message: &MidiMessage) -> Option<(u8, Option<u8>)> {
+    if message.data.len() < 3 {
+        return None;
+    }
+    match message.status() {
+        Status::NoteOn if message.data(2) > 0 => Some((message.data(1), Some(message.data(2)))),
+        Status::NoteOn | Status::NoteOff => Some((message.data(1), None)),
+        _ => None,
+    }
+}
+
+/// plays each note played as a chord, remembered as intervals above the note
+pub struct ChordMemory {
+    /// semitones above the note played, empty if disabled
+    intervals: Vec<u8>,
+    /// notes sounding for each note played, as a bit mask, so they are released even
+    /// if the chord changes, or is enabled or disabled, while the note is held
+    sounding: [u128; 128],
+    /// learning a new chord, from the notes held together
+    learning: bool,
+    learnt: u128,
+    held: u128,
+}
+
+impl ChordMemory {
+    pub fn new(intervals: Vec<u8>) -> Self {
+        Self {
+            intervals,
+            sounding: [0; 128],
+            learning: false,
+            learnt: 0,
+            held: 0,
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        !self.intervals.is_empty() || self.learning
+    }
+
+    /// true while any note played is held, whose notes must be released through expand
+    pub fn is_sounding(&self) -> bool {
+        self.sounding.iter().any(|notes| *notes != 0)
+    }
+
+    /// set chord, as semitones above each note played, empty to disable
+    pub fn set(&mut self, intervals: Vec<u8>) {
+        self.intervals = intervals;
+    }
+
+    /// learn the next chord played, from notes held together
+    pub fn learn(&mut self) {
+        self.learning = true;
+        self.learnt = 0;
+    }
+
+    // once all notes learnt are released, they become the chord
+    fn learn_note(&mut self, note: u8, on: bool) {
+        if on {
+            self.held |= 1 << note;
+            self.learnt |= 1 << note;
+        }
+        else {
+            self.held &= !(1 << note);
+            if self.held == 0 && self.learnt != 0 {
+                let root = self.learnt.trailing_zeros();
+                self.intervals = (0..128u32)
+                    .filter(|n| self.learnt & (1 << n) != 0)
+                    .map(|n| (n - root) as u8)
+                    .collect();
+                self.learning = false;
+            }
+        }
+    }
+
+    /// expand a note into the notes of the chord, calling f with each note and velocity
+    /// (None for note off). Notes are played as they are while learning, or disabled, and
+    /// released as they were played.
+    fn expand<F: FnMut(u8, Option<u8>)>(&mut self, note: u8, velocity: Option<u8>, mut f: F) {
+        let index = note as usize & 0x7F;
+        if self.learning {
+            self.learn_note(index as u8, velocity.is_some());
+        }
+        let notes = match velocity {
+            Some(_) if self.learning || self.intervals.is_empty() => 1u128 << index,
+            Some(_) => self.intervals.iter()
+                .map(|interval| index as u32 + *interval as u32)
+                .filter(|n| *n < 128)
+                .fold(0u128, |notes, n| notes | 1 << n),
+            // played before chord memory was in use
+            None if self.sounding[index] == 0 => 1u128 << index,
+            None => self.sounding[index],
+        };
+        self.sounding[index] = if velocity.is_some() { notes } else { 0 };
+        for n in 0..128u8 {
+            if notes & (1 << n) != 0 {
+                f(n, velocity);
+            }
+        }
+    }
+}
+
+/// plays held notes, one at a time, at a rate synced to the host's tempo
+pub struct Arpeggiator {
+    mode: Option<ArpMode>,
+    /// steps per beat
+    rate: f64,
+    /// note length, as a fraction of a step
+    gate: f64,
+    octaves: u8,
+    /// (note, velocity), in the order played
+    played: Vec<(u8, u8)>,
+    /// notes held, in ascending order
+    sorted: Vec<u8>,
+    /// index of next step within the pattern
+    step: usize,
+    /// frames until the next step
+    next_step: f64,
+    /// note sounding, and frames until its note off
+    sounding: Option<(u8, f64)>,
+    /// state of random number generator, xorshift
+    random: u32,
+}
+
+impl Arpeggiator {
+    pub fn new(mode: Option<ArpMode>, rate: f64, gate: f64, octaves: u8) -> Self {
+        Self {
+            mode,
+            rate: rate.max(0.0625),
+            gate: gate.max(0.01).min(1.0),
+            octaves: octaves.max(1),
+            played: Vec::with_capacity(128),
+            sorted: Vec::with_capacity(128),
+            step: 0,
+            next_step: 0.0,
+            sounding: None,
+            random: 0x1234_5678,
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.mode.is_some()
+    }
+
+    /// set the mode, None disables the arpeggiator, releasing held notes
+    pub fn set_mode(&mut self, mode: Option<ArpMode>) {
+        if mode.is_none() {
+            self.played.clear();
+            self.sorted.clear();
+        }
+        self.mode = mode;
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.rate = rate.max(0.0625);
+    }
+
+    pub fn set_gate(&mut self, gate: f64) {
+        self.gate = gate.max(0.01).min(1.0);
+    }
+
+    pub fn set_octaves(&mut self, octaves: u8) {
+        self.octaves = octaves.max(1);
+    }
+
+    fn hold(&mut self, note: u8, velocity: u8) {
+        if self.played.iter().any(|(n, _)| *n == note) {
+            return;
+        }
+        // start the pattern again, on the first note
+        if self.played.is_empty() {
+            self.step = 0;
+            self.next_step = 0.0;
+        }
+        self.played.push((note, velocity));
+        let position = self.sorted.iter().position(|n| *n > note).unwrap_or(self.sorted.len());
+        self.sorted.insert(position, note);
+    }
+
+    fn release(&mut self, note: u8) {
+        self.played.retain(|(n, _)| *n != note);
+        self.sorted.retain(|n| *n != note);
+    }
+
+    #[inline]
+    fn next_random(&mut self) -> u32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random
+    }
+
+    // note and velocity of the next step of the pattern
+    fn next_note(&mut self) -> Option<(u8, u8)> {
+        let n = self.played.len();
+        if n == 0 {
+            return None;
+        }
+        let total = n * self.octaves as usize;
+        let step = self.step;
+        self.step = self.step.wrapping_add(1);
+        let i = match self.mode? {
+            ArpMode::Up | ArpMode::AsPlayed => step % total,
+            ArpMode::Down => total - 1 - step % total,
+            ArpMode::UpDown => {
+                let period = (2 * total).saturating_sub(2).max(1);
+                let j = step % period;
+                if j < total { j } else { period - j }
+            },
+            ArpMode::Random => self.next_random() as usize % total,
+        };
+        let (note, octave) = (i % n, i / n);
+        let (note, velocity) = match self.mode? {
+            ArpMode::AsPlayed => self.played[note],
+            _ => {
+                let note = self.sorted[note];
+                (note, self.played.iter().find(|(n, _)| *n == note).map_or(100, |(_, v)| *v))
+            }
+        };
+        let note = note as usize + 12 * octave;
+        if note < 128 { Some((note as u8, velocity)) } else { None }
+    }
+
+    /// advance by frames, from offset, calling f with the offset and message of each
+    /// note on and off played, taken from spare
+    fn advance<F: FnMut(usize, MidiMessage)>(
+        &mut self,
+        offset: usize,
+        frames: f64,
+        step_frames: f64,
+        spare: &mut Vec<MidiMessage>,
+        f: &mut F) {
+        let mut position = 0.0;
+        loop {
+            let off = self.sounding.map_or(std::f64::INFINITY, |(_, off)| off);
+            let step = if self.played.is_empty() { std::f64::INFINITY } else { self.next_step };
+            let next = off.min(step);
+            if position + next >= frames {
+                let elapsed = frames - position;
+                self.next_step = (self.next_step - elapsed).max(0.0);
+                if let Some((_, off)) = self.sounding.as_mut() {
+                    *off -= elapsed;
+                }
+                return;
+            }
+
+            position += next;
+            self.next_step -= next;
+            if let Some((_, off)) = self.sounding.as_mut() {
+                *off -= next;
+            }
+            let at = offset + position as usize;
+
+            if let Some((note, off)) = self.sounding {
+                if off <= 0.0 || step <= next {
+                    f(at, note_off(spare, note));
+                    self.sounding = None;
+                }
+            }
+            if step <= next {
+                if let Some((note, velocity)) = self.next_note() {
+                    f(at, note_on(spare, note, velocity));
+                    self.sounding = Some((note, step_frames * self.gate));
+                }
+                self.next_step += step_frames;
+            }
+        }
+    }
+}
+
+/// the host's MIDI effects, chord memory followed by the arpeggiator
+pub struct MidiEffects {
+    pub chord: ChordMemory,
+    pub arp: Arpeggiator,
+}
+
+impl MidiEffects {
+    pub fn new(options: &MidiEffectsOptions) -> Self {
+        Self {
+            chord: ChordMemory::new(options.chord.clone()),
+            arp: Arpeggiator::new(options.arp, options.arp_rate, options.arp_gate, options.arp_octaves),
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.chord.enabled() || self.chord.is_sounding() || self.arp.enabled() || self.arp.sounding.is_some()
+    }
+
+    /// apply effects to a buffer's events, ordered by offset, which are moved to output,
+    /// with any notes the arpeggiator plays within the buffer. Notes played are taken
+    /// from spare, and notes consumed are returned to it, up to its capacity, so effects
+    /// do not allocate on the audio thread.
+    pub fn process(
+        &mut self,
+        input: &mut Vec<(usize, MidiMessage)>,
+        output: &mut Vec<(usize, MidiMessage)>,
+        spare: &mut Vec<MidiMessage>,
+        frames: usize,
+        sample_rate: f64,
+        tempo: f64) {
+        let step_frames = sample_rate * 60.0 / tempo.max(1.0) / self.arp.rate;
+        let mut position = 0;
+        for (offset, message) in input.drain(..) {
+            let offset = offset.min(frames);
+            if self.arp.enabled() || self.arp.sounding.is_some() {
+                self.arp.advance(
+                    position, (offset - position) as f64, step_frames, spare, &mut |o, m| output.push((o, m)));
+            }
+            position = offset;
+
+            match as_note(&message) {
+                Some((note, velocity)) => {
+                    let arp = &mut self.arp;
+                    let chord = &mut self.chord;
+                    let mut play = |note: u8, velocity: Option<u8>| {
+                        match (arp.enabled(), velocity) {
+                            (true, Some(velocity)) => arp.hold(note, velocity),
+                            (true, None) => arp.release(note),
+                            (false, Some(velocity)) => output.push((offset, note_on(spare, note, velocity))),
+                            (false, None) => output.push((offset, note_off(spare, note))),
+                        }
+                    };
+                    // always expanded, so notes held as the chord changes are released
+                    chord.expand(note, velocity, &mut play);
+                    if spare.len() < spare.capacity() {
+                        spare.push(message);
+                    }
+                },
+                None => output.push((offset, message)),
+            }
+        }
+        if self.arp.enabled() || self.arp.sounding.is_some() {
+            self.arp.advance(
+                position, (frames - position) as f64, step_frames, spare, &mut |o, m| output.push((o, m)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arpeggiator(mode: ArpMode, octaves: u8) -> Arpeggiator {
+        let mut arp = Arpeggiator::new(Some(mode), 4.0, 0.5, octaves);
+        for note in [64, 60, 67].iter() {
+            arp.hold(*note, 100);
+        }
+        arp
+    }
+
+    fn pattern(arp: &mut Arpeggiator, steps: usize) -> Vec<u8> {
+        (0..steps).filter_map(|_| arp.next_note()).map(|(note, _)| note).collect()
+    }
+
+    #[test]
+    fn arpeggiator_orders() {
+        assert_eq!(pattern(&mut arpeggiator(ArpMode::Up, 1), 6), vec![60, 64, 67, 60, 64, 67]);
+        assert_eq!(pattern(&mut arpeggiator(ArpMode::Down, 1), 6), vec![67, 64, 60, 67, 64, 60]);
+        assert_eq!(pattern(&mut arpeggiator(ArpMode::UpDown, 1), 7), vec![60, 64, 67, 64, 60, 64, 67]);
+        assert_eq!(pattern(&mut arpeggiator(ArpMode::AsPlayed, 1), 6), vec![64, 60, 67, 64, 60, 67]);
+        assert_eq!(pattern(&mut arpeggiator(ArpMode::Up, 2), 6), vec![60, 64, 67, 72, 76, 79]);
+        assert_eq!(pattern(&mut arpeggiator(ArpMode::AsPlayed, 2), 6), vec![64, 60, 67, 76, 72, 79]);
+    }
+
+    #[test]
+    fn random_order_plays_held_notes() {
+        let mut arp = arpeggiator(ArpMode::Random, 1);
+        assert!(pattern(&mut arp, 20).iter().all(|note| [60, 64, 67].contains(note)));
+    }
+
+    #[test]
+    fn released_notes_leave_the_pattern() {
+        let mut arp = arpeggiator(ArpMode::Up, 1);
+        arp.release(64);
+        assert_eq!(pattern(&mut arp, 4), vec![60, 67, 60, 67]);
+    }
+
+    fn message(bytes: &[u8]) -> MidiMessage {
+        MidiMessage::from_bytes(bytes.to_vec())
+    }
+
+    // (frame, bytes) of the events output for each block of input events
+    fn run(
+        effects: &mut MidiEffects,
+        frames: usize,
+        blocks: Vec<Vec<(usize, MidiMessage)>>) -> Vec<(usize, Vec<u8>)> {
+        let mut spare = Vec::with_capacity(16);
+        let mut played = Vec::new();
+        for (block, mut input) in blocks.into_iter().enumerate() {
+            let mut output = Vec::new();
+            // a step is 100 frames, at 60 BPM and 10 steps per beat
+            effects.process(&mut input, &mut output, &mut spare, frames, 1000.0, 60.0);
+            played.extend(output.into_iter().map(|(offset, m)| (block * frames + offset, m.data)));
+        }
+        played
+    }
+
+    #[test]
+    fn gate_is_timed_across_buffers() {
+        let mut effects = MidiEffects::new(&MidiEffectsOptions {
+            arp: Some(ArpMode::Up),
+            arp_rate: 10.0,
+            arp_gate: 0.5,
+            .. MidiEffectsOptions::default()
+        });
+        let mut blocks = vec![vec![(0, message(&[0x90, 60, 100]))]];
+        blocks.extend((0..3).map(|_| Vec::new()));
+        let played = run(&mut effects, 64, blocks);
+        assert_eq!(played, vec![
+            (0, vec![0x90, 60, 100]),
+            (50, vec![0x80, 60, 0]),
+            (100, vec![0x90, 60, 100]),
+            (150, vec![0x80, 60, 0]),
+            (200, vec![0x90, 60, 100]),
+            (250, vec![0x80, 60, 0]),
+        ]);
+    }
+
+    #[test]
+    fn the_last_note_is_released_after_its_gate() {
+        let mut effects = MidiEffects::new(&MidiEffectsOptions {
+            arp: Some(ArpMode::Up),
+            arp_rate: 10.0,
+            arp_gate: 0.5,
+            .. MidiEffectsOptions::default()
+        });
+        let played = run(&mut effects, 64, vec![
+            vec![(0, message(&[0x90, 60, 100]))],
+            vec![(0, message(&[0x80, 60, 0]))],
+            Vec::new(),
+        ]);
+        assert_eq!(played, vec![(0, vec![0x90, 60, 100]), (50, vec![0x80, 60, 0])]);
+    }
+
+    fn expand(chord: &mut ChordMemory, note: u8, velocity: Option<u8>) -> Vec<u8> {
+        let mut notes = Vec::new();
+        chord.expand(note, velocity, |n, _| notes.push(n));
+        notes
+    }
+
+    #[test]
+    fn chords_are_learnt_from_notes_held_together() {
+        let mut chord = ChordMemory::new(Vec::new());
+        chord.learn();
+        assert!(chord.enabled());
+        for note in [60, 64, 67].iter() {
+            assert_eq!(expand(&mut chord, *note, Some(100)), vec![*note]);
+        }
+        expand(&mut chord, 60, None);
+        expand(&mut chord, 64, None);
+        assert!(chord.learning);
+        expand(&mut chord, 67, None);
+        assert!(!chord.learning);
+        assert_eq!(chord.intervals, vec![0, 4, 7]);
+        assert_eq!(expand(&mut chord, 62, Some(100)), vec![62, 66, 69]);
+    }
+
+    #[test]
+    fn notes_are_released_as_they_were_played() {
+        let mut chord = ChordMemory::new(vec![0, 4, 7]);
+        assert_eq!(expand(&mut chord, 60, Some(100)), vec![60, 64, 67]);
+        chord.set(vec![0, 3]);
+        assert_eq!(expand(&mut chord, 60, None), vec![60, 64, 67]);
+        assert!(!chord.is_sounding());
+
+        // played before the chord was enabled
+        chord.set(Vec::new());
+        assert_eq!(expand(&mut chord, 62, Some(100)), vec![62]);
+        chord.set(vec![0, 4, 7]);
+        assert_eq!(expand(&mut chord, 62, None), vec![62]);
+
+        // notes above 127 are not played
+        assert_eq!(expand(&mut chord, 125, Some(100)), vec![125]);
+    }
+
+    #[test]
+    fn chords_are_released_after_they_are_disabled() {
+        let mut effects = MidiEffects::new(&MidiEffectsOptions { chord: vec![0, 7], .. MidiEffectsOptions::default() });
+        let mut played = run(&mut effects, 64, vec![vec![(10, message(&[0x90, 60, 100]))]]);
+        effects.chord.set(Vec::new());
+        assert!(effects.enabled());
+        played.extend(run(&mut effects, 64, vec![vec![(20, message(&[0x90, 60, 0])), (30, message(&[0xB0, 1, 2]))]]));
+        assert_eq!(played, vec![
+            (10, vec![0x90, 60, 100]),
+            (10, vec![0x90, 67, 100]),
+            (20, vec![0x80, 60, 0]),
+            (20, vec![0x80, 67, 0]),
+            (30, vec![0xB0, 1, 2]),
+        ]);
+        assert!(!effects.enabled());
+    }
+
+    #[test]
+    fn notes_reuse_spare_messages() {
+        let mut effects = MidiEffects::new(&MidiEffectsOptions { chord: vec![0, 4, 7], .. MidiEffectsOptions::default() });
+        let mut spare = Vec::with_capacity(4);
+        spare.extend((0..4).map(|_| message(&[0; 3])));
+        let mut input = vec![(0, message(&[0x90, 60, 100]))];
+        let mut output = Vec::new();
+        effects.process(&mut input, &mut output, &mut spare, 64, 1000.0, 60.0);
+        assert_eq!(output.len(), 3);
+        // three were taken, and the note played returned
+        assert_eq!(spare.len(), 2);
+        assert_eq!(spare.capacity(), 4);
+    }
+}
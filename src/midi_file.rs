@@ -0,0 +1,421 @@
+//!
+//! Standard MIDI File playback
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::path::Path;
+
+use rimd::{SMF, Event, MetaCommand, MidiMessage, Status};
+
+use crate::messages::*;
+
+/// default tempo, in microseconds per quarter note, until a tempo event is seen
+const DEFAULT_TEMPO: u64 = 500_000;
+/// first word of the text events that record parameter changes
+const PARAM_EVENT: &str = "param";
+
+/// an event played from a file
+pub enum FileEvent {
+    Midi(MidiMessage),
+    /// parameter change, replayed from a recording
+    Param(Index, Index, Value),
+}
+
+/// text event recording a parameter change, of the form "param <node> <index> <value>",
+/// with the value as sent to the module. Integer values are written without a decimal
+/// point, so they are replayed as integers.
+pub fn param_text(node: Index, index: Index, value: &Value) -> Option<String> {
+    match value {
+        Value::VInt(i) => Some(format!("{} {} {} {}", PARAM_EVENT, node, index, i)),
+        Value::VFloat(f) => Some(format!("{} {} {} {:?}", PARAM_EVENT, node, index, f)),
+        _ => None,
+    }
+}
+
+// parse a parameter change, written by param_text
+fn parse_param(text: &str) -> Option<(Index, Index, Value)> {
+    let mut words = text.split_whitespace();
+    if words.next() != Some(PARAM_EVENT) {
+        return None;
+    }
+    let node = words.next()?.parse().ok()?;
+    let index = words.next()?.parse().ok()?;
+    let value = words.next()?;
+    let value = match value.parse::<i32>() {
+        Ok(i) => Value::VInt(i),
+        Err(_) => Value::VFloat(value.parse().ok()?),
+    };
+    match words.next() {
+        None => Some((node, index, value)),
+        Some(_) => None,
+    }
+}
+
+/// plays the MIDI events of a Standard MIDI File, all tracks are merged, scheduled
+/// with sample offsets within each audio buffer. Parameter changes, recorded as text
+/// events, are played too.
+pub struct MidiFilePlayer {
+    /// (time in seconds, event), ordered by time
+    events: Vec<(f64, FileEvent)>,
+    /// index of next event to play
+    next: usize,
+    /// current position, in seconds
+    position: f64,
+    /// time of the last event
+    length: f64,
+    playing: bool,
+    looping: bool,
+    /// playback speed, 1.0 is the file's tempo
+    tempo_scale: f64,
+    /// notes currently sounding, so they can be released on stop or loop
+    sounding: [bool; 128],
+    /// note off for each note, created up front to avoid allocation in the audio thread
+    note_offs: Vec<FileEvent>,
+    /// release all notes on the next block
+    release: bool,
+}
+
+impl MidiFilePlayer {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let smf = SMF::from_file(Path::new(path)).map_err(|e| format!("{:?}", e))?;
+        Self::from_smf(&smf)
+    }
+
+    fn from_smf(smf: &SMF) -> Result<Self, String> {
+        if smf.division <= 0 {
+            return Err("SMPTE time division is not supported".to_string());
+        }
+        let division = smf.division as f64;
+
+        // merge tracks, in ticks, and collect tempo changes
+        let mut ticks = Vec::new();
+        let mut tempos = Vec::new();
+        for track in smf.tracks.iter() {
+            let mut tick = 0u64;
+            for event in track.events.iter() {
+                tick += event.vtime;
+                match &event.event {
+                    Event::Midi(message) => {
+                        ticks.push((tick, FileEvent::Midi(message.clone())));
+                    },
+                    Event::Meta(meta) => {
+                        if meta.command == MetaCommand::TempoSetting && meta.data.len() == 3 {
+                            let tempo =
+                                ((meta.data[0] as u64) << 16) | ((meta.data[1] as u64) << 8) | meta.data[2] as u64;
+                            tempos.push((tick, tempo));
+                        }
+                        else if meta.command == MetaCommand::TextEvent {
+                            let param = std::str::from_utf8(&meta.data).ok().and_then(parse_param);
+                            if let Some((node, index, value)) = param {
+                                ticks.push((tick, FileEvent::Param(node, index, value)));
+                            }
+                        }
+                    },
+                }
+            }
+        }
+        ticks.sort_by_key(|(tick, _)| *tick);
+        tempos.sort_by_key(|(tick, _)| *tick);
+
+        // convert ticks to seconds, following the tempo map
+        let mut events = Vec::with_capacity(ticks.len());
+        let mut tempo = DEFAULT_TEMPO;
+        let mut tempo_tick = 0u64;
+        let mut tempo_time = 0.0;
+        let mut t = 0;
+        for (tick, event) in ticks {
+            while t < tempos.len() && tempos[t].0 <= tick {
+                tempo_time += (tempos[t].0 - tempo_tick) as f64 * tempo as f64 / division / 1.0e6;
+                tempo_tick = tempos[t].0;
+                tempo = tempos[t].1;
+                t += 1;
+            }
+            let time = tempo_time + (tick - tempo_tick) as f64 * tempo as f64 / division / 1.0e6;
+            events.push((time, event));
+        }
+
+        let length = events.last().map_or(0.0, |(time, _)| *time);
+        Ok(Self {
+            events,
+            next: 0,
+            position: 0.0,
+            length,
+            playing: false,
+            looping: false,
+            tempo_scale: 1.0,
+            sounding: [false; 128],
+            note_offs: (0..128u8).map(|note| FileEvent::Midi(MidiMessage::from_bytes(vec![0x80, note, 0]))).collect(),
+            release: false,
+        })
+    }
+
+    pub fn play(&mut self) {
+        self.playing = true;
+    }
+
+    /// stop, releasing any sounding notes, and rewind
+    pub fn stop(&mut self) {
+        self.playing = false;
+        self.release = true;
+        self.next = 0;
+        self.position = 0.0;
+    }
+
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+
+    pub fn set_tempo_scale(&mut self, scale: f64) {
+        if scale > 0.0 {
+            self.tempo_scale = scale;
+        }
+    }
+
+    #[inline]
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// length of file, in seconds, at the file's tempo
+    #[inline]
+    pub fn length(&self) -> f64 {
+        self.length
+    }
+
+    /// true once playback has reached the end of a file that is not looping
+    pub fn finished(&self) -> bool {
+        !self.looping && self.next >= self.events.len()
+    }
+
+    fn release_all<F: FnMut(usize, &FileEvent)>(&mut self, offset: usize, f: &mut F) {
+        for note in 0..128 {
+            if self.sounding[note] {
+                self.sounding[note] = false;
+                f(offset, &self.note_offs[note]);
+            }
+        }
+    }
+
+    fn track(sounding: &mut [bool; 128], event: &FileEvent) {
+        let message = match event {
+            FileEvent::Midi(message) if message.data.len() >= 3 => message,
+            _ => return,
+        };
+        let note = (message.data(1) & 0x7F) as usize;
+        match message.status() {
+            Status::NoteOn if message.data(2) > 0 => sounding[note] = true,
+            Status::NoteOn | Status::NoteOff => sounding[note] = false,
+            _ => { },
+        }
+    }
+
+    /// advance by a buffer of frames, calling f with the sample offset, within the
+    /// buffer, and each event that falls within it. Events are borrowed from the file,
+    /// so playback does not allocate.
+    pub fn next_block<F: FnMut(usize, &FileEvent)>(&mut self, frames: usize, sample_rate: f64, mut f: F) {
+        // no offset falls within an empty buffer
+        if frames == 0 {
+            return;
+        }
+        if self.release {
+            self.release = false;
+            self.release_all(0, &mut f);
+        }
+        if !self.playing {
+            return;
+        }
+
+        // duration of buffer, in file time
+        let seconds_per_frame = self.tempo_scale / sample_rate;
+        let mut end = self.position + frames as f64 * seconds_per_frame;
+        let mut start = self.position;
+        let mut base = 0.0;
+        loop {
+            while self.next < self.events.len() && self.events[self.next].0 < end {
+                let (time, event) = &self.events[self.next];
+                let offset = base + (time - start).max(0.0) / seconds_per_frame;
+                let offset = (offset as usize).min(frames - 1);
+                Self::track(&mut self.sounding, event);
+                f(offset, event);
+                self.next += 1;
+            }
+
+            if self.next < self.events.len() {
+                self.position = end;
+                return;
+            }
+
+            if !self.looping {
+                self.playing = false;
+                self.position = end;
+                self.release_all(frames - 1, &mut f);
+                return;
+            }
+
+            // wrap around to the start, continuing within the same buffer
+            let offset = base + (self.length - start).max(0.0) / seconds_per_frame;
+            self.release_all((offset as usize).min(frames - 1), &mut f);
+            self.next = 0;
+            base = offset;
+            end -= self.length;
+            start = 0.0;
+            if end <= 0.0 || self.length <= 0.0 {
+                self.position = end.max(0.0);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rimd::{MetaEvent, SMFFormat, Track, TrackEvent};
+
+    const DIVISION: i16 = 480;
+    // so block lengths and offsets are exact in binary
+    const SAMPLE_RATE: f64 = 1024.0;
+
+    fn note(vtime: u64, status: u8, note: u8, velocity: u8) -> TrackEvent {
+        TrackEvent { vtime, event: Event::Midi(MidiMessage::from_bytes(vec![status, note, velocity])) }
+    }
+
+    // a file at 120 BPM, so a quarter note is half a second
+    fn player(events: Vec<TrackEvent>) -> MidiFilePlayer {
+        let smf = SMF {
+            format: SMFFormat::Single,
+            tracks: vec![Track { copyright: None, name: None, events }],
+            division: DIVISION,
+        };
+        MidiFilePlayer::from_smf(&smf).unwrap()
+    }
+
+    // (block, offset, bytes) of each event played in blocks of frames
+    fn play(player: &mut MidiFilePlayer, frames: usize, blocks: usize) -> Vec<(usize, usize, Vec<u8>)> {
+        let mut played = Vec::new();
+        for block in 0..blocks {
+            player.next_block(frames, SAMPLE_RATE, |offset, event| {
+                if let FileEvent::Midi(message) = event {
+                    played.push((block, offset, message.data.clone()));
+                }
+            });
+        }
+        played
+    }
+
+    #[test]
+    fn times_follow_the_tempo_map() {
+        let player = player(vec![
+            note(480, 0x90, 60, 100),
+            TrackEvent { vtime: 0, event: Event::Meta(MetaEvent::tempo_setting(250_000)) },
+            note(480, 0x80, 60, 0),
+        ]);
+        let times: Vec<f64> = player.events.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, vec![0.5, 0.75]);
+        assert_eq!(player.length(), 0.75);
+    }
+
+    #[test]
+    fn events_are_scheduled_at_their_offset() {
+        let mut player = player(vec![note(120, 0x90, 60, 100), note(360, 0x80, 60, 0)]);
+        player.play();
+        let played = play(&mut player, 96, 6);
+        assert_eq!(played, vec![(1, 32, vec![0x90, 60, 100]), (5, 32, vec![0x80, 60, 0])]);
+    }
+
+    #[test]
+    fn tempo_scale_changes_speed() {
+        let mut player = player(vec![note(480, 0x90, 60, 100), note(0, 0x80, 60, 0)]);
+        player.set_tempo_scale(2.0);
+        player.set_tempo_scale(0.0);
+        player.play();
+        let played = play(&mut player, 96, 3);
+        assert_eq!(played[0], (2, 64, vec![0x90, 60, 100]));
+    }
+
+    #[test]
+    fn the_end_releases_sounding_notes() {
+        let mut player = player(vec![note(0, 0x90, 60, 100), note(0, 0x90, 64, 100), note(240, 0x80, 60, 0)]);
+        player.play();
+        let played = play(&mut player, 96, 4);
+        assert_eq!(played[2], (2, 64, vec![0x80, 60, 0]));
+        assert_eq!(played[3], (2, 95, vec![0x80, 64, 0]));
+        assert_eq!(played.len(), 4);
+        assert!(!player.is_playing());
+        assert!(player.finished());
+    }
+
+    #[test]
+    fn stop_releases_sounding_notes_and_rewinds() {
+        let mut player = player(vec![note(0, 0x90, 60, 100), note(960, 0x80, 60, 0)]);
+        player.play();
+        play(&mut player, 100, 1);
+        player.stop();
+        let played = play(&mut player, 100, 1);
+        assert_eq!(played, vec![(0, 0, vec![0x80, 60, 0])]);
+        player.play();
+        let played = play(&mut player, 100, 1);
+        assert_eq!(played, vec![(0, 0, vec![0x90, 60, 100])]);
+    }
+
+    #[test]
+    fn looping_wraps_within_a_buffer() {
+        // a quarter second long
+        let mut player = player(vec![note(0, 0x90, 60, 100), note(240, 0x80, 60, 0)]);
+        player.set_looping(true);
+        player.play();
+        let played = play(&mut player, 300, 1);
+        assert_eq!(played, vec![
+            (0, 0, vec![0x90, 60, 100]),
+            (0, 256, vec![0x80, 60, 0]),
+            (0, 256, vec![0x90, 60, 100]),
+        ]);
+        assert!(player.is_playing());
+        assert!(!player.finished());
+    }
+
+    #[test]
+    fn empty_buffers_are_ignored() {
+        let mut player = player(vec![note(0, 0x90, 60, 100), note(960, 0x80, 60, 0)]);
+        player.play();
+        assert_eq!(play(&mut player, 10, 1), vec![(0, 0, vec![0x90, 60, 100])]);
+        assert!(play(&mut player, 0, 2).is_empty());
+        player.stop();
+        assert!(play(&mut player, 0, 1).is_empty());
+        assert_eq!(play(&mut player, 10, 1), vec![(0, 0, vec![0x80, 60, 0])]);
+    }
+
+    #[test]
+    fn params_are_played_from_text_events() {
+        let text = |text: &str| TrackEvent { vtime: 240, event: Event::Meta(MetaEvent::text_event(text.to_string())) };
+        let mut player = player(vec![
+            text(&param_text(1, 2, &Value::VFloat(0.5)).unwrap()),
+            text(&param_text(3, 4, &Value::VInt(7)).unwrap()),
+            text("param 1 2"),
+            text("a comment"),
+        ]);
+        player.play();
+        let mut params = Vec::new();
+        player.next_block(1024, SAMPLE_RATE, |offset, event| {
+            if let FileEvent::Param(node, index, value) = event {
+                params.push((offset, *node, *index, value.to_string(), matches!(value, Value::VInt(_))));
+            }
+        });
+        assert_eq!(params, vec![
+            (256, 1, 2, "0.5".to_string(), false),
+            (512, 3, 4, "7".to_string(), true),
+        ]);
+    }
+
+    #[test]
+    fn param_text_keeps_the_value_type() {
+        assert_eq!(param_text(0, 1, &Value::VFloat(2.0)).unwrap(), "param 0 1 2.0");
+        assert!(matches!(parse_param("param 0 1 2.0"), Some((0, 1, Value::VFloat(v))) if v == 2.0));
+        assert!(matches!(parse_param("param 0 1 2"), Some((0, 1, Value::VInt(2)))));
+        assert!(matches!(parse_param("param 0 1 1e-7"), Some((0, 1, Value::VFloat(_)))));
+        assert!(param_text(0, 1, &Value::VString("x".to_string())).is_none());
+        assert!(parse_param("param 0 1 2 3").is_none());
+        assert!(parse_param("param 0 1 x").is_none());
+    }
+}
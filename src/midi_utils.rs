@@ -1,14 +1,43 @@
 //! midi
 
 use std::fmt;
+use std::convert::TryFrom;
+use std::str::FromStr;
 
+/// MIDI notes, named in scientific pitch notation, where middle C (60) is C4. 
+/// Octave -1 is written N1, e.g. CN1 is note 0.
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq)]
-pub enum NoteSym {    
-    A0 = 21,
+pub enum NoteSym {
+    // octave -1, written N1
+    CN1 = 0,
+    CSN1 = 1,
+    DN1 = 2,
+    DSN1 = 3,
+    EN1 = 4,
+    FN1 = 5,
+    FSN1 = 6,
+    GN1 = 7,
+    GSN1 = 8,
+    AN1 = 9,
+    ASN1 = 10,
+    BN1 = 11,
+
+    // octave 0
+    C0  = 12,
+    CS0 = 13,
+    D0  = 14,
+    DS0 = 15,
+    E0  = 16,
+    F0  = 17,
+    FS0 = 18,
+    G0  = 19,
+    GS0 = 20,
+    A0  = 21,
     AS0 = 22,
     B0  = 23,
 
+    // octave 1
     C1  = 24,
     CS1 = 25,
     D1  = 26,
@@ -22,6 +51,7 @@ pub enum NoteSym {
     AS1 = 34,
     B1  = 35,
 
+    // octave 2
     C2  = 36,
     CS2 = 37,
     D2  = 38,
@@ -35,6 +65,7 @@ pub enum NoteSym {
     AS2 = 46,
     B2  = 47,
 
+    // octave 3
     C3  = 48,
     CS3 = 49,
     D3  = 50,
@@ -48,13 +79,13 @@ pub enum NoteSym {
     AS3 = 58,
     B3  = 59,
 
+    // octave 4
     C4  = 60,
     CS4 = 61,
     D4  = 62,
     DS4 = 63,
     E4  = 64,
     F4  = 65,
-
     FS4 = 66,
     G4  = 67,
     GS4 = 68,
@@ -62,6 +93,7 @@ pub enum NoteSym {
     AS4 = 70,
     B4  = 71,
 
+    // octave 5
     C5  = 72,
     CS5 = 73,
     D5  = 74,
@@ -75,6 +107,7 @@ pub enum NoteSym {
     AS5 = 82,
     B5  = 83,
 
+    // octave 6
     C6  = 84,
     CS6 = 85,
     D6  = 86,
@@ -88,6 +121,7 @@ pub enum NoteSym {
     AS6 = 94,
     B6  = 95,
 
+    // octave 7
     C7  = 96,
     CS7 = 97,
     D7  = 98,
@@ -101,11 +135,52 @@ pub enum NoteSym {
     AS7 = 106,
     B7  = 107,
 
+    // octave 8
     C8  = 108,
-
-    None,
+    CS8 = 109,
+    D8  = 110,
+    DS8 = 111,
+    E8  = 112,
+    F8  = 113,
+    FS8 = 114,
+    G8  = 115,
+    GS8 = 116,
+    A8  = 117,
+    AS8 = 118,
+    B8  = 119,
+
+    // octave 9
+    C9  = 120,
+    CS9 = 121,
+    D9  = 122,
+    DS9 = 123,
+    E9  = 124,
+    F9  = 125,
+    FS9 = 126,
+    G9  = 127,
 }
 
+// all notes, indexed by MIDI note number
+const NOTES: [NoteSym; 128] = [
+    NoteSym::CN1, NoteSym::CSN1, NoteSym::DN1, NoteSym::DSN1, NoteSym::EN1, NoteSym::FN1, NoteSym::FSN1, NoteSym::GN1, NoteSym::GSN1, NoteSym::AN1, NoteSym::ASN1, NoteSym::BN1,
+    NoteSym::C0, NoteSym::CS0, NoteSym::D0, NoteSym::DS0, NoteSym::E0, NoteSym::F0, NoteSym::FS0, NoteSym::G0, NoteSym::GS0, NoteSym::A0, NoteSym::AS0, NoteSym::B0,
+    NoteSym::C1, NoteSym::CS1, NoteSym::D1, NoteSym::DS1, NoteSym::E1, NoteSym::F1, NoteSym::FS1, NoteSym::G1, NoteSym::GS1, NoteSym::A1, NoteSym::AS1, NoteSym::B1,
+    NoteSym::C2, NoteSym::CS2, NoteSym::D2, NoteSym::DS2, NoteSym::E2, NoteSym::F2, NoteSym::FS2, NoteSym::G2, NoteSym::GS2, NoteSym::A2, NoteSym::AS2, NoteSym::B2,
+    NoteSym::C3, NoteSym::CS3, NoteSym::D3, NoteSym::DS3, NoteSym::E3, NoteSym::F3, NoteSym::FS3, NoteSym::G3, NoteSym::GS3, NoteSym::A3, NoteSym::AS3, NoteSym::B3,
+    NoteSym::C4, NoteSym::CS4, NoteSym::D4, NoteSym::DS4, NoteSym::E4, NoteSym::F4, NoteSym::FS4, NoteSym::G4, NoteSym::GS4, NoteSym::A4, NoteSym::AS4, NoteSym::B4,
+    NoteSym::C5, NoteSym::CS5, NoteSym::D5, NoteSym::DS5, NoteSym::E5, NoteSym::F5, NoteSym::FS5, NoteSym::G5, NoteSym::GS5, NoteSym::A5, NoteSym::AS5, NoteSym::B5,
+    NoteSym::C6, NoteSym::CS6, NoteSym::D6, NoteSym::DS6, NoteSym::E6, NoteSym::F6, NoteSym::FS6, NoteSym::G6, NoteSym::GS6, NoteSym::A6, NoteSym::AS6, NoteSym::B6,
+    NoteSym::C7, NoteSym::CS7, NoteSym::D7, NoteSym::DS7, NoteSym::E7, NoteSym::F7, NoteSym::FS7, NoteSym::G7, NoteSym::GS7, NoteSym::A7, NoteSym::AS7, NoteSym::B7,
+    NoteSym::C8, NoteSym::CS8, NoteSym::D8, NoteSym::DS8, NoteSym::E8, NoteSym::F8, NoteSym::FS8, NoteSym::G8, NoteSym::GS8, NoteSym::A8, NoteSym::AS8, NoteSym::B8,
+    NoteSym::C9, NoteSym::CS9, NoteSym::D9, NoteSym::DS9, NoteSym::E9, NoteSym::F9, NoteSym::FS9, NoteSym::G9,
+];
+
+// names of pitch classes, with sharps
+const PITCH_CLASSES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// octave of middle C, in scientific pitch notation. Some manufacturers use 3 (Yamaha) or 5.
+pub const MIDDLE_C_OCTAVE: i32 = 4;
+
 pub type Pitch = f32;
 
 impl NoteSym {
@@ -132,211 +207,202 @@ impl NoteSym {
         2.0f32.powf( (self as i32 - 69) as f32 / 12.0 ) * tuning
     }
 
-    pub fn from_u8(value: u8) -> NoteSym {
-        match value {
-            21 => NoteSym::A0,
-            22 => NoteSym::AS0,
-            23 => NoteSym::B0,
-
-            24 => NoteSym::C1,
-            25 => NoteSym::CS1,
-            26 => NoteSym::D1,
-            27 => NoteSym::DS1,
-            28 => NoteSym::E1,
-            29 => NoteSym::F1,
-            30 => NoteSym::FS1,
-            31 => NoteSym::G1,
-            32 => NoteSym::GS1,
-            33 => NoteSym::A1,
-            34 => NoteSym::AS1,
-            35 => NoteSym::B1,
-
-            36 => NoteSym::C2,
-            37 => NoteSym::CS2,
-            38 => NoteSym::D2,
-            39 => NoteSym::DS2,
-            40 => NoteSym::E2,
-            41 => NoteSym::F2,
-            42 => NoteSym::FS2,
-            43 => NoteSym::G2,
-            44 => NoteSym::GS2,
-            45 => NoteSym::A2,
-            46 => NoteSym::AS2,
-            47 => NoteSym::B2,
-
-            48 => NoteSym::C3,
-            49 => NoteSym::CS3,
-            50 => NoteSym::D3,
-            51 => NoteSym::DS3,
-            52 => NoteSym::E3,
-            53 => NoteSym::F3,
-            54 => NoteSym::FS3,
-            55 => NoteSym::G3,
-            56 => NoteSym::GS3,
-            57 => NoteSym::A3,
-            58 => NoteSym::AS3,
-            59 => NoteSym::B3,
-
-            60 => NoteSym::C4,
-            61 => NoteSym::CS4,
-            62 => NoteSym::D4,
-            63 => NoteSym::DS4,
-            64 => NoteSym::E4,
-            65 => NoteSym::F4,
-
-            66 => NoteSym::FS4,
-            67 => NoteSym::G4,
-            68 => NoteSym::GS4,
-            69 => NoteSym::A4,
-            70 => NoteSym::AS4,
-            71 => NoteSym::B4,
-
-            72 => NoteSym::C5,
-            73 => NoteSym::CS5,
-            74 => NoteSym::D5,
-            75 => NoteSym::DS5,
-            76 => NoteSym::E5,
-            77 => NoteSym::F5,
-            78 => NoteSym::FS5,
-            79 => NoteSym::G5,
-            80 => NoteSym::GS5,
-            81 => NoteSym::A5,
-            82 => NoteSym::AS5,
-            83 => NoteSym::B5,
-
-            84 => NoteSym::C6,
-            85 => NoteSym::CS6,
-            86 => NoteSym::D6,
-            87 => NoteSym::DS6,
-            88 => NoteSym::E6,
-            89 => NoteSym::F6,
-            90 => NoteSym::FS6,
-            91 => NoteSym::G6,
-            92 => NoteSym::GS6,
-            93 => NoteSym::A6,
-            94 => NoteSym::AS6,
-            95 => NoteSym::B6,
-
-            96 => NoteSym::C7,
-            97 => NoteSym::CS7,
-            98 => NoteSym::D7,
-            99 => NoteSym::DS7,
-            100 => NoteSym::E7,
-            101 => NoteSym::F7,
-            102 => NoteSym::FS7,
-            103 => NoteSym::G7,
-            104 => NoteSym::GS7,
-            105 => NoteSym::A7,
-            106 => NoteSym::AS7,
-            107 => NoteSym::B7,
-
-            108 => NoteSym::C8,
-            _ => panic!("Unknown value: {}", value),
+    /// nearest note to a frequency, in 12 tone equal temperament, None if outside of 
+    /// the MIDI note range
+    pub fn from_freq(freq: Pitch) -> Option<NoteSym> {
+        if freq.is_nan() || freq <= 0.0 {
+            return None;
+        }
+        let note = (69.0 + 12.0 * (freq / 440.0).log2()).round();
+        if !(0.0..=127.0).contains(&note) { None } else { Self::from_u8(note as u8) }
+    }
+
+    /// MIDI note number
+    #[inline]
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// convert a MIDI note number, None if it is not a note (i.e. above 127)
+    #[inline]
+    pub fn from_u8(value: u8) -> Option<NoteSym> {
+        NOTES.get(value as usize).cloned()
+    }
+
+    /// octave of note, with middle C in middle_c_octave
+    #[inline]
+    pub fn octave(self, middle_c_octave: i32) -> i32 {
+        self as i32 / 12 + middle_c_octave - 5
+    }
+
+    /// name of note, e.g. C#4, with middle C in middle_c_octave
+    pub fn name(self, middle_c_octave: i32) -> String {
+        format!("{}{}", PITCH_CLASSES[self as usize % 12], self.octave(middle_c_octave))
+    }
+
+    /// parse a note name, e.g. C4, C#4, Db4, or C-1, with middle C in middle_c_octave.
+    /// Sharps may be written # or s, flats b, and may be repeated.
+    pub fn parse(name: &str, middle_c_octave: i32) -> Result<NoteSym, String> {
+        let mut chars = name.trim().chars().peekable();
+        let pitch_class = match chars.next().map(|c| c.to_ascii_uppercase()) {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => return Err(format!("invalid note name: {}", name)),
+        };
+
+        let mut accidental = 0;
+        while let Some(c) = chars.peek() {
+            match c {
+                '#' | 's' | 'S' => accidental += 1,
+                'b' => accidental -= 1,
+                _ => break,
+            }
+            chars.next();
         }
+
+        let octave: i32 = chars.collect::<String>().parse()
+            .map_err(|_| format!("invalid octave in note name: {}", name))?;
+        let note = (octave - middle_c_octave + 5) * 12 + pitch_class + accidental;
+        if !(0..=127).contains(&note) {
+            return Err(format!("note out of MIDI range: {}", name));
+        }
+        Ok(NOTES[note as usize])
+    }
+}
+
+impl TryFrom<u8> for NoteSym {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Self::from_u8(value).ok_or_else(|| format!("not a MIDI note: {}", value))
+    }
+}
+
+impl From<NoteSym> for u8 {
+    fn from(note: NoteSym) -> u8 {
+        note as u8
+    }
+}
+
+/// parse a note name, with middle C as C4
+impl FromStr for NoteSym {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, MIDDLE_C_OCTAVE)
     }
 }
 
+/// note name, with middle C as C4, e.g. A#1
 impl fmt::Display for NoteSym {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            NoteSym::A0 =>  { write!(f, "A0") },
-            NoteSym::AS0 => { write!(f, "AS0") },
-            NoteSym::B0  => { write!(f, "B0") },
-
-            NoteSym::C1  => { write!(f, "C1") },
-            NoteSym::CS1 => { write!(f, "CS1") },
-            NoteSym::D1  => { write!(f, "D1") },
-            NoteSym::DS1 => { write!(f, "DS1") },
-            NoteSym::E1  => { write!(f, "E1") },
-            NoteSym::F1  => { write!(f, "F1") },
-            NoteSym::FS1 => { write!(f, "FS1") },
-            NoteSym::G1  => { write!(f, "G1") },
-            NoteSym::GS1 => { write!(f, "GS1") },
-            NoteSym::A1  => { write!(f, "A1") },
-            NoteSym::AS1 => { write!(f, "A1S") },
-            NoteSym::B1  => { write!(f, "B1") },
-
-            NoteSym::C2  => { write!(f, "C2") },
-            NoteSym::CS2 => { write!(f, "CS2") },
-            NoteSym::D2  => { write!(f, "D2") },
-            NoteSym::DS2 => { write!(f, "DS2") },
-            NoteSym::E2  => { write!(f, "E2") },
-            NoteSym::F2  => { write!(f, "F2") },
-            NoteSym::FS2 => { write!(f, "FS2") },
-            NoteSym::G2  => { write!(f, "G2") },
-            NoteSym::GS2 => { write!(f, "GS2") },
-            NoteSym::A2  => { write!(f, "A2") },
-            NoteSym::AS2 => { write!(f, "AS2") },
-            NoteSym::B2  => { write!(f, "B2") },
-
-            NoteSym::C3  => { write!(f, "C3") },
-            NoteSym::CS3 => { write!(f, "CS3") },
-            NoteSym::D3  => { write!(f, "D3") },
-            NoteSym::DS3 => { write!(f, "DS3") },
-            NoteSym::E3  => { write!(f, "E3") },
-            NoteSym::F3  => { write!(f, "F3") },
-            NoteSym::FS3 => { write!(f, "FS3") },
-            NoteSym::G3  => { write!(f, "G3") },
-            NoteSym::GS3 => { write!(f, "GS3") },
-            NoteSym::A3  => { write!(f, "A3") },
-            NoteSym::AS3 => { write!(f, "AS3") },
-            NoteSym::B3  => { write!(f, "B3") },
-
-            NoteSym::C4  => { write!(f, "C4") },
-            NoteSym::CS4 => { write!(f, "CS4") },
-            NoteSym::D4  => { write!(f, "D4") },
-            NoteSym::DS4 => { write!(f, "DS4") },
-            NoteSym::E4  => { write!(f, "E4") },
-            NoteSym::F4  => { write!(f, "F4") },
-            NoteSym::FS4 => { write!(f, "FS4") },
-            NoteSym::G4  => { write!(f, "G4") },
-            NoteSym::GS4 => { write!(f, "GS4") },
-            NoteSym::A4  => { write!(f, "A4") },
-            NoteSym::AS4 => { write!(f, "AS4") },
-            NoteSym::B4  => { write!(f, "B4") },
-
-            NoteSym::C5  => { write!(f, "C5") },
-            NoteSym::CS5 => { write!(f, "CS5") },
-            NoteSym::D5  => { write!(f, "D5") },
-            NoteSym::DS5 => { write!(f, "DS5") },
-            NoteSym::E5  => { write!(f, "E5") },
-            NoteSym::F5  => { write!(f, "F5") },
-            NoteSym::FS5 => { write!(f, "FS5") },
-            NoteSym::G5  => { write!(f, "G5") },
-            NoteSym::GS5 => { write!(f, "GS5") },
-            NoteSym::A5  => { write!(f, "A5") },
-            NoteSym::AS5 => { write!(f, "AS5") },
-            NoteSym::B5  => { write!(f, "B5") },
-
-            NoteSym::C6  => { write!(f, "C6") },
-            NoteSym::CS6 => { write!(f, "CS6") },
-            NoteSym::D6  => { write!(f, "D6") },
-            NoteSym::DS6 => { write!(f, "DS6") },
-            NoteSym::E6  => { write!(f, "E6") },
-            NoteSym::F6  => { write!(f, "F6") },
-            NoteSym::FS6 => { write!(f, "FS6") },
-            NoteSym::G6  => { write!(f, "G6") },
-            NoteSym::GS6 => { write!(f, "GS6") },
-            NoteSym::A6  => { write!(f, "A6") },
-            NoteSym::AS6 => { write!(f, "AS6") },
-            NoteSym::B6  => { write!(f, "B6") },
-
-            NoteSym::C7  => { write!(f, "C7") },
-            NoteSym::CS7 => { write!(f, "CS7") },
-            NoteSym::D7  => { write!(f, "D7") },
-            NoteSym::DS7 => { write!(f, "DS7") },
-            NoteSym::E7  => { write!(f, "E7") },
-            NoteSym::F7  => { write!(f, "F7") },
-            NoteSym::FS7 => { write!(f, "FS7") },
-            NoteSym::G7  => { write!(f, "G7") },
-            NoteSym::GS7 => { write!(f, "GS7") },
-            NoteSym::A7  => { write!(f, "A7") },
-            NoteSym::AS7 => { write!(f, "AS7") },
-            NoteSym::B7  => { write!(f, "B7") },
-
-            NoteSym::C8  => { write!(f, "C8") },
-
-            _ => { write!(f, "None") }
+        write!(f, "{}", self.name(MIDDLE_C_OCTAVE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // middle C conventions, Yamaha's, scientific pitch notation, and Roland's
+    const MIDDLE_C_OCTAVES: [i32; 3] = [3, 4, 5];
+
+    #[test]
+    fn from_u8_round_trips_every_note() {
+        for n in 0..=127u8 {
+            let note = NoteSym::from_u8(n).unwrap();
+            assert_eq!(note.to_u8(), n);
+            assert_eq!(u8::from(note), n);
+            assert_eq!(NoteSym::try_from(n), Ok(note));
+        }
+        for n in 128..=255u8 {
+            assert_eq!(NoteSym::from_u8(n), None);
+            assert!(NoteSym::try_from(n).is_err());
+        }
+    }
+
+    #[test]
+    fn name_parses_back_to_every_note() {
+        for middle_c in MIDDLE_C_OCTAVES.iter() {
+            for n in 0..=127u8 {
+                let note = NoteSym::from_u8(n).unwrap();
+                assert_eq!(NoteSym::parse(&note.name(*middle_c), *middle_c), Ok(note));
+            }
+        }
+    }
+
+    #[test]
+    fn display_and_from_str_round_trip_every_note() {
+        for n in 0..=127u8 {
+            let note = NoteSym::from_u8(n).unwrap();
+            assert_eq!(note.to_string().parse::<NoteSym>(), Ok(note));
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn middle_c_follows_convention() {
+        assert_eq!(NoteSym::C4.name(MIDDLE_C_OCTAVE), "C4");
+        assert_eq!(NoteSym::C4.name(3), "C3");
+        assert_eq!(NoteSym::C4.name(5), "C5");
+        assert_eq!(NoteSym::parse("C3", 3), Ok(NoteSym::C4));
+        assert_eq!(NoteSym::parse("C5", 5), Ok(NoteSym::C4));
+        assert_eq!(NoteSym::CN1.name(MIDDLE_C_OCTAVE), "C-1");
+        assert_eq!(NoteSym::CN1.name(3), "C-2");
+    }
+
+    #[test]
+    fn accidentals_are_equivalent() {
+        for middle_c in MIDDLE_C_OCTAVES.iter() {
+            let octave = middle_c;
+            let sharp = NoteSym::parse(&format!("C#{}", octave), *middle_c).unwrap();
+            assert_eq!(sharp, NoteSym::CS4);
+            assert_eq!(NoteSym::parse(&format!("Cs{}", octave), *middle_c), Ok(sharp));
+            assert_eq!(NoteSym::parse(&format!("Db{}", octave), *middle_c), Ok(sharp));
+            assert_eq!(NoteSym::parse(&format!("c##{}", octave), *middle_c), Ok(NoteSym::D4));
+            assert_eq!(NoteSym::parse(&format!("Cb{}", octave), *middle_c), Ok(NoteSym::B3));
+        }
+    }
+
+    #[test]
+    fn parse_rejects_notes_outside_midi_range() {
+        for middle_c in MIDDLE_C_OCTAVES.iter() {
+            let lowest = NoteSym::CN1.octave(*middle_c);
+            let highest = NoteSym::G9.octave(*middle_c);
+            assert_eq!(NoteSym::parse(&format!("C{}", lowest), *middle_c), Ok(NoteSym::CN1));
+            assert_eq!(NoteSym::parse(&format!("G{}", highest), *middle_c), Ok(NoteSym::G9));
+            assert!(NoteSym::parse(&format!("Cb{}", lowest), *middle_c).is_err());
+            assert!(NoteSym::parse(&format!("G#{}", highest), *middle_c).is_err());
+        }
+        assert!(NoteSym::parse("H4", MIDDLE_C_OCTAVE).is_err());
+        assert!(NoteSym::parse("C", MIDDLE_C_OCTAVE).is_err());
+        assert!(NoteSym::parse("", MIDDLE_C_OCTAVE).is_err());
+    }
+
+    #[test]
+    fn from_freq_inverts_to_freq_for_every_note() {
+        for n in 0..=127u8 {
+            let note = NoteSym::from_u8(n).unwrap();
+            let freq = note.to_freq();
+            assert_eq!(NoteSym::from_freq(freq), Some(note));
+            // nearest note, within a quarter tone either side
+            assert_eq!(NoteSym::from_freq(freq * 2.0f32.powf(0.4 / 12.0)), Some(note));
+            assert_eq!(NoteSym::from_freq(freq * 2.0f32.powf(-0.4 / 12.0)), Some(note));
+        }
+        assert_eq!(NoteSym::A4.to_freq(), 440.0);
+    }
+
+    #[test]
+    fn from_freq_rejects_frequencies_outside_midi_range() {
+        assert_eq!(NoteSym::from_freq(0.0), None);
+        assert_eq!(NoteSym::from_freq(-440.0), None);
+        assert_eq!(NoteSym::from_freq(f32::NAN), None);
+        assert_eq!(NoteSym::from_freq(f32::INFINITY), None);
+        assert_eq!(NoteSym::from_freq(NoteSym::CN1.to_freq() / 2.0), None);
+        assert_eq!(NoteSym::from_freq(NoteSym::G9.to_freq() * 2.0), None);
+    }
+}
@@ -0,0 +1,619 @@
+//!
+//! Record a module's output to WAV or FLAC
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crossbeam_channel as cb;
+use ringbuf::{RingBuffer, Producer, Consumer};
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, StreamInfo};
+use flacenc::source::{Fill, FrameBuf};
+use rimd::{SMF, SMFFormat, SMFWriter, Track, TrackEvent, Event, MetaEvent, MidiMessage};
+
+use crate::messages::*;
+use crate::comms::*;
+use crate::midi_file::param_text;
+
+/// samples buffered between audio and disk writer threads, about 3 seconds of stereo at 48kHz
+const RING_SIZE: usize = 1 << 18;
+/// commands queued from the audio thread to the disk writer, MIDI and parameter changes
+/// are dropped if it falls behind
+const COMMAND_QUEUE: usize = 4096;
+/// longest MIDI message recorded, longer messages, such as SysEx, are dropped
+const MIDI_MESSAGE_LEN: usize = 3;
+/// ticks per quarter note of recorded MIDI files
+const MIDI_DIVISION: i16 = 480;
+/// recorded MIDI files are at 120 BPM, in microseconds per quarter note
+const MIDI_TEMPO: u32 = 500_000;
+
+/// options for recording, from the command line
+#[derive(Clone, Debug)]
+pub struct RecordOptions {
+    /// file to start recording to as soon as audio starts
+    pub file: Option<String>,
+    /// bits per sample, 16, 24, or 32 (float, WAV only)
+    pub bits: u16,
+    /// MIDI CC that starts (value >= 64) and stops (value < 64) recording
+    pub cc: Option<u8>,
+    /// also record MIDI, to a Standard MIDI File next to each recording
+    pub midi: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Wav,
+    Flac,
+}
+
+impl Format {
+    fn from_path(path: &str) -> Self {
+        if path.to_lowercase().ends_with(".flac") { Format::Flac } else { Format::Wav }
+    }
+}
+
+/// commands carry the number of samples pushed to the ring, since the recorder was
+/// created, when they were sent. The ring is only drained up to that count, so samples
+/// are written to the recording they were pushed for.
+enum Command {
+    /// start recording, to a timestamped file if no path is given
+    Start {
+        path: Option<String>,
+        channels: usize,
+        sample_rate: u32,
+        pushed: u64,
+    },
+    /// samples have been pushed for the current recording
+    Written(u64),
+    Stop(u64),
+    Exit(u64),
+    /// MIDI message, its bytes and length, at a frame from the start of recording
+    Midi(u64, [u8; MIDI_MESSAGE_LEN], usize),
+    /// parameter change, at a frame from the start of recording, an integer or float
+    Param(u64, Index, Index, Value),
+}
+
+/// an open recording
+pub(crate) enum FileWriter {
+    Wav(hound::WavWriter<BufWriter<File>>, u16),
+    Flac(FlacWriter),
+}
+
+impl FileWriter {
+    pub(crate) fn create(path: &str, channels: usize, sample_rate: u32, bits: u16) -> Result<Self, String> {
+        match Format::from_path(path) {
+            Format::Wav => {
+                let spec = hound::WavSpec {
+                    channels: channels as u16,
+                    sample_rate,
+                    bits_per_sample: bits,
+                    sample_format:
+                        if bits == 32 { hound::SampleFormat::Float } else { hound::SampleFormat::Int },
+                };
+                hound::WavWriter::create(path, spec)
+                    .map(|w| FileWriter::Wav(w, bits))
+                    .map_err(|e| e.to_string())
+            },
+            // FLAC is integer only
+            Format::Flac => FlacWriter::create(path, channels, sample_rate, bits.min(24)).map(FileWriter::Flac),
+        }
+    }
+
+    #[inline]
+    fn to_int(s: f32, bits: u16) -> i32 {
+        let max = ((1 << (bits - 1)) - 1) as f32;
+        (s.max(-1.0).min(1.0) * max) as i32
+    }
+
+    pub(crate) fn write(&mut self, buffer: &[f32]) -> Result<(), String> {
+        match self {
+            FileWriter::Wav(writer, bits) => {
+                for s in buffer {
+                    let r = match bits {
+                        16 => writer.write_sample(Self::to_int(*s, 16) as i16),
+                        32 => writer.write_sample(*s),
+                        b => writer.write_sample(Self::to_int(*s, *b)),
+                    };
+                    r.map_err(|e| e.to_string())?;
+                }
+                Ok(())
+            },
+            FileWriter::Flac(writer) => writer.write(buffer),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Result<(), String> {
+        match self {
+            FileWriter::Wav(writer, _) => writer.finalize().map_err(|e| e.to_string()),
+            FileWriter::Flac(writer) => writer.finish(),
+        }
+    }
+}
+
+/// a FLAC recording, encoded a block at a time as samples arrive. The stream info
+/// header is written again, with the frame sizes and length, when recording stops.
+pub(crate) struct FlacWriter {
+    file: BufWriter<File>,
+    config: flacenc::config::Encoder,
+    info: StreamInfo,
+    /// interleaved samples of the block being filled
+    block: Vec<i32>,
+    frame_buf: FrameBuf,
+    frame_number: usize,
+    sink: ByteSink,
+    channels: usize,
+    bits: u16,
+}
+
+impl FlacWriter {
+    fn create(path: &str, channels: usize, sample_rate: u32, bits: u16) -> Result<Self, String> {
+        let config = flacenc::config::Encoder::default();
+        let block_size = config.block_sizes[0];
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path).map_err(|e| e.to_string())?),
+            config,
+            info: StreamInfo::new(sample_rate as usize, channels, bits as usize),
+            block: Vec::with_capacity(block_size * channels),
+            frame_buf: FrameBuf::with_size(channels, block_size),
+            frame_number: 0,
+            sink: ByteSink::new(),
+            channels,
+            bits,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    // the fLaC marker and stream info, the only metadata block
+    fn write_header(&mut self) -> Result<(), String> {
+        self.sink.clear();
+        self.info.write(&mut self.sink).map_err(|e| e.to_string())?;
+        let length = self.sink.as_slice().len();
+        self.file.write_all(b"fLaC").map_err(|e| e.to_string())?;
+        // last metadata block, of type STREAMINFO
+        self.file.write_all(&[0x80, (length >> 16) as u8, (length >> 8) as u8, length as u8])
+            .map_err(|e| e.to_string())?;
+        self.file.write_all(self.sink.as_slice()).map_err(|e| e.to_string())
+    }
+
+    fn write(&mut self, buffer: &[f32]) -> Result<(), String> {
+        let block_len = self.frame_buf.size() * self.channels;
+        for s in buffer {
+            self.block.push(FileWriter::to_int(*s, self.bits));
+            if self.block.len() == block_len {
+                self.write_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    // encode the samples in block as a frame, the last frame may be short
+    fn write_block(&mut self) -> Result<(), String> {
+        let frames = self.block.len() / self.channels;
+        if frames != self.frame_buf.size() {
+            self.frame_buf.resize(frames);
+        }
+        self.frame_buf.fill_interleaved(&self.block).map_err(|e| format!("{:?}", e))?;
+        self.block.clear();
+        let frame = flacenc::encode_fixed_size_frame(&self.config, &self.frame_buf, self.frame_number, &self.info)
+            .map_err(|e| format!("{:?}", e))?;
+        self.frame_number += 1;
+        self.info.update_frame_info(&frame);
+        self.sink.clear();
+        frame.write(&mut self.sink).map_err(|e| e.to_string())?;
+        self.file.write_all(self.sink.as_slice()).map_err(|e| e.to_string())
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        self.file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+        self.write_header()?;
+        self.file.flush().map_err(|e| e.to_string())
+    }
+}
+
+/// MIDI played during a recording, written as a Standard MIDI File when recording stops.
+/// Parameter changes are recorded, with their values unscaled, as text events of the
+/// form "param <node> <index> <value>", which the MIDI file player replays.
+struct MidiLog {
+    path: String,
+    sample_rate: f64,
+    /// (frame, event), in order of arrival
+    events: Vec<(u64, Event)>,
+}
+
+impl MidiLog {
+    fn new(path: &str, sample_rate: u32) -> Self {
+        Self {
+            path: Path::new(path).with_extension("mid").to_string_lossy().to_string(),
+            sample_rate: sample_rate as f64,
+            events: Vec::new(),
+        }
+    }
+
+    fn midi(&mut self, frame: u64, bytes: &[u8]) {
+        self.events.push((frame, Event::Midi(MidiMessage::from_bytes(bytes.to_vec()))));
+    }
+
+    fn param(&mut self, frame: u64, node: Index, index: Index, value: &Value) {
+        if let Some(text) = param_text(node, index, value) {
+            self.events.push((frame, Event::Meta(MetaEvent::text_event(text))));
+        }
+    }
+
+    fn write(mut self) -> Result<(), String> {
+        let ticks_per_frame = 
+            MIDI_DIVISION as f64 * 1.0e6 / MIDI_TEMPO as f64 / self.sample_rate;
+
+        let mut events = vec![TrackEvent { vtime: 0, event: Event::Meta(MetaEvent::tempo_setting(MIDI_TEMPO)) }];
+        // GUI and MIDI events may arrive slightly out of order, within a buffer
+        self.events.sort_by_key(|(frame, _)| *frame);
+        let mut last = 0;
+        for (frame, event) in self.events {
+            let tick = (frame as f64 * ticks_per_frame) as u64;
+            events.push(TrackEvent { vtime: tick - last, event });
+            last = tick;
+        }
+        events.push(TrackEvent { vtime: 0, event: Event::Meta(MetaEvent::end_of_track()) });
+
+        let smf = SMF {
+            format: SMFFormat::Single,
+            tracks: vec![Track { copyright: None, name: None, events }],
+            division: MIDI_DIVISION,
+        };
+        SMFWriter::from_smf(smf).write_to_file(Path::new(&self.path)).map_err(|e| format!("{:?}", e))
+    }
+}
+
+/// audio thread side of the recorder, copies output buffers into a lock-free ring,
+/// which is drained by a disk writer thread
+pub struct RecorderTap {
+    recording: Arc<AtomicBool>,
+    producer: Producer<f32>,
+    commands: cb::Sender<Command>,
+    writer: Option<thread::JoinHandle<()>>,
+    /// channels of the current recording
+    channels: usize,
+    /// frames written to the current recording, used to time MIDI
+    frames: u64,
+    /// samples pushed to the ring, since the recorder was created
+    pushed: u64,
+    options: RecordOptions,
+}
+
+impl RecorderTap {
+    #[inline]
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn options(&self) -> &RecordOptions {
+        &self.options
+    }
+
+    /// start recording to path, or to a timestamped file in the current directory, named
+    /// by the disk writer. Without channels only MIDI is recorded, if enabled.
+    pub fn start(&mut self, path: Option<String>, channels: usize, sample_rate: f64) {
+        if channels == 0 && !self.options.midi {
+            return;
+        }
+        if self.is_recording() {
+            self.stop();
+        }
+        self.channels = channels;
+        self.frames = 0;
+        let start = Command::Start { path, channels, sample_rate: sample_rate as u32, pushed: self.pushed };
+        if self.commands.try_send(start).is_ok() {
+            self.recording.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stop(&mut self) {
+        if self.is_recording() {
+            self.recording.store(false, Ordering::Relaxed);
+            let _ = self.commands.try_send(Command::Stop(self.pushed));
+        }
+    }
+
+    /// record a MIDI message, at offset within the buffer about to be written
+    pub fn midi(&mut self, offset: usize, message: &MidiMessage) {
+        let len = message.data.len();
+        if self.is_recording() && self.options.midi && len <= MIDI_MESSAGE_LEN {
+            let mut bytes = [0u8; MIDI_MESSAGE_LEN];
+            bytes[..len].copy_from_slice(&message.data);
+            let _ = self.commands.try_send(Command::Midi(self.frames + offset as u64, bytes, len));
+        }
+    }
+
+    /// record a parameter change, at offset within the buffer about to be written
+    pub fn param(&mut self, offset: usize, node: Index, index: Index, value: &Value) {
+        if self.is_recording() && self.options.midi {
+            // copied without allocating
+            let value = match value {
+                Value::VFloat(v) => Value::VFloat(*v),
+                Value::VInt(v) => Value::VInt(*v),
+                _ => return,
+            };
+            let _ = self.commands.try_send(Command::Param(self.frames + offset as u64, node, index, value));
+        }
+    }
+
+    /// write an interleaved buffer, with channels, which is converted to the number of
+    /// channels being recorded. Samples are dropped if the disk writer falls behind.
+    pub fn write(&mut self, buffer: &[f32], channels: usize, frames: usize) {
+        if !self.is_recording() {
+            return;
+        }
+        // counted when only MIDI is recorded, to time it
+        self.frames += frames as u64;
+        if channels == 0 || self.channels == 0 {
+            return;
+        }
+        let pushed = if channels == self.channels {
+            self.producer.push_slice(&buffer[..frames * channels])
+        }
+        else {
+            let mut pushed = 0;
+            for frame in buffer.chunks(channels).take(frames) {
+                for c in 0..self.channels {
+                    pushed += self.producer.push(frame[c % channels]).map_or(0, |_| 1);
+                }
+            }
+            pushed
+        };
+        self.pushed += pushed as u64;
+        // if the queue is full, a later count includes these samples
+        let _ = self.commands.try_send(Command::Written(self.pushed));
+    }
+}
+
+impl Drop for RecorderTap {
+    // wait for any recording to be written, before the application exits
+    fn drop(&mut self) {
+        self.stop();
+        let _ = self.commands.send(Command::Exit(self.pushed));
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// a timestamped file in the current directory, for recordings started without one
+pub fn recording_path() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    format!("aa_recording_{}.wav", secs)
+}
+
+// send recording state to GUI, path is empty when recording has stopped
+fn send_recording(send_to_gui: &cb::Sender<Message>, path: &str) {
+    let _ = send_to_gui.send(Message {
+        id: MessageID::Recording,
+        node: 0,
+        index: 0,
+        value: Value::VString(path.to_string()),
+    });
+}
+
+// drain the ring up to pushed samples, counting popped samples, into writer, or discard
+// them if there is none
+fn drain(
+    consumer: &mut Consumer<f32>,
+    buffer: &mut Vec<f32>,
+    popped: &mut u64,
+    pushed: u64,
+    writer: &mut Option<FileWriter>) {
+
+    while *popped < pushed {
+        let len = ((pushed - *popped) as usize).min(buffer.len());
+        let n = consumer.pop_slice(&mut buffer[..len]);
+        if n == 0 {
+            break;
+        }
+        *popped += n as u64;
+        if let Some(w) = writer {
+            if let Err(e) = w.write(&buffer[..n]) {
+                eprintln!("Failed to write recording {}", e);
+            }
+        }
+    }
+}
+
+fn finish(writer: &mut Option<FileWriter>, midi: &mut Option<MidiLog>, send_to_gui: &cb::Sender<Message>) {
+    let recording = writer.is_some() || midi.is_some();
+    if let Some(log) = midi.take() {
+        let path = log.path.clone();
+        if let Err(e) = log.write() {
+            eprintln!("Failed to write MIDI recording {} {}", path, e);
+        }
+    }
+    if let Some(w) = writer.take() {
+        if let Err(e) = w.finish() {
+            eprintln!("Failed to finish recording {}", e);
+        }
+    }
+    if recording {
+        send_recording(send_to_gui, "");
+    }
+}
+
+/// create a recorder, returning the tap for the audio thread. Recordings are written
+/// to disk from a separate thread.
+pub fn spawn_recorder(options: RecordOptions, send_to_gui: cb::Sender<Message>) -> RecorderTap {
+    let recording = Arc::new(AtomicBool::new(false));
+    let (producer, mut consumer) = RingBuffer::<f32>::new(RING_SIZE).split();
+    let (commands, receive_commands) = cb::bounded(COMMAND_QUEUE);
+    let bits = options.bits;
+    let record_midi = options.midi;
+
+    let writer_thread = thread::spawn(move || {
+        let mut writer: Option<FileWriter> = None;
+        let mut midi: Option<MidiLog> = None;
+        let mut buffer = vec![0.0f32; RING_SIZE / 4];
+        let mut popped = 0;
+
+        loop {
+            match receive_commands.recv() {
+                Ok(Command::Start { path, channels, sample_rate, pushed }) => {
+                    let path = path.unwrap_or_else(recording_path);
+                    // samples before the start belong to any previous recording
+                    drain(&mut consumer, &mut buffer, &mut popped, pushed, &mut writer);
+                    finish(&mut writer, &mut midi, &send_to_gui);
+                    // without outputs only MIDI is recorded
+                    let created = if channels > 0 {
+                        FileWriter::create(&path, channels, sample_rate, bits).map(|w| writer = Some(w))
+                    }
+                    else {
+                        Ok(())
+                    };
+                    match created {
+                        Ok(()) if record_midi => {
+                            let log = MidiLog::new(&path, sample_rate);
+                            send_recording(&send_to_gui, if writer.is_some() { &path } else { &log.path });
+                            midi = Some(log);
+                        },
+                        Ok(()) => send_recording(&send_to_gui, &path),
+                        Err(e) => eprintln!("Failed to create recording {} {}", path, e),
+                    }
+                },
+                Ok(Command::Written(pushed)) => {
+                    drain(&mut consumer, &mut buffer, &mut popped, pushed, &mut writer);
+                },
+                Ok(Command::Stop(pushed)) => {
+                    drain(&mut consumer, &mut buffer, &mut popped, pushed, &mut writer);
+                    finish(&mut writer, &mut midi, &send_to_gui);
+                },
+                Ok(Command::Midi(frame, bytes, len)) => {
+                    if let Some(log) = midi.as_mut() {
+                        log.midi(frame, &bytes[..len]);
+                    }
+                },
+                Ok(Command::Param(frame, node, index, value)) => {
+                    if let Some(log) = midi.as_mut() {
+                        log.param(frame, node, index, &value);
+                    }
+                },
+                Ok(Command::Exit(pushed)) => {
+                    drain(&mut consumer, &mut buffer, &mut popped, pushed, &mut writer);
+                    finish(&mut writer, &mut midi, &send_to_gui);
+                    break;
+                },
+                Err(_) => {
+                    finish(&mut writer, &mut midi, &send_to_gui);
+                    break;
+                },
+            }
+        }
+    });
+
+    RecorderTap {
+        recording,
+        producer,
+        commands,
+        writer: Some(writer_thread),
+        channels: 0,
+        frames: 0,
+        pushed: 0,
+        options,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("aa_recorder_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn options(midi: bool) -> RecordOptions {
+        RecordOptions { file: None, bits: 16, cc: None, midi }
+    }
+
+    #[test]
+    fn restarting_writes_samples_to_their_own_recording() {
+        let (first, second) = (temp_path("first.wav"), temp_path("second.wav"));
+        let (send_to_gui, _receive) = cb::unbounded();
+        let mut tap = spawn_recorder(options(false), send_to_gui);
+        tap.start(Some(first.clone()), 2, 48_000.0);
+        tap.write(&[0.5; 200], 2, 100);
+        // restarted before the writer has drained the first recording
+        tap.start(Some(second.clone()), 1, 48_000.0);
+        tap.write(&[0.5; 100], 2, 50);
+        drop(tap);
+
+        assert_eq!(hound::WavReader::open(&first).unwrap().len(), 200);
+        let reader = hound::WavReader::open(&second).unwrap();
+        assert_eq!(reader.spec().channels, 1);
+        assert_eq!(reader.len(), 50);
+        let _ = std::fs::remove_file(first);
+        let _ = std::fs::remove_file(second);
+    }
+
+    #[test]
+    fn midi_is_recorded_with_the_audio() {
+        let path = temp_path("midi.wav");
+        let (send_to_gui, _receive) = cb::unbounded();
+        let mut tap = spawn_recorder(options(true), send_to_gui);
+        tap.start(Some(path.clone()), 1, 48_000.0);
+        tap.write(&[0.0; 24_000], 1, 24_000);
+        tap.midi(0, &MidiMessage::from_bytes(vec![0x90, 60, 100]));
+        // too long to record
+        tap.midi(0, &MidiMessage::from_bytes(vec![0xF0, 1, 2, 3, 0xF7]));
+        drop(tap);
+
+        let log = Path::new(&path).with_extension("mid");
+        let smf = SMF::from_file(&log).unwrap();
+        let notes: Vec<(u64, Vec<u8>)> = smf.tracks[0].events.iter()
+            .filter_map(|e| match &e.event {
+                Event::Midi(message) => Some((e.vtime, message.data.clone())),
+                _ => None,
+            })
+            .collect();
+        // half a second at 120 BPM is a quarter note
+        assert_eq!(notes, vec![(MIDI_DIVISION as u64, vec![0x90, 60, 100])]);
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(log);
+    }
+
+    #[test]
+    fn params_are_replayed_from_the_recording() {
+        let path = temp_path("params.wav");
+        let (send_to_gui, _receive) = cb::unbounded();
+        let mut tap = spawn_recorder(options(true), send_to_gui);
+        tap.start(Some(path.clone()), 0, 48_000.0);
+        tap.param(0, 1, 2, &Value::VFloat(0.25));
+        tap.write(&[], 0, 24_000);
+        tap.param(0, 3, 4, &Value::VInt(5));
+        tap.param(0, 3, 4, &Value::VString("ignored".to_string()));
+        drop(tap);
+
+        let log = Path::new(&path).with_extension("mid");
+        let mut player = crate::midi_file::MidiFilePlayer::load(&log.to_string_lossy()).unwrap();
+        player.play();
+        let mut params = Vec::new();
+        player.next_block(48_000, 48_000.0, |offset, event| {
+            if let crate::midi_file::FileEvent::Param(node, index, value) = event {
+                params.push((offset, *node, *index, value.to_string(), matches!(value, Value::VInt(_))));
+            }
+        });
+        assert_eq!(params, vec![
+            (0, 1, 2, "0.25".to_string(), false),
+            (24_000, 3, 4, "5".to_string(), true),
+        ]);
+        let _ = std::fs::remove_file(log);
+    }
+}
@@ -0,0 +1,135 @@
+//!
+//! Offline rendering of a module, driven by a MIDI file, to an audio file
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::mpsc::channel;
+
+use crossbeam_channel as cb;
+
+use crate::utils::*;
+use crate::bundle::*;
+use crate::engine::*;
+use crate::recorder::{FileWriter, RecordOptions};
+use crate::midi_effects::MidiEffectsOptions;
+use crate::clock::{ClockOptions, ClockSource};
+use crate::standalone::*;
+use crate::trust::Trust;
+
+/// frames computed at a time
+const BLOCK_SIZE: usize = 64;
+/// seconds rendered after the last MIDI event, so that notes can decay
+const TAIL: f64 = 2.0;
+
+/// options for an offline render
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    /// url of AA server
+    pub url: String,
+    /// module bundle json, relative to url, or the server's default if None
+    pub module: Option<String>,
+    pub midi_file: String,
+    pub tempo_scale: f64,
+    /// .wav or .flac file to write
+    pub output: String,
+    pub bits: u16,
+    pub sample_rate: f64,
+    /// keys the module must be signed with
+    pub trust: Trust,
+}
+
+/// render a module, playing a MIDI file, as fast as possible to an audio file
+pub fn render(options: &RenderOptions) -> Result<()> {
+    let json = match &options.module {
+        Some(json) => json.clone(),
+        None => {
+            let modules = get_string(&[&options.url[..], "modules.json"].join("/"))?;
+            Modules::from_json(&modules)?.default
+        }
+    };
+
+    let (mut aaunit, bundle) = Standalone::create_aaunit(&options.url, &json, &options.trust)?;
+    let _ = aaunit.init(options.sample_rate);
+    Standalone::set_params(&aaunit, &bundle.gui.params);
+
+    let inputs = bundle.info.inputs.max(0) as usize;
+    let outputs = bundle.info.outputs.max(0) as usize;
+    if outputs == 0 {
+        eprintln!("Module {} has no outputs to render", bundle.info.name);
+        return err();
+    }
+
+    // the host plays the MIDI file, nothing is sent to the GUI or analyser
+    let (send_from_audio, _) = cb::bounded(1);
+    let (send_to_gui, _) = cb::bounded(1);
+    let (_send_from_gui, receive_from_gui) = cb::unbounded();
+    let (_send_from_midi, receive_from_midi) = cb::unbounded();
+    let (send_stop, _receive_stop) = channel();
+    let host = Rc::new(RefCell::new(Host::new(send_from_audio.clone(), send_to_gui, host_options(options))));
+    if host.borrow().midi_player.is_none() {
+        return err();
+    }
+    host.borrow_mut().start_stream(options.sample_rate, outputs as i32, 0.0);
+    let mut engine = Engine::new(
+        Rc::new(RefCell::new(aaunit)),
+        host.clone(),
+        &bundle,
+        receive_from_gui,
+        receive_from_midi,
+        send_from_audio,
+        send_stop);
+
+    let mut writer = FileWriter::create(&options.output, outputs, options.sample_rate as u32, options.bits)
+        .map_err(|e| eprintln!("{}", e))?;
+
+    // modules with inputs are given silence
+    let in_buffer = vec![0.0f32; BLOCK_SIZE * inputs];
+    let mut out_buffer = vec![0.0f32; BLOCK_SIZE * outputs];
+    let mut tail = (TAIL * options.sample_rate) as usize;
+
+    while tail > 0 {
+        if host.borrow().midi_player.as_ref().map_or(true, |player| player.finished()) {
+            tail = tail.saturating_sub(BLOCK_SIZE);
+        }
+
+        engine.schedule_midi_file(BLOCK_SIZE);
+        engine.apply_midi_effects(BLOCK_SIZE);
+        engine.set_host_params();
+        engine.compute_events(BLOCK_SIZE, &in_buffer, &mut out_buffer);
+        engine.advance(BLOCK_SIZE);
+
+        writer.write(&out_buffer[..]).map_err(|e| eprintln!("{}", e))?;
+    }
+
+    writer.finish().map_err(|e| eprintln!("{}", e))
+}
+
+// host options for a render, playing the MIDI file once, with no recording or effects
+fn host_options(options: &RenderOptions) -> HostOptions {
+    HostOptions {
+        record: RecordOptions {
+            file: None,
+            bits: options.bits,
+            cc: None,
+            midi: false,
+        },
+        input_files: Vec::new(),
+        midi_file: Some(MidiFileOptions {
+            file: options.midi_file.clone(),
+            looping: false,
+            tempo_scale: options.tempo_scale,
+        }),
+        voices: None,
+        tuning: None,
+        effects: MidiEffectsOptions::default(),
+        clock: ClockOptions {
+            tempo: 120.0,
+            beats_per_bar: 4,
+            source: ClockSource::Internal,
+            midi_out: None,
+        },
+    }
+}
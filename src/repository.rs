@@ -0,0 +1,191 @@
+//!
+//! Module repositories, each an AA server's modules.json, whose modules are merged into
+//! a single list for the GUI
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::bundle::*;
+use crate::utils::get_string;
+
+/// a module repository, with a name to show in the GUI
+#[derive(Deserialize, Clone, Debug)]
+pub struct Repository {
+    pub name: String,
+    pub url: String,
+}
+
+impl Repository {
+    /// a repository from the command line, either name=url, or url, named by its host
+    pub fn parse(repository: &str) -> Self {
+        match repository.find('=') {
+            Some(i) if !repository[..i].contains("://") => Self {
+                name: repository[..i].to_string(),
+                url: repository[i + 1..].trim_end_matches('/').to_string(),
+            },
+            _ => Self {
+                name: Self::host(repository).to_string(),
+                url: repository.trim_end_matches('/').to_string(),
+            },
+        }
+    }
+
+    fn host(url: &str) -> &str {
+        let host = url.splitn(2, "://").nth(1).unwrap_or(url);
+        host.split('/').next().unwrap_or(host)
+    }
+}
+
+/// the default repositories file, ~/.config/aa_standalone/repositories.json
+pub fn default_repositories_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".config/aa_standalone/repositories.json"))
+}
+
+/// load repositories, a JSON array of { "name": ..., "url": ... }. A missing default file is
+/// not an error, there are just no additional repositories.
+pub fn load_repositories(file: Option<&str>) -> Result<Vec<Repository>, String> {
+    let (path, required) = match file {
+        Some(file) => (Some(PathBuf::from(file)), true),
+        None => (default_repositories_file(), false),
+    };
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(Vec::new()),
+    };
+    match fs::read_to_string(&path) {
+        Ok(data) => {
+            let repositories: Vec<Repository> = serde_json::from_str(&data)
+                .map_err(|e| format!("{} {}", path.display(), e))?;
+            Ok(repositories.into_iter()
+                .map(|r| Repository { url: r.url.trim_end_matches('/').to_string(), ..r })
+                .collect())
+        },
+        Err(e) if required => Err(format!("{} {}", path.display(), e)),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+/// a module, from one of the repositories
+#[derive(Clone, Debug)]
+pub struct ModuleEntry {
+    /// index of the module's repository
+    pub repository: usize,
+    /// name shown in the GUI, unique across all repositories
+    pub name: String,
+    /// bundle json, relative to the repository's URL
+    pub json_url: String,
+    /// the bundle's Info.category, empty if it could not be fetched
+    pub category: String,
+    /// the bundle's Info, None if it could not be fetched
+    pub info: Option<Info>,
+    pub metadata: Metadata,
+}
+
+/// the modules of all repositories, ordered by repository, then category, then name
+#[derive(Clone, Debug)]
+pub struct ModuleList {
+    pub repositories: Vec<Repository>,
+    pub modules: Vec<ModuleEntry>,
+    /// (repository url, bundle json) of the module loaded at start up, the first
+    /// repository's default
+    pub default: (String, String),
+}
+
+impl ModuleList {
+    /// fetch each repository's modules.json, and each module's bundle, for its info.
+    /// Repositories that can not be fetched are skipped, but at least one is needed.
+    pub fn load(repositories: &[Repository]) -> Result<Self, String> {
+        let mut default = None;
+        let mut modules = Vec::new();
+        for (r, repository) in repositories.iter().enumerate() {
+            let list = get_string(&[&repository.url[..], "modules.json"].join("/"))
+                .and_then(|json| Modules::from_json(&json));
+            let list = match list {
+                Ok(list) => list,
+                Err(_) => {
+                    eprintln!("Failed to load modules from repository {} ({})", repository.name, repository.url);
+                    continue;
+                }
+            };
+            if default.is_none() {
+                default = Some((repository.url.clone(), list.default.clone()));
+            }
+
+            for module in list.modules.iter() {
+                // a module listed more than once is only added once
+                if modules.iter().any(|m: &ModuleEntry| m.repository == r && m.json_url == module.json_url) {
+                    continue;
+                }
+                let bundle = get_string(&[&repository.url[..], &module.json_url[..]].join("/"))
+                    .ok()
+                    .and_then(|json| Bundle::parse(&json).ok());
+                modules.push(ModuleEntry {
+                    repository: r,
+                    name: module.name.clone(),
+                    json_url: module.json_url.clone(),
+                    category: bundle.as_ref().map_or(String::new(), |bundle| bundle.info.category.clone()),
+                    info: bundle.as_ref().map(|bundle| bundle.info.clone()),
+                    metadata: bundle.map_or(Metadata::default(), |bundle| bundle.metadata),
+                });
+            }
+        }
+
+        let default = default.ok_or_else(|| "no module repository could be loaded".to_string())?;
+        modules.sort_by(|a, b| {
+            (a.repository, &a.category, &a.name).cmp(&(b.repository, &b.category, &b.name))
+        });
+        let mut list = Self { repositories: repositories.to_vec(), modules, default };
+        list.resolve_duplicates();
+        Ok(list)
+    }
+
+    // modules with the same name, in different repositories, are named with their
+    // repository, and any that are still the same numbered
+    fn resolve_duplicates(&mut self) {
+        let names: Vec<String> = self.modules.iter().map(|m| m.name.clone()).collect();
+        for (i, module) in self.modules.iter_mut().enumerate() {
+            let shared = names.iter().enumerate().any(|(j, name)| j != i && *name == module.name);
+            if shared && self.repositories.len() > 1 {
+                module.name = format!("{} ({})", module.name, self.repositories[module.repository].name);
+            }
+        }
+        let names: Vec<String> = self.modules.iter().map(|m| m.name.clone()).collect();
+        for (i, module) in self.modules.iter_mut().enumerate() {
+            let count = names[..i].iter().filter(|name| **name == module.name).count();
+            if count > 0 {
+                module.name = format!("{} {}", module.name, count + 1);
+            }
+        }
+    }
+
+    /// label shown in the GUI's module list, grouped by repository, if there is more
+    /// than one, and category
+    pub fn label(&self, module: &ModuleEntry) -> String {
+        let mut label = Vec::new();
+        if self.repositories.len() > 1 {
+            label.push(&self.repositories[module.repository].name[..]);
+        }
+        if !module.category.is_empty() {
+            label.push(&module.category[..]);
+        }
+        label.push(&module.name[..]);
+        label.join(" / ")
+    }
+
+    /// key the GUI uses to select a module, the absolute URL of its bundle
+    pub fn key(&self, module: &ModuleEntry) -> String {
+        [&self.repositories[module.repository].url[..], &module.json_url[..]].join("/")
+    }
+
+    /// (repository url, bundle json) of a module selected in the GUI, by key
+    pub fn resolve(&self, key: &str) -> Option<(String, String)> {
+        self.modules.iter()
+            .find(|m| self.key(m) == key)
+            .map(|m| (self.repositories[m.repository].url.clone(), m.json_url.clone()))
+    }
+}
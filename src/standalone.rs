@@ -4,7 +4,7 @@
 //! 
 #![allow(dead_code)]
 
-use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::channel;
 use std::thread;
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -18,33 +18,48 @@ use crate::messages::*;
 use crate::comms::*;
 use crate::utils::*;
 use crate::bundle::*;
-
-extern crate portaudio;
-use portaudio as pa;
+use crate::driver::*;
+use crate::engine::*;
+use crate::audio_backend::*;
+use crate::file_player::*;
+use crate::voices::*;
+use crate::trust::*;
+use crate::repository::*;
+use crate::catalogue::*;
+use crate::loader::*;
+use crate::cache;
 
 use crate::midi_device::*;
-use rimd::{MidiMessage, Status};
 
 /// Wasmtime based Standalone Audio Anytime Application
 pub struct Standalone<'a> {
-    /// url used for interface, modules, and the like
-    url: String, 
     /// default json
     json: String,
     /// input/output midi
     midi: Option<Midi>,
-    send_from_midi: cb::Sender<MidiMessage>,
-    receive_from_midi: cb::Receiver<MidiMessage>,
+    send_from_midi: cb::Sender<MidiEvent>,
+    receive_from_midi: cb::Receiver<MidiEvent>,
     /// currently selected audio input device
-    input_device: pa::DeviceIndex,
+    input_device: Option<u32>,
     /// currenlty selected audio outut device
-    output_device: pa::DeviceIndex,
+    output_device: Option<u32>,
+    /// audio driver used to run modules
+    driver: AudioDriver,
+    /// options for host state, e.g. recording
+    host_options: HostOptions,
+    /// keys modules must be signed with
+    trust: Trust,
+    /// modules of all repositories
+    modules: ModuleList,
+    /// loads modules selected in GUI, started by run
+    loader: Loader,
+    loads: LoadSender,
     /// GUI, only one instance for application, modules are injected iframe
     gui: GUI<'a>,
     /// incomming messages from GUI
     receive_from_gui: cb::Receiver<Message>,
-    /// send from audio
-    send_from_audio: Sender<(u32, Value)>,
+    /// send from audio, values and levels to GUI
+    send_from_audio: cb::Sender<Message>,
     /// send from gui
     send_from_gui: cb::Sender<Message>,
     /// gui comms, for sending messages to GUI
@@ -53,33 +68,44 @@ pub struct Standalone<'a> {
 }
 
 impl <'a>Standalone<'a> {
-    pub fn new(url: &str, midi_device: Option<String>) -> Result<Self> {
+    pub fn new(
+        repositories: &[Repository], 
+        midi_device: Option<String>, 
+        driver: AudioDriver, 
+        host_options: HostOptions,
+        trust: Trust) -> Result<Self> {
        
-        // Form GUI HTML, index.html is the same for all anywhere modules
-        let html = &[url, "index.html"].join("/");
+        // Form GUI HTML, index.html is the same for all anywhere modules, so is taken from
+        // the first repository, through the cache if enabled
+        let html = &cache::local_url(&[&repositories[0].url[..], "index.html"].join("/"));
 
-        let modules = get_string(&[url, "modules.json"].join("/")).unwrap();
-        Modules::from_json(&modules).and_then(|modules| {
+        ModuleList::load(repositories).map_err(|e| eprintln!("{}", e)).and_then(|modules| {
             // thread communication channels
             let (send_from_midi, receive_from_midi) = cb::unbounded();
             let (send_from_gui, receive_from_gui) = cb::unbounded();
-            let (send_from_audio, receive_from_audio) = channel();
+            let (send_from_audio, receive_from_audio) = cb::bounded(AUDIO_QUEUE_SIZE);
 
-            // default module to be loaded on startup
-            let json = &modules.default.clone();
+            // default module to be loaded on startup, only its bundle is needed for GUI, the
+            // module is fetched by the loader, once running
+            let (url, json) = &modules.default.clone();
+            // module changes from GUI go to the loader
+            let (loader, loads) = Loader::new();
 
-            Self::create_aaunit(url, json, send_from_audio.clone()).and_then(|(aaunit, bundle)| {
+            get_string(&[&url[..], &json[..]].join("/")).and_then(|data| Bundle::from_json(&data)).and_then(|bundle| {
 
                 GUI::new(
                     &html[..],
-                    Box::new(LocalSendCB::new(send_from_gui.clone())),
+                    Box::new(LoaderSend::new(send_from_gui.clone(), loads.clone())),
                     bundle.gui.params.clone(), //vec![Value::VFloat(-50.)],
+                    Catalogue::new(&modules),
                     "Audio Anywhere",
                     //(900,900)).and_then(|gui| {
-                    (1600,1000)).and_then(|gui| {
-                        let pa = pa::PortAudio::new().unwrap();
-                        let input_device = pa.default_input_device().unwrap();
-                        let output_device = pa.default_output_device().unwrap();
+                    (1600,1000)).and_then(|mut gui| {
+                        gui.set_audio_receiver(receive_from_audio);
+
+                        let backend = Self::create_backend(&driver)?;
+                        let input_device = backend.default_input_device();
+                        let output_device = backend.default_output_device();
 
                         let comms_sender = gui.comms_sender();
                         let comms = gui.comms();
@@ -100,21 +126,27 @@ impl <'a>Standalone<'a> {
                             };
                         
                         // send Modules to GUI
-                        Self::send_modules(&comms_sender, &modules.modules);
-                        // send Audio devices to GUI
-                        Self::send_audio_devices(&comms_sender);
-                        // set default values for GUI and AAUnit
+                        Self::send_modules(&comms_sender, &modules);
+                        // send Audio devices to GUI, JACK handles routing itself
+                        if !driver.is_jack() {
+                            Self::send_audio_devices(&comms_sender, backend.as_ref(), &host_options.input_files);
+                        }
+                        // set default values for GUI, the AAUnit's are set once loaded
                         Self::send_params(&comms_sender, &bundle.gui.params);
-                        Self::set_params(&aaunit, &bundle.gui.params);
                         
                         Ok(Self {
-                            url: url.to_string(),
                             json: json.to_string(),
                             midi,
                             send_from_midi,
                             receive_from_midi,
                             input_device,
                             output_device,
+                            driver,
+                            host_options,
+                            trust,
+                            modules,
+                            loader,
+                            loads,
                             gui,
                             receive_from_gui,
                             send_from_audio,
@@ -128,19 +160,21 @@ impl <'a>Standalone<'a> {
     }
 
     // send a list of input/output audio devices to GUI
-    fn send_audio_devices(comms: &cb::Sender<Message>) {
-        let pa = pa::PortAudio::new().unwrap();
-        for device in pa.devices().unwrap() {
-            let (index, info) = device.unwrap();
-        
-            if info.max_input_channels > 0 {
-                Self::send_add_input_device(&comms, info.name, index);
+    // audio files are sent as pseudo input devices
+    fn send_audio_devices(comms: &cb::Sender<Message>, backend: &dyn AudioBackend, input_files: &Vec<String>) {
+        for info in backend.devices().unwrap_or_default() {
+            if info.max_inputs > 0 {
+                Self::send_add_input_device(&comms, &info.name, info.index);
             }
 
-            if info.max_output_channels > 0 {
-                Self::send_add_output_device(&comms, info.name, index);
+            if info.max_outputs > 0 {
+                Self::send_add_output_device(&comms, &info.name, info.index);
             }
         }
+
+        for (i, path) in input_files.iter().enumerate() {
+            Self::send_add_input_device(&comms, &FilePlayer::display_name(path), FILE_DEVICE_BASE + i as u32);
+        }
     }
 
     // send a list of params settings, indexed by position in the vector, to GUI
@@ -157,11 +191,19 @@ impl <'a>Standalone<'a> {
         }
     }
 
-    // send a list of modules to GUI
-    fn send_modules(comms: &cb::Sender<Message>, modules: &Vec<Module>) {
-        for m in modules {
-            Self::send_add_module(comms, &m.name, &m.json_url);
+    // send a list of modules, from all repositories, to GUI, grouped by repository and
+    // category
+    fn send_modules(comms: &cb::Sender<Message>, modules: &ModuleList) {
+        for m in modules.modules.iter() {
+            Self::send_add_module(comms, &modules.label(m), &modules.key(m));
         }
+        // and their info, for the GUI to browse
+        comms.send(Message {
+            id: MessageID::Catalogue,
+            node: 0,
+            index: 0,
+            value: Value::VString(Catalogue::new(modules).to_json()),
+        }).unwrap();
     }
 
     // send a message to GUI to add a module to drop down menu
@@ -175,425 +217,362 @@ impl <'a>Standalone<'a> {
     }
 
     // send a message to GUI to add an input audio device
-    fn send_add_input_device(comms: &cb::Sender<Message>, name: &str, index: pa::DeviceIndex) {
+    fn send_add_input_device(comms: &cb::Sender<Message>, name: &str, index: u32) {
         comms.send(Message {
             id: MessageID::AddInputDevice,
             node: 0,
             index: 0,
-            value: Value::VString([name, &index.0.to_string()].join("="))
+            value: Value::VString([name, &index.to_string()].join("="))
         }).unwrap();
     }
 
     // send a message to GUI to add an output audio device
-    fn send_add_output_device(comms: &cb::Sender<Message>, name: &str, index: pa::DeviceIndex) {
+    fn send_add_output_device(comms: &cb::Sender<Message>, name: &str, index: u32) {
         comms.send(Message {
             id: MessageID::AddOutputDevice,
             node: 0,
             index: 0,
-            value: Value::VString([name, &index.0.to_string()].join("="))
+            value: Value::VString([name, &index.to_string()].join("="))
         }).unwrap();
     }
 
-    /// create an instance of an aaunit
-    fn create_aaunit(url: &str, json: &str, send_from_audio: Sender<(u32, Value)>) -> Result<(AAUnit, Bundle)> {
+    // fetch a bundle's wasm files, checking each against its hash, calling progress with
+    // each file's URL, bytes received, and total, which cancels the fetch if it returns false
+    fn fetch_wasm(url: &str, bundle: &Bundle, progress: &mut dyn FnMut(&str, u64, u64) -> bool) -> Result<Vec<Vec<u8>>> {
+        let mut wasm_bytes = Vec::new();
+        for (i, wasm_url) in bundle.wasm_url.iter().enumerate() {
+            let wasm_url = [url, &wasm_url].join("");
+            let bytes = get_vec_with_progress(&wasm_url, &mut |loaded, total| progress(&wasm_url, loaded, total))?;
+            verify_wasm(bundle, i, &bytes).map_err(|e| eprintln!("{}", e))?;
+            wasm_bytes.push(bytes);
+        }
+        ok(wasm_bytes)
+    }
+
+    /// fetch a module's bundle, if it is trusted, and its wasm, calling progress as its
+    /// wasm is fetched, see fetch_wasm
+    pub(crate) fn fetch_module(
+        url: &str, 
+        json: &str, 
+        trust: &Trust, 
+        progress: &mut dyn FnMut(&str, u64, u64) -> bool) -> Result<(Bundle, Vec<Vec<u8>>)> {
         // firstly load the json bundle
         get_string(&[url, json].join("/")).and_then(|json| {
             Bundle::from_json(&json).and_then(|bundle| {
-                let mut wasm_bytes = Vec::new();
+                trust.verify_bundle(&json, &bundle).map_err(|e| eprintln!("{}", e))?;
                 // fetch wasm files
-                for wasm_url in bundle.wasm_url.iter() {
-                    wasm_bytes.push(get_vec(&[url, &wasm_url].join(""))?);
-                }
-                // create module
-                // if let Ok(aaunit) = AAUnit::new(wasm_bytes) {
-                //     Ok((aaunit, bundle))
-                // }
-                // else {
-                //     println!("failed to create aaunit");
-                //     Err(())
-                // }
-                match AAUnit::new(wasm_bytes) {
-                    Ok(aaunit) => {
-                        Ok((aaunit, bundle))
-                    },
-                    Err(e) => {
-                        eprintln!("Failed to create aaunit {:?}", e);
-                        Err(())
-                    }
-                }
+                let wasm_bytes = Self::fetch_wasm(url, &bundle, progress)?;
+                Ok((bundle, wasm_bytes))
             })
         })
     }
 
-    // set a aaunit parameter
-    #[inline]
-    fn set_param(aaunit: &AAUnit, node: Index, index: Index, param: Value) {
-        match param {
-            Value::VFloat(f) => {
-                let _ = aaunit.set_param_float(node, index, f);
-            },
-            Value::VInt(i) => {
-                let _ = aaunit.set_param_int(node, index, i);
+    /// create an instance of an aaunit from its wasm
+    pub(crate) fn instantiate(wasm_bytes: &[Vec<u8>]) -> Result<AAUnit> {
+        match AAUnit::new(wasm_bytes.to_vec()) {
+            Ok(aaunit) => {
+                Ok(aaunit)
             },
-            _ => {
+            Err(e) => {
+                eprintln!("Failed to create aaunit {:?}", e);
+                Err(())
+            }
+        }
+    }
+
+    /// create an instance of an aaunit, if its bundle is trusted
+    pub(crate) fn create_aaunit(url: &str, json: &str, trust: &Trust) -> Result<(AAUnit, Bundle)> {
+        Self::fetch_module(url, json, trust, &mut |_, _, _| true).and_then(|(bundle, wasm_bytes)| {
+            Self::instantiate(&wasm_bytes).map(|aaunit| (aaunit, bundle))
+        })
+    }
+
+    /// create additional instances of a module, for host polyphony, from the wasm already
+    /// fetched for its main instance, if the module declares voice parameters. Run by the
+    /// loader, with the main instance, as compiling each instance takes as long as the
+    /// main one, so the current module keeps playing meanwhile, rather than the audio thread
+    /// stopping it. None if the module is not polyphonic, when a single voice is used.
+    pub(crate) fn create_voices(bundle: &Bundle, wasm_bytes: &[Vec<u8>], options: &Option<VoiceOptions>) -> Option<Voices> {
+        let options = options.as_ref().filter(|o| o.count > 0)?;
+        let params = match &bundle.gui.voice {
+            Some(params) => params.clone(),
+            None => {
+                if options.count > 1 {
+                    eprintln!("Module {} does not declare voice parameters, so is monophonic", bundle.info.name);
+                }
+                return None;
+            }
+        };
+        let mut units = Vec::new();
+        for _ in 1..options.count {
+            match AAUnit::new(wasm_bytes.to_vec()) {
+                Ok(aaunit) => units.push(aaunit),
+                Err(e) => {
+                    eprintln!("Failed to create voice {:?}", e);
+                    return None;
+                }
             }
         }
+        Some(Voices::new(units, bundle, params, options.stealing))
     }
 
     // set aaunit parameters from a list of parameters
-    fn set_params(aaunit: &AAUnit, params: &Vec<Vec<Value>>) {
+    pub(crate) fn set_params(aaunit: &AAUnit, params: &Vec<Vec<Value>>) {
         for (node, p) in params.iter().enumerate() {
             for (index, param) in p.iter().enumerate() {
-                Self::set_param(aaunit, node as u32, index as u32, (*param).clone());
+                set_param(aaunit, node as u32, index as u32, (*param).clone());
             }
         }
     }
 
-    /// audio handler for duplex streams (i.e. input and output)
-    /// 0 < number inputs < 3 and 0 < number of outputs < 3
-    fn audio_x_y(
-        aaunit: Rc<RefCell<AAUnit>>, 
-        input_device: pa::DeviceIndex,
-        output_device: pa::DeviceIndex,
-        bundle: Bundle, 
-        receive_from_gui: cb::Receiver<Message>, 
-        receive_from_midi: cb::Receiver<MidiMessage>,
-        send_from_audio: cb::Sender<Message>) -> Option<Message> {
-        let pa = pa::PortAudio::new().unwrap();
-
-        let num_inputs = bundle.info.inputs;
-        let num_outputs = bundle.info.outputs;
-
-        let input_params = pa::stream::Parameters::new(
-            input_device, 
-            num_inputs,
-            true,
-            0.1);
-
-        let output_params = pa::stream::Parameters::new(
-            output_device, 
-            num_outputs,
-            true,
-            0.1);
-
-        let settings = 
-            pa::stream::DuplexSettings::new(
-                input_params, output_params, 44_100.0, 64);
-
-        let (send_stop,rec_stop) = channel();
-        let callback = move |pa::DuplexStreamCallbackArgs {
-            in_buffer, 
-            out_buffer, 
-            frames, 
-            .. }| { 
-                // handle any incomming messages from MIDI
-                loop {
-                    if let Ok(message) = receive_from_midi.try_recv() {
-                        println!("{:?}", message);
-                    }
-                    else {
-                        break;
-                    }
-                }
-
-                // handle any incomming messages from UI
-                loop {
-                    if let Ok(message) = receive_from_gui.try_recv() {
-                        match message.id {
-                            MessageID::Param => {
-                                Self::set_param(&aaunit.borrow(), message.node, message.index, message.value);
-                            },
-                            MessageID::Control => {},
-                            MessageID::ChangeModule 
-                                | MessageID::AddInputDevice 
-                                | MessageID::AddOutputDevice 
-                                | MessageID::Exit => {
-                                send_stop.send(Some(message.clone())).unwrap();
-                                return pa::Complete;
-                            },
-                            _ => { }
-                        }
-                    }
-                    else {
-                        break;
-                    }
-                }                                                
-
-                if num_inputs == 1 {
-                    if num_outputs == 1 {
-                        let _ = aaunit.borrow().compute_one_one(
-                            frames, 
-                            &in_buffer[..], 
-                            &mut out_buffer[..]);
-                    }
-                    else {
-                        let _ = aaunit.borrow().compute_one_two(
-                            frames, 
-                            &in_buffer[..], 
-                            &mut out_buffer[..]);
-                    }
-                }
-                else  {
-                    if num_outputs == 1 {
-                        let _ = aaunit.borrow().compute_two_one(
-                            frames, 
-                            &in_buffer[..], 
-                            &mut out_buffer[..]);
-                    }
-                    else {
-                        let _ = aaunit.borrow().compute_two_two(
-                            frames, 
-                            &in_buffer[..], 
-                            &mut out_buffer[..]);
-                    }
-                }
-                
-                pa::Continue
-        };
-
-        let mut stream = pa.open_non_blocking_stream(settings, callback).unwrap();
-        stream.start().unwrap();
-
-        // block until we recieve message to swap module
-        match rec_stop.recv() {
-            Ok(s) => {
-                stream.stop().unwrap();
-                s 
-            }
-            _ => {
-                stream.stop().unwrap();
-                None
-            }
+    /// create the audio backend for a driver, JACK is not a backend, as it also
+    /// provides MIDI and transport, and is handled separately
+    fn create_backend(driver: &AudioDriver) -> Result<Box<dyn AudioBackend>> {
+        match driver {
+            AudioDriver::PortAudio => {
+                PortAudioBackend::new().map(|b| Box::new(b) as Box<dyn AudioBackend>)
+            },
+            AudioDriver::Null | AudioDriver::Jack(_) => {
+                ok(Box::new(NullBackend::new(true)))
+            },
         }
     }
 
-    /// audio handler for output stream only
-    fn audio_zero_x(
+    /// audio handler for  0:1, 1:1, 0:2, 1:2, 2:2, 1:0, 2:0 audio input:outputs
+    /// currently limited to a maximum of stereo in out.
+    /// Returns the message that stopped the stream, or None if it could not be run.
+    fn audio(
         aaunit: Rc<RefCell<AAUnit>>, 
-        output_device: pa::DeviceIndex,
+        host: Rc<RefCell<Host>>,
+        driver: &AudioDriver,
+        backend: &mut dyn AudioBackend,
+        input_device: Option<u32>,
+        output_device: Option<u32>,
         bundle: Bundle, 
         receive_from_gui: cb::Receiver<Message>, 
-        receive_from_midi: cb::Receiver<MidiMessage>,
+        receive_from_midi: cb::Receiver<MidiEvent>, 
         send_from_audio: cb::Sender<Message>) -> Option<Message> {
-        let pa = pa::PortAudio::new().unwrap();
-
-        let num_outputs = bundle.info.outputs;
-
-        let output_params = pa::stream::Parameters::new(
-            output_device, 
-            num_outputs,
-            true,
-            0.1);
-
-        let settings = 
-            pa::stream::OutputSettings::new(output_params, 44_100.0, 64);        
-
-        let (send_stop,rec_stop) = channel();
-        let callback = move |pa::OutputStreamCallbackArgs {
-            buffer, 
-            frames, 
-            .. }| { 
-                // handle any incomming messages from MIDI
-                loop {
-                    if let Ok(message) = receive_from_midi.try_recv() {
-                        match message.status() {
-                            Status::NoteOn => {
-                                let note     = message.data(1) as i32;
-                                let velocity = message.data(2) as f32 / 127.0;
-                                let _ = aaunit.borrow().handle_note_on(note, velocity);
-                            },
-                            Status::NoteOff => {
-                                let note     = message.data(1) as i32;
-                                let velocity = message.data(2) as f32 / 127.0;
-                                let _ = aaunit.borrow().handle_note_off(note, velocity);
-                            },
-                            // Status::ControlChange => {
-                            //     let controller = message.data(1);
-                            //     let data       = message.data(2);
-                            //     //send_stop.send(Some(message.clone())).unwrap();
-                            //     //println!("{},{}", controller, data);
-                            //     send_from_audio.send(Message {
-                            //         id: MessageID::Control,
-                            //         index: controller as Index,
-                            //         value: Value::VInt(data as i32),
-                            //     }).unwrap();
-                            //     //println!("{:?}", message);
-                            // },
-                            _ => {},
-                        }
-                    }
-                    else {
-                        break;
-                    }
-                }
 
-                // handle any incomming messages from UI
-                loop {
-                    if let Ok(message) = receive_from_gui.try_recv() {
-                        match message.id {
-                            MessageID::NoteOn => {
-                                if let Value::VVU8(data) = message.value {
-                                    let note = data[0] as i32;
-                                    let velocity = data[1] as f32 / 127.0;
-                                    let _ = aaunit.borrow().handle_note_on(note, velocity );
-                                }
-                            },
-                            MessageID::NoteOff => {
-                                if let Value::VVU8(data) = message.value {
-                                    let note = data[0] as i32;
-                                    let velocity = data[1] as f32 / 127.0;
-                                    let _ = aaunit.borrow().handle_note_off(note, velocity );
-                                }
-                            },
-                            MessageID::Param => {
-                                Self::set_param(&aaunit.borrow(), message.node, message.index, message.value);
-                            },
-                            MessageID::Control => {},
-                            MessageID::ChangeModule 
-                                | MessageID::AddInputDevice 
-                                | MessageID::AddOutputDevice 
-                                | MessageID::Exit => {
-                                send_stop.send(Some(message.clone())).unwrap();
-                                return pa::Complete;
-                            },
-                            _ => { }
-                        }
-                    }
-                    else {
-                        break;
-                    }
-                }                                                
-
-                if num_outputs == 1 {
-                    let _ = aaunit.borrow().compute_zero_one(
-                        frames,  
-                        &mut buffer[..]);
+        #[cfg(feature = "jack")]
+        {
+            if let AudioDriver::Jack(options) = driver {
+                if bundle.info.inputs > 0 || bundle.info.outputs > 0 {
+                    return crate::jack_audio::audio_jack(
+                        aaunit, host, options, bundle, receive_from_gui, receive_from_midi, send_from_audio);
                 }
-                else {
-                    let _ = aaunit.borrow().compute_zero_two(
-                        frames,  
-                        &mut buffer[..]);
-                }
-
-                pa::Continue
-        };
-
-        let mut stream = pa.open_non_blocking_stream(settings, callback).unwrap();
-        stream.start().unwrap();
-
-        // block until we recieve message to swap module
-        match rec_stop.recv() {
-            Ok(s) => {
-                stream.stop().unwrap();
-                s 
+                return None;
             }
-            _ => {
-                stream.stop().unwrap();
-                None
+        }
+        #[cfg(not(feature = "jack"))]
+        {
+            if driver.is_jack() {
+                eprintln!("JACK driver requested, but not enabled (build with --features jack)");
+                return None;
             }
         }
-    }
 
-    /// audio handler for  0:1, 1:1, 0:2, 1:2, 2:2 audio input:outputs
-    /// currently limited to a maximum of stereo in out.
-    #[inline]
-    fn audio(
-        aaunit: Rc<RefCell<AAUnit>>, 
-        input_device: pa::DeviceIndex,
-        output_device: pa::DeviceIndex,
-        bundle: Bundle, 
-        receive_from_gui: cb::Receiver<Message>, 
-        receive_from_midi: cb::Receiver<MidiMessage>, 
-        send_from_audio: cb::Sender<Message>) -> Option<Message> {
+        let config = StreamConfig {
+            input_device,
+            output_device,
+            inputs: bundle.info.inputs,
+            outputs: bundle.info.outputs,
+            sample_rate: 44_100.0,
+            frames: 64,
+        };
 
-        // initialize the audio module
-        let _ = aaunit.borrow_mut().init(44_100.0);
+        let (send_stop, rec_stop) = channel();
+        let mut engine = Engine::new(
+            aaunit.clone(), 
+            host.clone(),
+            &bundle, 
+            receive_from_gui, 
+            receive_from_midi, 
+            send_from_audio, 
+            send_stop);
+        let callback: ProcessCallback = Box::new(
+            move |in_buffer: &[f32], out_buffer: &mut [f32], frames: usize| {
+                engine.process(in_buffer, out_buffer, frames)
+            });
+
+        // an audio file, selected as input, replaces the input device 
+        let file_input = host.borrow_mut().select_input(input_device);
+
+        // handle duplex, output only, or input only audio
+        let stream = 
+            if file_input && bundle.info.outputs > 0 {
+                backend.open_output(&config, callback)
+            }
+            else if file_input && bundle.info.inputs > 0 {
+                // input only, so there is no device to drive the module
+                NullBackend::new(true).open_input(&config, callback)
+            }
+            else if bundle.info.inputs > 0 && bundle.info.outputs > 0 {
+                backend.open_duplex(&config, callback)
+            }
+            else if bundle.info.outputs > 0 {
+                backend.open_output(&config, callback)
+            }
+            else if bundle.info.inputs > 0 {
+                backend.open_input(&config, callback)
+            }
+            else {
+                eprintln!("Module {} has neither audio inputs or outputs", bundle.info.name);
+                return None;
+            };
+
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => {
+                eprintln!("Failed to open {} audio stream", backend.name());
+                return None;
+            }
+        };
 
-        // handle duplex or output only audio
-        if bundle.info.inputs > 0 && bundle.info.outputs > 0 {
-            Self::audio_x_y(
-                aaunit, input_device, output_device, bundle, receive_from_gui, receive_from_midi, send_from_audio)
-        }
-        else if bundle.info.outputs > 0 {
-            Self::audio_zero_x(aaunit, output_device, bundle, receive_from_gui, receive_from_midi, send_from_audio)
-        }
-        else {
-            // TODO: add error! logging
-            None
+        // initialize the audio module, at the rate the stream actually runs at
+        let _ = aaunit.borrow_mut().init(stream.sample_rate());
+        host.borrow_mut().start_stream(stream.sample_rate(), bundle.info.outputs, stream.latency());
+        if stream.start().is_err() {
+            eprintln!("Failed to start {} audio stream", backend.name());
+            return None;
         }
+
+        // block until we recieve message to swap module
+        let message = rec_stop.recv().unwrap_or(None);
+        let _ = stream.stop();
+        message
     }
 
     /// Take hold of module a run Audio handler and GUI.
-    /// The audio handler can be dynanically swapped on module change or input/output audio device change
+    /// The audio handler can be dynanically swapped on module change or input/output audio device change.
+    /// Modules are fetched and instantiated by the loader, while the current module keeps
+    /// running, and only swapped on the audio thread once ready.
     pub fn run(self) -> Result<()> {
         let mut gui = self.gui;
         let mut input_device = self.input_device;
         let mut output_device = self.output_device;
-        let url = self.url;
         let receive_from_gui = self.receive_from_gui;
         let send_from_audio = self.send_from_audio;
         let comms = self.comms_sender;
         let json = self.json;
         let receive_from_midi = self.receive_from_midi;
+        let driver = self.driver;
+        let host_options = self.host_options;
+        let voice_options = host_options.voices.clone();
+        let loads = self.loads;
+
+        let (loader_thread, receive_loaded) = self.loader.spawn(
+            self.modules, 
+            self.trust, 
+            voice_options,
+            comms.clone(), 
+            self.send_from_gui.clone());
+        // the default module is loaded like any other
+        loads.load(&json);
 
         // create thread to handle all things audio...
+        let audio_loads = loads.clone();
         let audio_thread = thread::spawn(move || { 
-            // we have to do this here, to avoid having to handle issues with wasmtime 
-            // being initalized on the wrong thread.
-            let (aaunit, bundle) = Self::create_aaunit(
-                &url, 
-                &json, 
-                send_from_audio.clone()).unwrap();
-            let aaunit = Rc::new(RefCell::new(aaunit));
-            let mut bundle = bundle.clone();
+            let loads = audio_loads;
+            let mut backend = match Self::create_backend(&driver) {
+                Ok(backend) => backend,
+                Err(_) => {
+                    eprintln!("Failed to initialize audio backend");
+                    return;
+                }
+            };
+
+            let host = Rc::new(RefCell::new(Host::new(send_from_audio.clone(), comms.clone(), host_options)));
+            // no module runs until the loader has loaded the default
+            let mut module: Option<(Rc<RefCell<AAUnit>>, Bundle)> = None;
+            // false if the stream could not be run, until another device or module is selected
+            let mut runnable = true;
 
             // audio can quit for a number of reasons:
             //          request change input/ouput device
-            //          change audio anywhere module
+            //          change audio anywhere module, once loaded
             //          exit application
-            //          unknown error
-            while let Some(message) = Self::audio(
+            //          unknown error, e.g. the device could not be opened
+            loop {
+                let message = match &module {
+                    Some((aaunit, bundle)) if runnable => Self::audio(
                             aaunit.clone(),
+                            host.clone(),
+                            &driver,
+                            backend.as_mut(),
                             input_device,
                             output_device,
                             bundle.clone(), 
                             receive_from_gui.clone(),
                             receive_from_midi.clone(),
-                            comms.clone()) {
+                            send_from_audio.clone()),
+                    _ => None,
+                };
+                // when nothing is running, wait for GUI to select a device or module, or exit
+                let message = match message {
+                    Some(message) => message,
+                    None => {
+                        if module.is_some() && runnable {
+                            eprintln!("Audio stopped, select another device or module");
+                            runnable = false;
+                        }
+                        match receive_from_gui.recv() {
+                            Ok(message) => message,
+                            Err(_) => break,
+                        }
+                    },
+                };
+
                 match message.id {
                     // switch input device
                     MessageID::AddInputDevice => {
                         if let Value::VInt(index) =  message.value {
-                            input_device = pa::DeviceIndex(index as u32);
+                            input_device = Some(index as u32);
                         }
+                        runnable = true;
                     },
                     // switch output device
                     MessageID::AddOutputDevice => {
                         if let Value::VInt(index) =  message.value {
-                            output_device = pa::DeviceIndex(index as u32);
+                            output_device = Some(index as u32);
                         }
+                        runnable = true;
                     },
-                    // switch module
+                    // switch to the module the loader has loaded, unless another has been
+                    // requested since, which the loader sends once ready, so the old module
+                    // plays until now
                     MessageID::ChangeModule => {
-                        if let Value::VString(json) = message.value {
-                            if let Ok((au, bundle_new)) = 
-                                Self::create_aaunit(&url, &json, send_from_audio.clone()) {
-                                comms.send(
-                                    Message::change_module(
-                                        &([&url[..], 
-                                            &bundle_new.gui.url[..]].join("")), 
-                                            bundle_new.gui.width, 
-                                            bundle_new.gui.height)).unwrap();
-                                
-                                // set default values for GUI
-                                Self::send_params(&comms, &bundle_new.gui.params);
-                                Self::set_params(&au, &bundle_new.gui.params);
-
-                                // finally install the auunit and bundle
-                                *aaunit.borrow_mut() = au;
-                                bundle = bundle_new;
+                        let mut latest = None;
+                        for AudioThreadOnly(loaded) in receive_loaded.try_iter() {
+                            if loads.is_current(loaded.generation) {
+                                latest = Some(loaded);
+                            }
+                            else {
+                                send_load_status(&comms, &loaded.key, LoadStatus::Cancelled);
                             }
                         }
+                        let loaded = match latest {
+                            Some(loaded) => loaded,
+                            None => continue,
+                        };
+
+                        // the GUI already shows the default module
+                        if module.is_some() {
+                            comms.send(
+                                Message::change_module(
+                                    &cache::local_url(&[&loaded.url[..], 
+                                        &loaded.bundle.gui.url[..]].join("")), 
+                                        loaded.bundle.gui.width, 
+                                        loaded.bundle.gui.height)).unwrap();
+                            
+                            // set default values for GUI
+                            Self::send_params(&comms, &loaded.bundle.gui.params);
+                        }
+
+                        // finally install the auunit, its voices, and bundle
+                        host.borrow_mut().voices = loaded.voices;
+                        module = Some((Rc::new(RefCell::new(loaded.aaunit)), loaded.bundle));
+                        runnable = true;
+                        send_load_status(&comms, &loaded.key, LoadStatus::Ready);
                     },
                     MessageID::Exit => {
                         break;
@@ -613,6 +592,11 @@ impl <'a>Standalone<'a> {
             value: Value::VInt(0),
         }).unwrap();
         audio_thread.join().unwrap();
+        // the loader exits once all senders are gone, and any load is cancelled
+        loads.cancel();
+        drop(loads);
+        drop(gui);
+        loader_thread.join().unwrap();
         
         Ok(())
     }
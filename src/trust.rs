@@ -0,0 +1,270 @@
+//!
+//! Integrity checking, with SHA-256 hashes, and ed25519 signatures, of module bundles
+//! and the wasm they refer to, before any of it is run
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use serde_json::Value as Json;
+use sha2::{Digest, Sha256};
+
+use crate::archive::BUNDLE_JSON;
+use crate::bundle::*;
+
+/// options for trusting modules, from the command line
+#[derive(Clone, Debug)]
+pub struct TrustOptions {
+    /// file of trusted public keys, or the default in the user's config directory
+    pub keys_file: Option<String>,
+    /// run bundles that are not signed by a trusted key
+    pub allow_unsigned: bool,
+}
+
+/// the default trusted keys file, ~/.config/aa_standalone/trusted_keys
+pub fn default_keys_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".config/aa_standalone/trusted_keys"))
+}
+
+/// SHA-256 of data, in lower case hex
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+// the bytes signed, the bundle's JSON without its signature, with keys sorted and no
+// white space, so formatting the bundle does not invalidate its signature
+fn signed_bytes(json: &str) -> Result<Vec<u8>, String> {
+    let mut json: Json = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    if let Some(bundle) = json.as_object_mut() {
+        bundle.remove("signature");
+    }
+    serde_json::to_vec(&json).map_err(|e| e.to_string())
+}
+
+fn parse_public_key(key: &str) -> Result<PublicKey, String> {
+    let bytes = hex::decode(key.trim()).map_err(|_| format!("invalid key {}", key))?;
+    PublicKey::from_bytes(&bytes).map_err(|_| format!("invalid key {}", key))
+}
+
+/// the keys modules may be signed with, and whether unsigned modules are allowed
+#[derive(Clone, Debug)]
+pub struct Trust {
+    /// (name, key)
+    keys: Vec<(String, PublicKey)>,
+    allow_unsigned: bool,
+}
+
+impl Trust {
+    /// load trusted keys, one hex encoded ed25519 public key per line, followed by an
+    /// optional name. Lines starting with # are comments. A missing default file is
+    /// not an error, there are just no trusted keys.
+    pub fn load(options: &TrustOptions) -> Result<Self, String> {
+        let (path, required) = match &options.keys_file {
+            Some(file) => (Some(PathBuf::from(file)), true),
+            None => (default_keys_file(), false),
+        };
+
+        let mut keys = Vec::new();
+        if let Some(path) = path {
+            match fs::read_to_string(&path) {
+                Ok(data) => {
+                    for line in data.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+                        let mut parts = line.splitn(2, char::is_whitespace);
+                        let key = parse_public_key(parts.next().unwrap_or(""))
+                            .map_err(|e| format!("{} {}", path.display(), e))?;
+                        let name = parts.next().map_or(String::new(), |name| name.trim().to_string());
+                        keys.push((name, key));
+                    }
+                },
+                Err(e) if required => return Err(format!("{} {}", path.display(), e)),
+                Err(_) => { },
+            }
+        }
+        Ok(Self { keys, allow_unsigned: options.allow_unsigned })
+    }
+
+    /// check a bundle is signed by a trusted key, and has a hash for each of its wasm files.
+    /// Bundles with an invalid signature are always rejected, unsigned bundles, or those
+    /// signed by an unknown key, only if unsigned bundles are not allowed.
+    pub fn verify_bundle(&self, json: &str, bundle: &Bundle) -> Result<(), String> {
+        let name = &bundle.info.name;
+        let signature = match &bundle.signature {
+            Some(signature) => signature,
+            None => return self.unsigned(format!("module {} is not signed", name)),
+        };
+
+        let key = parse_public_key(&signature.key)?;
+        let value = hex::decode(&signature.value)
+            .ok()
+            .and_then(|bytes| Signature::try_from(&bytes[..]).ok())
+            .ok_or_else(|| format!("module {} has an invalid signature", name))?;
+        key.verify(&signed_bytes(json)?, &value)
+            .map_err(|_| format!("module {} has an invalid signature", name))?;
+
+        // without a hash for each wasm file, the signature does not cover the code run
+        if bundle.wasm_sha256.len() != bundle.wasm_url.len() {
+            return Err(format!("module {} is signed, but does not have a hash for each wasm file", name));
+        }
+
+        match self.keys.iter().find(|(_, trusted)| *trusted == key) {
+            Some(_) => Ok(()),
+            None => self.unsigned(format!("module {} is signed with an untrusted key {}", name, signature.key)),
+        }
+    }
+
+    fn unsigned(&self, message: String) -> Result<(), String> {
+        if self.allow_unsigned {
+            eprintln!("{}, running as unsigned modules are allowed", message);
+            Ok(())
+        }
+        else {
+            Err(format!("{} (use --allow-unsigned to run it anyway)", message))
+        }
+    }
+}
+
+/// check a wasm file against the bundle's hash for it, if it has one
+pub fn verify_wasm(bundle: &Bundle, index: usize, data: &[u8]) -> Result<(), String> {
+    match bundle.wasm_sha256.get(index) {
+        Some(expected) if !expected.eq_ignore_ascii_case(&sha256_hex(data)) => {
+            Err(format!("{} does not match its SHA-256 hash", bundle.wasm_url[index]))
+        },
+        _ => Ok(()),
+    }
+}
+
+/// sign a bundle, in a directory with its wasm files, setting the hash of each wasm file
+/// and signing with a secret key, a hex encoded 32 byte seed (e.g. from openssl rand
+/// -hex 32). Returns the bundle's new JSON and the hex encoded public key to trust.
+pub fn sign(dir: &str, secret_key: &str) -> Result<(String, String), String> {
+    let root = Path::new(dir);
+    let bundle_path = root.join(BUNDLE_JSON);
+    let data = fs::read_to_string(&bundle_path).map_err(|e| format!("{} {}", bundle_path.display(), e))?;
+    let mut json: Json = serde_json::from_str(&data).map_err(|e| format!("{} {}", bundle_path.display(), e))?;
+
+    let wasm_urls: Vec<String> = json.get("wasm_url")
+        .and_then(|urls| serde_json::from_value(urls.clone()).ok())
+        .ok_or_else(|| "bundle has no wasm_url".to_string())?;
+    let mut hashes = Vec::new();
+    for wasm_url in wasm_urls.iter() {
+        let path = root.join(wasm_url.trim_start_matches('/'));
+        let wasm = fs::read(&path).map_err(|e| format!("{} {}", path.display(), e))?;
+        hashes.push(Json::String(sha256_hex(&wasm)));
+    }
+
+    let secret = hex::decode(secret_key.trim())
+        .ok()
+        .and_then(|bytes| SecretKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| "invalid secret key".to_string())?;
+    let public = PublicKey::from(&secret);
+    let keypair = Keypair { secret, public };
+
+    let bundle = json.as_object_mut().ok_or_else(|| "bundle is not an object".to_string())?;
+    bundle.insert("wasm_sha256".to_string(), Json::Array(hashes));
+    bundle.remove("signature");
+    let signature = keypair.sign(&serde_json::to_vec(&json).map_err(|e| e.to_string())?);
+
+    let public = hex::encode(keypair.public.as_bytes());
+    if let Some(bundle) = json.as_object_mut() {
+        bundle.insert("signature".to_string(), serde_json::json!({
+            "key": public,
+            "value": hex::encode(signature.to_bytes()),
+        }));
+    }
+    let signed = serde_json::to_string_pretty(&json).map_err(|e| e.to_string())?;
+    Ok((signed, public))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUNDLE: &str = r#"{
+        "format_version": 2,
+        "wasm_url": ["/synth.wasm"],
+        "gui": { "url": "/index.html", "name": "Synth", "params": [[440.0]], "width": 400, "height": 300 },
+        "info": { "name": "Synth", "parameters": 1, "inputs": 0, "outputs": 2 }
+    }"#;
+    const WASM: &[u8] = b"\0asm";
+    const SECRET: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+
+    // a signed bundle, and the public key it was signed with
+    fn signed(name: &str) -> (String, String) {
+        let dir = std::env::temp_dir().join(format!("aa_trust_{}_{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(BUNDLE_JSON), BUNDLE).unwrap();
+        fs::write(dir.join("synth.wasm"), WASM).unwrap();
+        let signed = sign(&dir.to_string_lossy(), SECRET).unwrap();
+        let _ = fs::remove_dir_all(dir);
+        signed
+    }
+
+    fn trust(keys: &[&str], allow_unsigned: bool) -> Trust {
+        Trust {
+            keys: keys.iter().map(|key| ("test".to_string(), parse_public_key(key).unwrap())).collect(),
+            allow_unsigned,
+        }
+    }
+
+    fn verify(trust: &Trust, json: &str) -> Result<(), String> {
+        trust.verify_bundle(json, &Bundle::parse(json).unwrap())
+    }
+
+    #[test]
+    fn signed_bundles_verify() {
+        let (json, public) = signed("verify");
+        let bundle = Bundle::parse(&json).unwrap();
+        assert_eq!(bundle.wasm_sha256, vec![sha256_hex(WASM)]);
+        assert!(verify(&trust(&[&public], false), &json).is_ok());
+        assert!(verify_wasm(&bundle, 0, WASM).is_ok());
+
+        // formatting does not change what was signed
+        let compact = serde_json::to_string(&serde_json::from_str::<Json>(&json).unwrap()).unwrap();
+        assert!(verify(&trust(&[&public], false), &compact).is_ok());
+    }
+
+    #[test]
+    fn tampered_bundles_and_wasm_are_rejected() {
+        let (json, public) = signed("tampered");
+        let tampered = json.replace("\"Synth\"", "\"Evil\"");
+        assert_ne!(tampered, json);
+        let e = verify(&trust(&[&public], true), &tampered).unwrap_err();
+        assert!(e.contains("invalid signature"), "{}", e);
+
+        let bundle = Bundle::parse(&json).unwrap();
+        assert!(verify_wasm(&bundle, 0, b"\0asm tampered").is_err());
+    }
+
+    #[test]
+    fn untrusted_and_unsigned_bundles_need_allowing() {
+        let (json, _) = signed("untrusted");
+        let other = hex::encode(PublicKey::from(&SecretKey::from_bytes(&[2; 32]).unwrap()).as_bytes());
+        assert!(verify(&trust(&[&other], false), &json).unwrap_err().contains("untrusted key"));
+        assert!(verify(&trust(&[&other], true), &json).is_ok());
+
+        assert!(verify(&trust(&[], false), BUNDLE).unwrap_err().contains("not signed"));
+        assert!(verify(&trust(&[], true), BUNDLE).is_ok());
+        // unsigned bundles may have no hashes
+        assert!(verify_wasm(&Bundle::parse(BUNDLE).unwrap(), 0, b"anything").is_ok());
+    }
+
+    #[test]
+    fn trusted_keys_are_loaded_with_names() {
+        let (_, public) = signed("keys");
+        let path = std::env::temp_dir().join(format!("aa_trust_{}_keys", std::process::id()));
+        fs::write(&path, format!("# trusted keys\n\n{} Test Key\n", public)).unwrap();
+        let options = |file: &Path| TrustOptions { keys_file: Some(file.to_string_lossy().to_string()), allow_unsigned: false };
+        let trust = Trust::load(&options(&path)).unwrap();
+        assert_eq!(trust.keys.len(), 1);
+        assert_eq!(trust.keys[0].0, "Test Key");
+
+        fs::write(&path, "not a key\n").unwrap();
+        assert!(Trust::load(&options(&path)).is_err());
+        let _ = fs::remove_file(&path);
+        assert!(Trust::load(&options(&path)).is_err());
+    }
+}
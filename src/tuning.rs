@@ -0,0 +1,402 @@
+//!
+//! Microtuning, from Scala scale and keyboard mapping files, and MIDI Tuning Standard SysEx
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::fs;
+
+/// number of MIDI notes
+const NOTES: usize = 128;
+/// length of a MIDI Tuning Standard bulk dump, up to its checksum, from F0, and the
+/// offset of its first note
+const BULK_DUMP_LEN: usize = 406;
+const BULK_DUMP_NOTES: usize = 22;
+
+/// frequency of a MIDI note in 12 tone equal temperament, A4 = 440Hz
+#[inline]
+fn equal_temperament(note: usize) -> f32 {
+    2.0f32.powf((note as f32 - 69.0) / 12.0) * 440.0
+}
+
+/// a Scala scale (.scl), as cents above the tonic for each degree. The last degree
+/// is the period, usually an octave.
+#[derive(Clone, Debug)]
+pub struct Scale {
+    pub description: String,
+    pub cents: Vec<f64>,
+}
+
+// lines of a Scala file, without comments, which may be indented
+fn scala_lines(data: &str) -> impl Iterator<Item = &str> {
+    data.lines().filter(|line| !line.trim_start().starts_with('!'))
+}
+
+impl Scale {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data = fs::read_to_string(path).map_err(|e| format!("{} {}", path, e))?;
+        Self::parse(&data).map_err(|e| format!("{} {}", path, e))
+    }
+
+    pub fn parse(data: &str) -> Result<Self, String> {
+        let mut lines = scala_lines(data);
+        let description = lines.next().ok_or("missing description")?.trim().to_string();
+        let count: usize = lines.next()
+            .and_then(|line| line.split_whitespace().next())
+            .and_then(|n| n.parse().ok())
+            .ok_or("missing number of notes")?;
+
+        let mut cents = Vec::with_capacity(count);
+        for line in lines.take(count) {
+            let pitch = line.split_whitespace().next().ok_or("missing pitch")?;
+            cents.push(Self::parse_pitch(pitch).ok_or_else(|| format!("invalid pitch {}", pitch))?);
+        }
+        if cents.len() != count || count == 0 {
+            return Err(format!("expected {} pitches, found {}", count, cents.len()));
+        }
+        Ok(Self { description, cents })
+    }
+
+    // a pitch is in cents, if it contains a '.', otherwise it is a ratio, or an integer
+    fn parse_pitch(pitch: &str) -> Option<f64> {
+        if pitch.contains('.') {
+            return pitch.parse().ok();
+        }
+        let mut parts = pitch.splitn(2, '/');
+        let n: f64 = parts.next()?.parse().ok()?;
+        let d: f64 = parts.next().map_or(Some(1.0), |d| d.parse().ok())?;
+        if n <= 0.0 || d <= 0.0 {
+            return None;
+        }
+        Some(1200.0 * (n / d).log2())
+    }
+
+    /// cents above the tonic of a scale degree, which may be outside of a single period
+    pub fn degree_cents(&self, degree: i32) -> f64 {
+        let size = self.cents.len() as i32;
+        let period = self.cents[self.cents.len() - 1];
+        let octave = degree.div_euclid(size);
+        let step = degree.rem_euclid(size);
+        let cents = if step == 0 { 0.0 } else { self.cents[(step - 1) as usize] };
+        octave as f64 * period + cents
+    }
+}
+
+/// a Scala keyboard mapping (.kbm), mapping MIDI notes to scale degrees
+#[derive(Clone, Debug)]
+pub struct KeyboardMapping {
+    pub first_note: usize,
+    pub last_note: usize,
+    /// note to which scale degree 0 is mapped
+    pub middle_note: i32,
+    /// note whose frequency is given
+    pub reference_note: i32,
+    pub reference_freq: f64,
+    /// degree which is the formal octave of the mapping
+    pub octave_degree: i32,
+    /// degree for each key of the mapping, None if the key is not mapped. An empty mapping
+    /// maps keys to consecutive degrees.
+    pub mapping: Vec<Option<i32>>,
+}
+
+impl KeyboardMapping {
+    /// the default mapping for a scale, middle C is the tonic and A4 is 440Hz
+    pub fn linear(scale: &Scale) -> Self {
+        Self {
+            first_note: 0,
+            last_note: NOTES - 1,
+            middle_note: 60,
+            reference_note: 69,
+            reference_freq: 440.0,
+            octave_degree: scale.cents.len() as i32,
+            mapping: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data = fs::read_to_string(path).map_err(|e| format!("{} {}", path, e))?;
+        Self::parse(&data).map_err(|e| format!("{} {}", path, e))
+    }
+
+    pub fn parse(data: &str) -> Result<Self, String> {
+        let mut values = scala_lines(data).filter_map(|line| line.split_whitespace().next());
+        let mut next = |name: &str| values.next().ok_or_else(|| format!("missing {}", name));
+
+        let size: usize = next("size of map")?.parse().map_err(|_| "invalid size of map")?;
+        let first_note: usize = next("first note")?.parse().map_err(|_| "invalid first note")?;
+        let last_note: usize = next("last note")?.parse().map_err(|_| "invalid last note")?;
+        let middle_note: i32 = next("middle note")?.parse().map_err(|_| "invalid middle note")?;
+        let reference_note: i32 = next("reference note")?.parse().map_err(|_| "invalid reference note")?;
+        let reference_freq: f64 = next("reference frequency")?.parse().map_err(|_| "invalid reference frequency")?;
+        let octave_degree: i32 = next("octave degree")?.parse().map_err(|_| "invalid octave degree")?;
+
+        let mut mapping = Vec::with_capacity(size);
+        for _ in 0..size {
+            // keys at the end of the mapping may be left out, and are unmapped
+            match values.next() {
+                Some("x") | None => mapping.push(None),
+                Some(degree) => mapping.push(Some(degree.parse().map_err(|_| format!("invalid degree {}", degree))?)),
+            }
+        }
+
+        Ok(Self {
+            first_note: first_note.min(NOTES - 1),
+            last_note: last_note.min(NOTES - 1),
+            middle_note,
+            reference_note,
+            reference_freq,
+            octave_degree,
+            mapping,
+        })
+    }
+
+    /// scale degree of a note, None if the note is not mapped
+    pub fn degree(&self, note: i32) -> Option<i32> {
+        let offset = note - self.middle_note;
+        if self.mapping.is_empty() {
+            return Some(offset);
+        }
+        let size = self.mapping.len() as i32;
+        let octave = offset.div_euclid(size);
+        self.mapping[offset.rem_euclid(size) as usize].map(|degree| degree + octave * self.octave_degree)
+    }
+}
+
+/// frequency, in Hz, of each MIDI note. Defaults to 12 tone equal temperament.
+#[derive(Clone)]
+pub struct Tuning {
+    freqs: [f32; NOTES],
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        let mut freqs = [0.0; NOTES];
+        for (note, freq) in freqs.iter_mut().enumerate() {
+            *freq = equal_temperament(note);
+        }
+        Self { freqs }
+    }
+}
+
+impl Tuning {
+    /// tuning for a scale, with a keyboard mapping or the default linear mapping.
+    /// Notes that are not mapped remain in equal temperament.
+    pub fn from_scala(scale: &Scale, mapping: Option<&KeyboardMapping>) -> Self {
+        let linear = KeyboardMapping::linear(scale);
+        let mapping = mapping.unwrap_or(&linear);
+        let mut tuning = Self::default();
+
+        let reference_cents = scale.degree_cents(mapping.degree(mapping.reference_note).unwrap_or(0));
+        for note in mapping.first_note..=mapping.last_note {
+            if let Some(degree) = mapping.degree(note as i32) {
+                let cents = scale.degree_cents(degree) - reference_cents;
+                tuning.freqs[note] = (mapping.reference_freq * 2.0f64.powf(cents / 1200.0)) as f32;
+            }
+        }
+        tuning
+    }
+
+    /// load a tuning from a scale, and optional keyboard mapping, file
+    pub fn load(scale: &str, mapping: Option<&str>) -> Result<Self, String> {
+        let scale = Scale::load(scale)?;
+        let mapping = mapping.map(KeyboardMapping::load).transpose()?;
+        Ok(Self::from_scala(&scale, mapping.as_ref()))
+    }
+
+    /// frequency of a MIDI note
+    #[inline]
+    pub fn freq(&self, note: u8) -> f32 {
+        self.freqs[note as usize & 0x7F]
+    }
+
+    /// frequencies of all notes, e.g. to send to the audio thread
+    pub fn to_vec(&self) -> Vec<f32> {
+        self.freqs.to_vec()
+    }
+
+    /// set all frequencies, from to_vec, ignored if the wrong length
+    pub fn set(&mut self, freqs: &[f32]) {
+        if freqs.len() == NOTES {
+            self.freqs.copy_from_slice(freqs);
+        }
+    }
+
+    // set a note's frequency from its MIDI Tuning Standard semitone and 14 bit fraction,
+    // returning false if it is 7F 7F 7F, which is no change
+    fn set_mts(&mut self, key: u8, semitone: u8, msb: u8, lsb: u8) -> bool {
+        if semitone == 0x7F && msb == 0x7F && lsb == 0x7F {
+            return false;
+        }
+        // fraction of a semitone, in units of 100/16384 cents
+        let fraction = (((msb as u32) << 7) | lsb as u32) as f32 / 16384.0;
+        self.freqs[key as usize & 0x7F] = 2.0f32.powf((semitone as f32 + fraction - 69.0) / 12.0) * 440.0;
+        true
+    }
+
+    /// apply a MIDI Tuning Standard single note tuning change, real time or bank based,
+    /// or a bulk dump, returning true if it was one. A bulk dump is ignored if its
+    /// checksum does not match. Does not allocate, so can be used from the audio thread.
+    pub fn apply_sysex(&mut self, data: &[u8]) -> bool {
+        // F0 7F <device> 08 02 <program> <count> ...
+        // F0 7E|7F <device> 08 07 <bank> <program> <count> ...
+        // F0 7E <device> 08 01 <program> <name, 16 bytes> <128 notes, 3 bytes each> <checksum> F7
+        if data.len() < 6 || data[0] != 0xF0 || data[3] != 0x08 {
+            return false;
+        }
+        let start = match (data[1], data[4]) {
+            (0x7F, 0x02) => 6,
+            (0x7E, 0x07) | (0x7F, 0x07) => 7,
+            (0x7E, 0x01) => return self.apply_bulk_dump(data),
+            _ => return false,
+        };
+        if data.len() <= start {
+            return false;
+        }
+
+        let count = data[start] as usize;
+        for change in data[start + 1..].chunks_exact(4).take(count) {
+            self.set_mts(change[0], change[1], change[2], change[3]);
+        }
+        true
+    }
+
+    // the checksum is the XOR of the bytes after F0, up to the checksum, in 7 bits
+    fn apply_bulk_dump(&mut self, data: &[u8]) -> bool {
+        if data.len() <= BULK_DUMP_LEN {
+            return false;
+        }
+        let checksum = data[1..BULK_DUMP_LEN].iter().fold(0, |sum, b| sum ^ b) & 0x7F;
+        if checksum != data[BULK_DUMP_LEN] {
+            return false;
+        }
+        let notes = &data[BULK_DUMP_NOTES..BULK_DUMP_LEN];
+        for (key, note) in notes.chunks_exact(3).enumerate() {
+            self.set_mts(key as u8, note[0], note[1], note[2]);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn scale_pitches_are_cents_or_ratios() {
+        let scale = Scale::parse("! test.scl\n!\nJust fifths\n 3\n  ! an indented comment\n 701.955\n 3/2 fifth\n 2\n").unwrap();
+        assert_eq!(scale.description, "Just fifths");
+        assert_eq!(scale.cents.len(), 3);
+        assert!(close(scale.cents[0], 701.955));
+        assert!(close(scale.cents[1], 701.955_000_865_3));
+        assert!(close(scale.cents[2], 1200.0));
+    }
+
+    #[test]
+    fn invalid_scales_are_rejected() {
+        assert!(Scale::parse("missing pitches\n2\n100.0\n").is_err());
+        assert!(Scale::parse("bad ratio\n1\n-3/2\n").is_err());
+        assert!(Scale::parse("no notes\n0\n").is_err());
+        assert!(Scale::parse("").is_err());
+    }
+
+    #[test]
+    fn degrees_extend_beyond_the_period() {
+        let scale = Scale::parse("whole tone\n6\n200.0\n400.0\n600.0\n800.0\n1000.0\n2/1\n").unwrap();
+        assert!(close(scale.degree_cents(0), 0.0));
+        assert!(close(scale.degree_cents(7), 1400.0));
+        assert!(close(scale.degree_cents(-1), -200.0));
+    }
+
+    #[test]
+    fn linear_mapping_of_equal_temperament_is_unchanged() {
+        let cents: String = (1..=12).map(|n| format!("{}.0\n", n * 100)).collect();
+        let scale = Scale::parse(&format!("12-TET\n12\n{}", cents)).unwrap();
+        let tuning = Tuning::from_scala(&scale, None);
+        let default = Tuning::default();
+        for note in 0..128u8 {
+            assert!((tuning.freq(note) / default.freq(note) - 1.0).abs() < 1e-5, "note {}", note);
+        }
+    }
+
+    #[test]
+    fn keyboard_mapping_maps_keys_to_degrees() {
+        let kbm = "! white keys only\n12\n0\n127\n60\n69\n440.0\n7\n0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n";
+        let mapping = KeyboardMapping::parse(kbm).unwrap();
+        assert_eq!(mapping.mapping.len(), 12);
+        assert_eq!(mapping.degree(60), Some(0));
+        assert_eq!(mapping.degree(61), None);
+        assert_eq!(mapping.degree(62), Some(1));
+        assert_eq!(mapping.degree(72), Some(7));
+        assert_eq!(mapping.degree(59), Some(-1));
+
+        // a 7 note equal scale, with A4 at 440Hz, and unmapped keys in equal temperament
+        let cents: String = (1..=7).map(|n| format!("{:.4}\n", n as f64 * 1200.0 / 7.0)).collect();
+        let scale = Scale::parse(&format!("7-TET\n7\n{}", cents)).unwrap();
+        let tuning = Tuning::from_scala(&scale, Some(&mapping));
+        assert!((tuning.freq(69) - 440.0).abs() < 1e-3);
+        assert!((tuning.freq(81) - 880.0).abs() < 1e-3);
+        assert_eq!(tuning.freq(61), Tuning::default().freq(61));
+        let step = 2.0f32.powf(1.0 / 7.0);
+        assert!((tuning.freq(71) / tuning.freq(69) - step).abs() < 1e-4);
+    }
+
+    #[test]
+    fn short_keyboard_mappings_leave_keys_unmapped() {
+        let mapping = KeyboardMapping::parse("3\n0\n127\n60\n69\n440.0\n2\n0\n").unwrap();
+        assert_eq!(mapping.mapping, vec![Some(0), None, None]);
+        assert!(KeyboardMapping::parse("3\n0\n127\n60\n").is_err());
+    }
+
+    #[test]
+    fn single_note_changes_set_frequencies() {
+        let mut tuning = Tuning::default();
+        // A4 to 69 semitones and half a semitone, then C4 unchanged
+        let sysex = [0xF0, 0x7F, 0x00, 0x08, 0x02, 0x00, 0x02, 69, 69, 0x40, 0x00, 60, 0x7F, 0x7F, 0x7F, 0xF7];
+        assert!(tuning.apply_sysex(&sysex));
+        assert!((tuning.freq(69) - 440.0 * 2.0f32.powf(0.5 / 12.0)).abs() < 1e-3);
+        assert_eq!(tuning.freq(60), Tuning::default().freq(60));
+
+        // bank based, moving C4 to D4
+        let sysex = [0xF0, 0x7E, 0x00, 0x08, 0x07, 0x00, 0x00, 0x01, 60, 62, 0x00, 0x00, 0xF7];
+        assert!(tuning.apply_sysex(&sysex));
+        assert!((tuning.freq(60) - Tuning::default().freq(62)).abs() < 1e-3);
+
+        assert!(!tuning.apply_sysex(&[0xF0, 0x7F, 0x00, 0x04, 0x01, 0x00, 0x7F, 0xF7]));
+        assert!(!tuning.apply_sysex(&[0xF0, 0x7F, 0x00, 0x08, 0x02, 0x00]));
+    }
+
+    // a bulk dump, tuning each note a semitone up, with its checksum
+    fn bulk_dump() -> Vec<u8> {
+        let mut data = vec![0xF0, 0x7E, 0x00, 0x08, 0x01, 0x00];
+        data.extend_from_slice(b"semitone up     ");
+        for note in 0..128u8 {
+            data.extend_from_slice(&[(note + 1).min(127), 0x00, 0x00]);
+        }
+        let checksum = data[1..].iter().fold(0, |sum, b| sum ^ b) & 0x7F;
+        data.push(checksum);
+        data.push(0xF7);
+        data
+    }
+
+    #[test]
+    fn bulk_dumps_are_checked_and_applied() {
+        let data = bulk_dump();
+        assert_eq!(data.len(), BULK_DUMP_LEN + 2);
+        assert_eq!(data[BULK_DUMP_NOTES], 1);
+
+        let mut tampered = data.clone();
+        tampered[BULK_DUMP_NOTES] = 2;
+        let mut tuning = Tuning::default();
+        assert!(!tuning.apply_sysex(&tampered));
+        assert!(!tuning.apply_sysex(&data[..BULK_DUMP_LEN]));
+        assert_eq!(tuning.freq(60), Tuning::default().freq(60));
+
+        assert!(tuning.apply_sysex(&data));
+        assert!((tuning.freq(60) - Tuning::default().freq(61)).abs() < 1e-3);
+        assert!((tuning.freq(126) - Tuning::default().freq(127)).abs() < 1e-2);
+    }
+}
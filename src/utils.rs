@@ -1,4 +1,8 @@
-use curl::easy::Easy;
+use std::thread;
+use std::time::Duration;
+
+use curl::easy::{Easy, List};
+use once_cell::sync::OnceCell;
 
 pub type Result<T> = std::result::Result<T, ()>;
 pub fn err<T>() -> std::result::Result<T,()> {
@@ -15,29 +19,194 @@ pub fn get_string(url: &str) -> Result<String> {
         |v| String::from_utf8(v).map_or(Err(()), |s| Ok(s)))
 }
 
+/// fetch a URL, through the module cache, if enabled
 pub fn get_vec(url: &str) -> Result<Vec<u8>> {
+    get_vec_with_progress(url, &mut |_, _| true)
+}
+
+/// fetch a URL, through the module cache, if enabled, calling progress with the bytes
+/// received and the total, 0 if not known, as it is downloaded. The fetch is cancelled if
+/// progress returns false.
+pub fn get_vec_with_progress(url: &str, progress: &mut dyn FnMut(u64, u64) -> bool) -> Result<Vec<u8>> {
+    match crate::cache::cache() {
+        Some(cache) => cache.get(url, progress),
+        None => fetch(url, &[], progress).map(|response| response.body),
+    }
+}
+
+/// timeouts and retries for HTTP requests, from the command line
+#[derive(Clone, Debug)]
+pub struct HttpOptions {
+    /// time allowed to connect
+    pub connect_timeout: Duration,
+    /// time allowed for a whole request, including the download
+    pub timeout: Duration,
+    /// number of times a request is retried, after a network or server error
+    pub retries: u32,
+    /// wait before the first retry, doubled for each one after
+    pub backoff: Duration,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(120),
+            retries: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+static HTTP_OPTIONS: OnceCell<HttpOptions> = OnceCell::new();
+
+/// set the options for all HTTP requests, otherwise the defaults are used
+pub fn set_http_options(options: HttpOptions) {
+    let _ = HTTP_OPTIONS.set(options);
+}
+
+/// maximum number of redirects followed
+const MAX_REDIRECTS: u32 = 10;
+
+// why a single request failed
+enum Failure {
+    /// network error, or a response that may be retried
+    Error(String),
+    /// progress callback asked for the transfer to stop
+    Cancelled,
+}
+
+impl From<curl::Error> for Failure {
+    fn from(e: curl::Error) -> Self {
+        if e.is_aborted_by_callback() {
+            Failure::Cancelled
+        }
+        else {
+            Failure::Error(e.to_string())
+        }
+    }
+}
+
+/// a response to an HTTP request
+pub struct Response {
+    /// HTTP status, 0 for file URLs
+    pub code: u32,
+    /// response headers, as (lower case name, value)
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// value of a header, by lower case name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| &value[..])
+    }
+}
+
+/// fetch a URL, with additional request headers, e.g. "If-None-Match: <etag>", following
+/// redirects. Network errors, and server errors, are retried, with backoff, and any 
+/// response other than success, or not modified, is an error, as is a fetch cancelled
+/// by progress returning false.
+pub fn fetch(url: &str, request_headers: &[String], progress: &mut dyn FnMut(u64, u64) -> bool) -> Result<Response> {
+    let options = HTTP_OPTIONS.get().cloned().unwrap_or_default();
+    let mut backoff = options.backoff;
+    let mut attempt = 0;
+    loop {
+        let error = match fetch_once(url, request_headers, &options, progress) {
+            // file URLs have no response code
+            Ok(response) if response.code == 0 || response.code == 304 || (200..300).contains(&response.code) => {
+                return Ok(response);
+            },
+            Ok(response) if response.code >= 500 || response.code == 429 => format!("HTTP status {}", response.code),
+            Ok(response) => {
+                eprintln!("Failed to fetch {} HTTP status {}", url, response.code);
+                return err();
+            },
+            Err(Failure::Error(e)) => e,
+            Err(Failure::Cancelled) => return err(),
+        };
+        if attempt >= options.retries {
+            eprintln!("Failed to fetch {} {}", url, error);
+            return err();
+        }
+        attempt += 1;
+        eprintln!("Failed to fetch {} {}, retrying ({}/{})", url, error, attempt, options.retries);
+        thread::sleep(backoff);
+        backoff *= 2;
+    }
+}
+
+// a single request, returning any response received
+fn fetch_once(
+    url: &str, 
+    request_headers: &[String], 
+    options: &HttpOptions, 
+    progress: &mut dyn FnMut(u64, u64) -> bool) -> std::result::Result<Response, Failure> {
  
     // get the html for GUI
     let mut data = Vec::new();
+    let mut headers = Vec::new();
     let mut handle = Easy::new();
  
-    if let Err(_) = handle.url(url) {
-        return Err(());
+    handle.url(url)?;
+    handle.connect_timeout(options.connect_timeout)?;
+    handle.timeout(options.timeout)?;
+    handle.follow_location(true)?;
+    handle.max_redirections(MAX_REDIRECTS)?;
+    handle.progress(true)?;
+
+    if !request_headers.is_empty() {
+        let mut list = List::new();
+        for header in request_headers.iter() {
+            list.append(header)?;
+        }
+        handle.http_headers(list)?;
     }
 
     {    
         let mut transfer = handle.transfer();
-        if let Err(_) = transfer.write_function(|new_data| {
+        transfer.write_function(|new_data| {
             data.extend_from_slice(new_data);
             Ok(new_data.len())
-        }) {
-            return Err(());   
-        }
+        })?;
 
-        if let Err(_) = transfer.perform() {
-            return Err(());
-        }
+        transfer.header_function(|header| {
+            let header = String::from_utf8_lossy(header);
+            // each response, when redirected, starts with its status line
+            if header.starts_with("HTTP/") {
+                headers.clear();
+            }
+            else if let Some(colon) = header.find(':') {
+                headers.push((
+                    header[..colon].trim().to_lowercase(),
+                    header[colon + 1..].trim().to_string()));
+            }
+            true
+        })?;
+
+        transfer.progress_function(|total, now, _, _| {
+            progress(now as u64, total as u64)
+        })?;
+
+        transfer.perform()?;
     }
 
-    Ok(data)
-}
\ No newline at end of file
+    let code = handle.response_code()?;
+    Ok(Response { code, headers, body: data })
+}
+
+/// check that a URL can be fetched, without fetching its body
+pub fn url_exists(url: &str) -> bool {
+    let options = HTTP_OPTIONS.get().cloned().unwrap_or_default();
+    let mut handle = Easy::new();
+    if handle.url(url).is_err() 
+        || handle.nobody(true).is_err() 
+        || handle.connect_timeout(options.connect_timeout).is_err()
+        || handle.timeout(options.timeout).is_err()
+        || handle.follow_location(true).is_err()
+        || handle.perform().is_err() {
+        return false;
+    }
+    // file URLs have no response code
+    handle.response_code().map_or(false, |code| code == 0 || (200..300).contains(&code))
+}
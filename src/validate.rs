@@ -0,0 +1,625 @@
+//!
+//! Validation of module bundles, and module repositories, reporting each problem with
+//! the JSON path at which it was found
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::fmt;
+
+use serde_json::Value as Json;
+
+use crate::bundle::*;
+use crate::utils::{get_string, url_exists};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    /// the bundle loads, but may not behave as intended
+    Warning,
+    /// the bundle does not load
+    Error,
+}
+
+/// a problem found in a bundle, or repository, at a JSON path, e.g. $.gui.params[0]
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub path: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn warning(path: &str, message: String) -> Self {
+        Self { severity: Severity::Warning, path: path.to_string(), message }
+    }
+
+    pub fn error(path: &str, message: String) -> Self {
+        Self { severity: Severity::Error, path: path.to_string(), message }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}: {}", severity, self.path, self.message)
+    }
+}
+
+/// true if any of the diagnostics is an error
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+// collects diagnostics
+#[derive(Default)]
+struct Report {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Report {
+    fn error(&mut self, path: &str, message: String) {
+        self.diagnostics.push(Diagnostic::error(path, message));
+    }
+
+    fn warning(&mut self, path: &str, message: String) {
+        self.diagnostics.push(Diagnostic::warning(path, message));
+    }
+}
+
+//-----------------------------------------------------------------------------
+// the expected shape of bundles and modules.json, matching the serde structs in bundle.rs
+
+enum Kind {
+    Str,
+    /// i32
+    Int,
+    /// u32
+    UInt,
+    Bool,
+    /// a parameter value, a number, string, or array of numbers
+    Param,
+    /// (node, index) of a parameter
+    Pair,
+    /// may be null
+    Nullable(&'static Kind),
+    Array(&'static Kind),
+    Object(&'static [Field]),
+}
+
+struct Field {
+    name: &'static str,
+    kind: Kind,
+    required: bool,
+}
+
+const fn field(name: &'static str, kind: Kind) -> Field {
+    Field { name, kind, required: true }
+}
+
+const fn optional(name: &'static str, kind: Kind) -> Field {
+    Field { name, kind, required: false }
+}
+
+const VOICE_PARAMS: &[Field] = &[
+    optional("freq", Kind::Nullable(&Kind::Pair)),
+    optional("gate", Kind::Nullable(&Kind::Pair)),
+    optional("gain", Kind::Nullable(&Kind::Pair)),
+];
+
+const HOST_PARAMS: &[Field] = &[
+    optional("tempo", Kind::Nullable(&Kind::Pair)),
+    optional("playing", Kind::Nullable(&Kind::Pair)),
+    optional("bar", Kind::Nullable(&Kind::Pair)),
+    optional("beat", Kind::Nullable(&Kind::Pair)),
+];
+
+const GUI: &[Field] = &[
+    field("url", Kind::Str),
+    field("name", Kind::Str),
+    field("params", Kind::Array(&Kind::Array(&Kind::Param))),
+    field("width", Kind::Int),
+    field("height", Kind::Int),
+    optional("output_params", Kind::Array(&Kind::Pair)),
+    optional("voice", Kind::Nullable(&Kind::Object(VOICE_PARAMS))),
+    optional("host_params", Kind::Object(HOST_PARAMS)),
+];
+
+const INFO: &[Field] = &[
+    field("name", Kind::Str),
+    optional("vendor", Kind::Str),
+    optional("presets", Kind::UInt),
+    field("parameters", Kind::UInt),
+    field("inputs", Kind::Int),
+    field("outputs", Kind::Int),
+    optional("midi_inputs", Kind::UInt),
+    optional("midi_outputs", Kind::UInt),
+    optional("id", Kind::UInt),
+    optional("version", Kind::UInt),
+    optional("category", Kind::Str),
+    optional("initial_delay", Kind::UInt),
+    optional("preset_chunks", Kind::Bool),
+    optional("f64_precision", Kind::Bool),
+    optional("silent_when_stopped", Kind::Bool),
+];
+
+const METADATA: &[Field] = &[
+    optional("author", Kind::Nullable(&Kind::Str)),
+    optional("description", Kind::Nullable(&Kind::Str)),
+    optional("tags", Kind::Array(&Kind::Str)),
+    optional("icon", Kind::Nullable(&Kind::Str)),
+    optional("license", Kind::Nullable(&Kind::Str)),
+];
+
+const SIGNATURE: &[Field] = &[
+    field("key", Kind::Str),
+    field("value", Kind::Str),
+];
+
+const BUNDLE: &[Field] = &[
+    optional("format_version", Kind::UInt),
+    field("wasm_url", Kind::Array(&Kind::Str)),
+    optional("wasm_sha256", Kind::Array(&Kind::Str)),
+    field("gui", Kind::Object(GUI)),
+    field("info", Kind::Object(INFO)),
+    optional("metadata", Kind::Object(METADATA)),
+    optional("signature", Kind::Nullable(&Kind::Object(SIGNATURE))),
+];
+
+const MODULE: &[Field] = &[
+    field("name", Kind::Str),
+    field("json_url", Kind::Str),
+];
+
+const MODULES: &[Field] = &[
+    field("default", Kind::Str),
+    field("modules", Kind::Array(&Kind::Object(MODULE))),
+];
+
+fn is_uint(json: &Json) -> bool {
+    json.as_u64().map_or(false, |n| n <= u32::MAX as u64)
+}
+
+fn is_pair(json: &Json) -> bool {
+    json.as_array().map_or(false, |a| a.len() == 2 && a.iter().all(is_uint))
+}
+
+// check a JSON value against the shape expected
+fn check(json: &Json, kind: &Kind, path: &str, report: &mut Report) {
+    let expected = match kind {
+        Kind::Str if !json.is_string() => "a string",
+        Kind::Int if json.as_i64().map_or(true, |n| n < i32::MIN as i64 || n > i32::MAX as i64) => "an integer",
+        Kind::UInt if !is_uint(json) => "a non-negative integer",
+        Kind::Bool if !json.is_boolean() => "true or false",
+        Kind::Param => {
+            let valid = match json {
+                Json::Number(_) | Json::String(_) => true,
+                Json::Array(a) => a.iter().all(|v| v.is_number()),
+                _ => false,
+            };
+            if valid { return } else { "a number, string, or array of numbers" }
+        },
+        Kind::Pair if !is_pair(json) => "a [node, index] pair",
+        Kind::Nullable(kind) => {
+            if !json.is_null() {
+                check(json, kind, path, report);
+            }
+            return;
+        },
+        Kind::Array(kind) => {
+            match json.as_array() {
+                Some(array) => {
+                    for (i, v) in array.iter().enumerate() {
+                        check(v, kind, &format!("{}[{}]", path, i), report);
+                    }
+                    return;
+                },
+                None => "an array",
+            }
+        },
+        Kind::Object(fields) => {
+            match json.as_object() {
+                Some(object) => {
+                    for field in fields.iter() {
+                        let field_path = format!("{}.{}", path, field.name);
+                        match object.get(field.name) {
+                            Some(v) => check(v, &field.kind, &field_path, report),
+                            None if field.required => report.error(&field_path, "missing field".to_string()),
+                            None => { },
+                        }
+                    }
+                    // serde ignores unknown fields, but they are often misspelt optional ones
+                    for name in object.keys().filter(|name| !fields.iter().any(|f| f.name == name.as_str())) {
+                        report.warning(&format!("{}.{}", path, name), "unknown field, ignored".to_string());
+                    }
+                    return;
+                },
+                None => "an object",
+            }
+        },
+        _ => return,
+    };
+    report.error(path, format!("expected {}", expected));
+}
+
+// parse json, reporting if it is not valid
+fn parse_json(data: &str, report: &mut Report) -> Option<Json> {
+    serde_json::from_str::<Json>(data)
+        .map_err(|e| report.error("$", format!("invalid JSON, {}", e)))
+        .ok()
+}
+
+// check json has the expected shape, returning false if not
+fn check_json(json: &Json, fields: &'static [Field], report: &mut Report) -> bool {
+    let errors = report.diagnostics.len();
+    check(json, &Kind::Object(fields), "$", report);
+    !has_errors(&report.diagnostics[errors..])
+}
+
+//-----------------------------------------------------------------------------
+
+// check the bundle's contents are consistent
+fn check_bundle(bundle: &Bundle, report: &mut Report) {
+    if bundle.wasm_url.is_empty() {
+        report.error("$.wasm_url", "no wasm modules".to_string());
+    }
+    if !bundle.wasm_sha256.is_empty() && bundle.wasm_sha256.len() != bundle.wasm_url.len() {
+        report.error(
+            "$.wasm_sha256",
+            format!("{} hashes for {} wasm files", bundle.wasm_sha256.len(), bundle.wasm_url.len()));
+    }
+    for (i, hash) in bundle.wasm_sha256.iter().enumerate() {
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            report.error(&format!("$.wasm_sha256[{}]", i), "expected a hex encoded SHA-256 hash".to_string());
+        }
+    }
+    if bundle.gui.width <= 0 || bundle.gui.height <= 0 {
+        report.error("$.gui", format!("invalid size {}x{}", bundle.gui.width, bundle.gui.height));
+    }
+
+    let info = &bundle.info;
+    if info.inputs < 0 {
+        report.error("$.info.inputs", format!("negative number of inputs {}", info.inputs));
+    }
+    if info.outputs < 0 {
+        report.error("$.info.outputs", format!("negative number of outputs {}", info.outputs));
+    }
+    if info.inputs <= 0 && info.outputs <= 0 {
+        report.warning("$.info", "module has no audio inputs or outputs".to_string());
+    }
+
+    // parameters set at load, and from the GUI, against those the module declares
+    let params = &bundle.gui.params;
+    let count: usize = params.iter().map(|p| p.len()).sum();
+    if count > info.parameters as usize {
+        report.error(
+            "$.gui.params",
+            format!("{} parameters, but the module declares {}", count, info.parameters));
+    }
+    else if count < info.parameters as usize {
+        report.warning(
+            "$.gui.params",
+            format!("{} parameters, module declares {}, the rest keep the module's defaults", count, info.parameters));
+    }
+
+    // parameters the host sets must be ones the GUI sets
+    let mut check_param = |path: &str, param: Option<(u32, u32)>| {
+        if let Some((node, index)) = param {
+            let exists = params.get(node as usize).map_or(false, |p| (index as usize) < p.len());
+            if !exists {
+                report.error(path, format!("no parameter at node {}, index {}", node, index));
+            }
+        }
+    };
+    if let Some(voice) = &bundle.gui.voice {
+        check_param("$.gui.voice.freq", voice.freq);
+        check_param("$.gui.voice.gate", voice.gate);
+        check_param("$.gui.voice.gain", voice.gain);
+    }
+    let host = &bundle.gui.host_params;
+    check_param("$.gui.host_params.tempo", host.tempo);
+    check_param("$.gui.host_params.playing", host.playing);
+    check_param("$.gui.host_params.bar", host.bar);
+    check_param("$.gui.host_params.beat", host.beat);
+
+    if bundle.gui.voice.is_some() && info.midi_inputs == 0 {
+        report.warning("$.gui.voice", "voice parameters declared, but the module has no MIDI inputs".to_string());
+    }
+}
+
+// check the bundle's wasm files can be fetched, relative to the repository URL
+fn check_bundle_urls(url: &str, bundle: &Bundle, report: &mut Report) {
+    for (i, wasm_url) in bundle.wasm_url.iter().enumerate() {
+        let wasm = [url, wasm_url.as_str()].join("");
+        if !url_exists(&wasm) {
+            report.error(&format!("$.wasm_url[{}]", i), format!("{} not found", wasm));
+        }
+    }
+}
+
+/// validate a bundle's JSON, after migrating it to the current format version, without
+/// fetching its wasm files
+pub fn validate_bundle_json(data: &str) -> Vec<Diagnostic> {
+    let mut report = Report::default();
+    if let Some(mut json) = parse_json(data, &mut report) {
+        report.diagnostics.extend(Bundle::migrate(&mut json));
+        if check_json(&json, BUNDLE, &mut report) {
+            match serde_json::from_value::<Bundle>(json) {
+                Ok(bundle) => check_bundle(&bundle, &mut report),
+                Err(e) => report.error("$", e.to_string()),
+            }
+        }
+    }
+    report.diagnostics
+}
+
+/// validate modules.json
+pub fn validate_modules_json(data: &str) -> Vec<Diagnostic> {
+    let mut report = Report::default();
+    let json = parse_json(data, &mut report).filter(|json| check_json(json, MODULES, &mut report));
+    if let Some(json) = json {
+        match serde_json::from_value::<Modules>(json) {
+            Ok(modules) => check_modules(&modules, &mut report),
+            Err(e) => report.error("$", e.to_string()),
+        }
+    }
+    report.diagnostics
+}
+
+fn check_modules(modules: &Modules, report: &mut Report) {
+    if modules.modules.is_empty() {
+        report.warning("$.modules", "no modules".to_string());
+    }
+    if !modules.modules.iter().any(|m| m.json_url == modules.default) {
+        report.warning("$.default", format!("{} is not one of the modules", modules.default));
+    }
+    for (i, m) in modules.modules.iter().enumerate() {
+        if modules.modules[..i].iter().any(|other| other.name == m.name) {
+            report.warning(&format!("$.modules[{}].name", i), format!("duplicate module name {}", m.name));
+        }
+    }
+}
+
+/// validate a module repository, its modules.json and each module's bundle, and, if
+/// check_wasm, that the bundle's wasm files can be fetched. Diagnostics are listed by file.
+pub fn validate_repository(url: &str, check_wasm: bool) -> Vec<(String, Vec<Diagnostic>)> {
+    let mut results = Vec::new();
+
+    let modules_url = [url, "modules.json"].join("/");
+    let modules = match get_string(&modules_url) {
+        Ok(data) => {
+            let diagnostics = validate_modules_json(&data);
+            let modules = if has_errors(&diagnostics) { None } else { Modules::from_json(&data).ok() };
+            results.push((modules_url, diagnostics));
+            modules
+        },
+        Err(_) => {
+            let mut report = Report::default();
+            report.error("$", "failed to fetch".to_string());
+            results.push((modules_url, report.diagnostics));
+            None
+        },
+    };
+
+    // validate each module, and the default even if it is not listed
+    let mut jsons: Vec<String> = modules.iter().flat_map(|m| m.modules.iter().map(|m| m.json_url.clone())).collect();
+    if let Some(modules) = &modules {
+        if !jsons.contains(&modules.default) {
+            jsons.push(modules.default.clone());
+        }
+    }
+    for json in jsons.iter() {
+        let json_url = [url, json.as_str()].join("/");
+        let mut report = Report::default();
+        match get_string(&json_url) {
+            Ok(data) => {
+                report.diagnostics = validate_bundle_json(&data);
+                if check_wasm && !has_errors(&report.diagnostics) {
+                    if let Ok(bundle) = Bundle::parse(&data) {
+                        check_bundle_urls(url, &bundle, &mut report);
+                    }
+                }
+            },
+            Err(_) => report.error("$", "failed to fetch".to_string()),
+        }
+        results.push((json_url, report.diagnostics));
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // a valid bundle, with two parameters, both used by the voice
+    fn bundle() -> Json {
+        json!({
+            "format_version": FORMAT_VERSION,
+            "wasm_url": ["/synth.wasm"],
+            "gui": {
+                "url": "/index.html",
+                "name": "Synth",
+                "params": [[440.0, 0]],
+                "width": 400,
+                "height": 300,
+                "voice": { "freq": [0, 0], "gate": [0, 1] }
+            },
+            "info": { "name": "Synth", "parameters": 2, "inputs": 0, "outputs": 2, "midi_inputs": 1 }
+        })
+    }
+
+    // (severity, path, message) of each diagnostic of a bundle, changed by f
+    fn diagnose<F: FnOnce(&mut Json)>(f: F) -> Vec<(Severity, String, String)> {
+        let mut json = bundle();
+        f(&mut json);
+        validate_bundle_json(&json.to_string())
+            .into_iter()
+            .map(|d| (d.severity, d.path, d.message))
+            .collect()
+    }
+
+    fn error(path: &str, message: &str) -> (Severity, String, String) {
+        (Severity::Error, path.to_string(), message.to_string())
+    }
+
+    fn warning(path: &str, message: &str) -> (Severity, String, String) {
+        (Severity::Warning, path.to_string(), message.to_string())
+    }
+
+    #[test]
+    fn a_valid_bundle_has_no_diagnostics() {
+        assert!(diagnose(|_| { }).is_empty());
+    }
+
+    #[test]
+    fn invalid_json_is_reported_at_the_root() {
+        let diagnostics = validate_bundle_json("{ \"wasm_url\": ");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].path, "$");
+        assert!(diagnostics[0].message.starts_with("invalid JSON"));
+        assert!(has_errors(&diagnostics));
+    }
+
+    #[test]
+    fn fields_are_checked_for_presence_and_type() {
+        assert_eq!(diagnose(|json| { json["gui"].as_object_mut().unwrap().remove("url"); }),
+            vec![error("$.gui.url", "missing field")]);
+        assert_eq!(diagnose(|json| json["info"]["parameters"] = json!(-1)),
+            vec![error("$.info.parameters", "expected a non-negative integer")]);
+        assert_eq!(diagnose(|json| json["info"]["inputs"] = json!(1.5)),
+            vec![error("$.info.inputs", "expected an integer")]);
+        assert_eq!(diagnose(|json| json["info"]["name"] = json!(1)),
+            vec![error("$.info.name", "expected a string")]);
+        assert_eq!(diagnose(|json| json["info"]["preset_chunks"] = json!(1)),
+            vec![error("$.info.preset_chunks", "expected true or false")]);
+        assert_eq!(diagnose(|json| json["gui"]["params"][0][1] = json!({})),
+            vec![error("$.gui.params[0][1]", "expected a number, string, or array of numbers")]);
+        assert_eq!(diagnose(|json| json["gui"]["voice"]["freq"] = json!([0])),
+            vec![error("$.gui.voice.freq", "expected a [node, index] pair")]);
+        assert_eq!(diagnose(|json| json["wasm_url"] = json!("/synth.wasm")),
+            vec![error("$.wasm_url", "expected an array")]);
+        assert_eq!(diagnose(|json| json["gui"] = json!([])),
+            vec![error("$.gui", "expected an object")]);
+    }
+
+    #[test]
+    fn nulls_and_strings_are_accepted_where_allowed() {
+        assert!(diagnose(|json| {
+            json["gui"]["voice"]["gain"] = Json::Null;
+            json["gui"]["params"][0][1] = json!("sine");
+            json["signature"] = Json::Null;
+        }).is_empty());
+    }
+
+    #[test]
+    fn unknown_fields_are_warned_of() {
+        assert_eq!(diagnose(|json| json["info"]["vendr"] = json!("me")),
+            vec![warning("$.info.vendr", "unknown field, ignored")]);
+    }
+
+    #[test]
+    fn wasm_urls_and_hashes_are_checked() {
+        assert_eq!(diagnose(|json| json["wasm_url"] = json!([])),
+            vec![error("$.wasm_url", "no wasm modules")]);
+        let hash = "0".repeat(64);
+        assert_eq!(diagnose(|json| json["wasm_sha256"] = json!([hash, hash])),
+            vec![error("$.wasm_sha256", "2 hashes for 1 wasm files")]);
+        assert_eq!(diagnose(|json| json["wasm_sha256"] = json!(["xyz"])),
+            vec![error("$.wasm_sha256[0]", "expected a hex encoded SHA-256 hash")]);
+    }
+
+    #[test]
+    fn sizes_and_channels_are_checked() {
+        assert_eq!(diagnose(|json| json["gui"]["width"] = json!(0)),
+            vec![error("$.gui", "invalid size 0x300")]);
+        assert_eq!(diagnose(|json| json["info"]["inputs"] = json!(-1)),
+            vec![error("$.info.inputs", "negative number of inputs -1")]);
+        assert_eq!(diagnose(|json| json["info"]["outputs"] = json!(0)),
+            vec![warning("$.info", "module has no audio inputs or outputs")]);
+        assert_eq!(diagnose(|json| json["info"]["outputs"] = json!(-2)),
+            vec![
+                error("$.info.outputs", "negative number of outputs -2"),
+                warning("$.info", "module has no audio inputs or outputs"),
+            ]);
+    }
+
+    #[test]
+    fn params_are_counted_against_the_module() {
+        assert_eq!(diagnose(|json| json["info"]["parameters"] = json!(1)),
+            vec![error("$.gui.params", "2 parameters, but the module declares 1")]);
+        assert_eq!(diagnose(|json| json["info"]["parameters"] = json!(3)),
+            vec![warning("$.gui.params", "2 parameters, module declares 3, the rest keep the module's defaults")]);
+    }
+
+    #[test]
+    fn host_and_voice_params_must_exist() {
+        assert_eq!(diagnose(|json| json["gui"]["voice"]["gate"] = json!([0, 2])),
+            vec![error("$.gui.voice.gate", "no parameter at node 0, index 2")]);
+        assert_eq!(diagnose(|json| json["gui"]["host_params"] = json!({ "tempo": [1, 0] })),
+            vec![error("$.gui.host_params.tempo", "no parameter at node 1, index 0")]);
+        assert_eq!(diagnose(|json| json["info"]["midi_inputs"] = json!(0)),
+            vec![warning("$.gui.voice", "voice parameters declared, but the module has no MIDI inputs")]);
+    }
+
+    #[test]
+    fn older_and_newer_versions_are_warned_of() {
+        let diagnostics = diagnose(|json| {
+            json.as_object_mut().unwrap().remove("format_version");
+            json["info"]["vendor"] = json!("Me");
+        });
+        assert_eq!(diagnostics[0], warning("$.format_version", "migrated from version 1 to 2"));
+        assert!(diagnostics.contains(&warning("$.info.presets", "missing from a version 1 bundle, using the default")));
+        assert!(!diagnostics.iter().any(|(_, path, _)| path == "$.info.vendor"));
+
+        assert_eq!(diagnose(|json| json["format_version"] = json!(FORMAT_VERSION + 1)),
+            vec![warning("$.format_version", "version 3 is newer than supported version 2, new fields are ignored")]);
+    }
+
+    // (severity, path, message) of each diagnostic of modules.json
+    fn diagnose_modules(json: Json) -> Vec<(Severity, String, String)> {
+        validate_modules_json(&json.to_string())
+            .into_iter()
+            .map(|d| (d.severity, d.path, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn modules_are_checked() {
+        let module = |name: &str, json_url: &str| json!({ "name": name, "json_url": json_url });
+        assert!(diagnose_modules(json!({ "default": "a.json", "modules": [module("A", "a.json")] })).is_empty());
+        assert_eq!(diagnose_modules(json!({ "default": "a.json", "modules": [] })),
+            vec![warning("$.modules", "no modules"), warning("$.default", "a.json is not one of the modules")]);
+        assert_eq!(diagnose_modules(json!({ "default": "a.json", "modules": [module("A", "a.json"), module("A", "b.json")] })),
+            vec![warning("$.modules[1].name", "duplicate module name A")]);
+        assert_eq!(diagnose_modules(json!({ "default": "a.json", "modules": [{ "name": "A" }] })),
+            vec![error("$.modules[0].json_url", "missing field")]);
+    }
+
+    #[test]
+    fn repositories_are_checked_for_wasm_files() {
+        let dir = std::env::temp_dir().join(format!("aa_validate_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let modules = json!({ "default": "synth.json", "modules": [{ "name": "Synth", "json_url": "synth.json" }] });
+        std::fs::write(dir.join("modules.json"), modules.to_string()).unwrap();
+        std::fs::write(dir.join("synth.json"), bundle().to_string()).unwrap();
+        let url = format!("file://{}", dir.to_string_lossy());
+
+        let results = validate_repository(&url, true);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].1.is_empty());
+        assert_eq!(results[1].0, format!("{}/synth.json", url));
+        assert_eq!(results[1].1.len(), 1);
+        assert_eq!(results[1].1[0].path, "$.wasm_url[0]");
+        assert_eq!(results[1].1[0].message, format!("{}/synth.wasm not found", url));
+
+        std::fs::write(dir.join("synth.wasm"), b"\0asm").unwrap();
+        assert!(validate_repository(&url, true)[1].1.is_empty());
+        // not fetched
+        std::fs::remove_file(dir.join("synth.wasm")).unwrap();
+        assert!(validate_repository(&url, false)[1].1.is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,234 @@
+//!
+//! Host side polyphony, for modules that implement a single voice
+//! Copyright: Benedict R. Gaster
+//!
+#![allow(dead_code)]
+
+use std::str::FromStr;
+
+use rimd::{MidiMessage, Status};
+
+use aa_wasmtime::*;
+use crate::messages::*;
+use crate::bundle::*;
+use crate::tuning::Tuning;
+use crate::engine::{set_param, compute_block};
+
+/// which voice is taken for a new note, when all voices are sounding
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stealing {
+    /// the voice whose note started first
+    Oldest,
+    /// the voice playing the lowest note
+    Lowest,
+    /// the voice with the lowest output level
+    Quietest,
+}
+
+impl FromStr for Stealing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oldest" => Ok(Stealing::Oldest),
+            "lowest" => Ok(Stealing::Lowest),
+            "quietest" => Ok(Stealing::Quietest),
+            _ => Err(format!("unknown voice stealing mode: {}", s)),
+        }
+    }
+}
+
+/// options for host polyphony, from the command line
+#[derive(Clone, Debug)]
+pub struct VoiceOptions {
+    /// number of voices, each an instance of the module
+    pub count: usize,
+    pub stealing: Stealing,
+}
+
+// allocation state of a voice
+#[derive(Clone, Copy, Debug)]
+struct Voice {
+    /// note sounding, None if free (or released)
+    note: Option<u8>,
+    /// order in which the voice was last started, or released
+    age: u64,
+    /// peak output of the last buffer computed
+    level: f32,
+}
+
+/// allocates notes to a number of instances of a monophonic module, setting its declared
+/// freq (from the host's tuning), gate, and gain parameters, and sums their outputs. The
+/// engine's aaunit is the first voice, so parameters and output parameters continue to
+/// work as for a single instance.
+pub struct Voices {
+    /// instances for voices 1 and above
+    units: Vec<AAUnit>,
+    voices: Vec<Voice>,
+    params: VoiceParams,
+    stealing: Stealing,
+    /// incremented for each note started or released
+    clock: u64,
+    /// output of each voice, before it is summed
+    buffer: Vec<f32>,
+}
+
+impl Voices {
+    /// create voices, from additional instances of a module, which are set to the bundle's
+    /// default parameters
+    pub fn new(units: Vec<AAUnit>, bundle: &Bundle, params: VoiceParams, stealing: Stealing) -> Self {
+        for unit in units.iter() {
+            for (node, p) in bundle.gui.params.iter().enumerate() {
+                for (index, param) in p.iter().enumerate() {
+                    set_param(unit, node as u32, index as u32, param.clone());
+                }
+            }
+        }
+        let voice = Voice { note: None, age: 0, level: 0.0 };
+        Self {
+            voices: vec![voice; units.len() + 1],
+            units,
+            params,
+            stealing,
+            clock: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.voices.len()
+    }
+
+    #[inline]
+    fn unit<'a>(&'a self, main: &'a AAUnit, voice: usize) -> &'a AAUnit {
+        if voice == 0 { main } else { &self.units[voice - 1] }
+    }
+
+    /// initialize the additional instances, the engine's aaunit is initialized separately
+    pub fn init(&mut self, sample_rate: f64) {
+        for unit in self.units.iter_mut() {
+            let _ = unit.init(sample_rate);
+        }
+        for voice in self.voices.iter_mut() {
+            voice.note = None;
+            voice.level = 0.0;
+        }
+    }
+
+    /// set a parameter of the additional instances
+    pub fn set_param(&self, node: Index, index: Index, param: &Value) {
+        for unit in self.units.iter() {
+            set_param(unit, node, index, param.clone());
+        }
+    }
+
+    fn set(unit: &AAUnit, param: Option<(u32, u32)>, value: f32) {
+        if let Some((node, index)) = param {
+            let _ = unit.set_param_float(node, index, value);
+        }
+    }
+
+    // choose a voice for a new note, a voice already playing the note, then the longest
+    // free voice, and finally a voice stolen from those sounding
+    fn allocate(&self, note: u8) -> usize {
+        let voices = self.voices.iter().enumerate();
+        if let Some((v, _)) = voices.clone().find(|(_, voice)| voice.note == Some(note)) {
+            return v;
+        }
+        if let Some((v, _)) = voices.clone().filter(|(_, voice)| voice.note.is_none()).min_by_key(|(_, voice)| voice.age) {
+            return v;
+        }
+        let stolen = match self.stealing {
+            Stealing::Oldest => voices.min_by_key(|(_, voice)| voice.age),
+            Stealing::Lowest => voices.min_by_key(|(_, voice)| voice.note),
+            Stealing::Quietest => voices.min_by(|(_, a), (_, b)| {
+                a.level.partial_cmp(&b.level).unwrap_or(std::cmp::Ordering::Equal)
+            }),
+        };
+        stolen.map_or(0, |(v, _)| v)
+    }
+
+    fn note_on(&mut self, main: &AAUnit, tuning: &Tuning, note: u8, velocity: u8) {
+        let v = self.allocate(note);
+        self.clock += 1;
+        self.voices[v] = Voice { note: Some(note), age: self.clock, level: self.voices[v].level };
+        let unit = self.unit(main, v);
+        Self::set(unit, self.params.freq, tuning.freq(note));
+        Self::set(unit, self.params.gain, velocity as f32 / 127.0);
+        Self::set(unit, self.params.gate, 1.0);
+    }
+
+    fn note_off(&mut self, main: &AAUnit, note: u8) {
+        if let Some(v) = self.voices.iter().position(|voice| voice.note == Some(note)) {
+            self.clock += 1;
+            self.voices[v].note = None;
+            self.voices[v].age = self.clock;
+            Self::set(self.unit(main, v), self.params.gate, 0.0);
+        }
+    }
+
+    /// dispatch a note on or off to a voice, returns false for any other message. Notes
+    /// may be any of the 128 MIDI notes, each has a frequency in the tuning.
+    pub fn handle_note(&mut self, main: &AAUnit, tuning: &Tuning, message: &MidiMessage) -> bool {
+        if message.data.len() < 3 {
+            return false;
+        }
+        let note = message.data(1) & 0x7F;
+        match message.status() {
+            Status::NoteOn if message.data(2) > 0 => {
+                self.note_on(main, tuning, note, message.data(2));
+                true
+            },
+            Status::NoteOn | Status::NoteOff => {
+                self.note_off(main, note);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// update the frequency of sounding voices, after the tuning has changed
+    pub fn retune(&self, main: &AAUnit, tuning: &Tuning) {
+        for (v, voice) in self.voices.iter().enumerate() {
+            if let Some(note) = voice.note {
+                Self::set(self.unit(main, v), self.params.freq, tuning.freq(note));
+            }
+        }
+    }
+
+    #[inline]
+    fn peak(buffer: &[f32]) -> f32 {
+        buffer.iter().fold(0.0, |peak, s| s.abs().max(peak))
+    }
+
+    /// compute a buffer of each voice, summed into out_buffer. Each voice is given the
+    /// same input.
+    pub fn compute(
+        &mut self,
+        main: &AAUnit,
+        num_inputs: i32,
+        num_outputs: i32,
+        frames: usize,
+        in_buffer: &[f32],
+        out_buffer: &mut [f32],
+        scratch: &mut Vec<f32>) {
+        compute_block(main, num_inputs, num_outputs, frames, in_buffer, out_buffer, scratch);
+        self.voices[0].level = Self::peak(out_buffer);
+
+        // only allocates if the buffer size grows
+        let mut buffer = std::mem::take(&mut self.buffer);
+        if buffer.len() < out_buffer.len() {
+            buffer.resize(out_buffer.len(), 0.0);
+        }
+        let buffer_out = &mut buffer[..out_buffer.len()];
+        for v in 1..self.voices.len() {
+            compute_block(&self.units[v - 1], num_inputs, num_outputs, frames, in_buffer, buffer_out, scratch);
+            self.voices[v].level = Self::peak(buffer_out);
+            for (o, s) in out_buffer.iter_mut().zip(buffer_out.iter()) {
+                *o += *s;
+            }
+        }
+        self.buffer = buffer;
+    }
+}